@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use autosurgeon::{hydrate, reconcile};
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+use tasknet_shared::task::{Task, TaskId};
+
+use crate::{
+    config::ServerConfig,
+    encrypted::EncryptedDocument,
+    metrics::disk_size,
+    server::Document,
+    storage::{last_modified, Storage, StorageConfig},
+};
+
+/// File in the documents directory locked by whoever is using the documents.
+const LOCK_FILE: &str = "tasknet.lock";
+
+/// Commands for managing the documents directly in storage.
+///
+/// They take the same lock as the server so they refuse to run while it is.
+#[derive(Debug, clap::Subcommand)]
+pub enum DocumentsCommand {
+    /// List the documents with their size, number of tasks and when they last changed.
+    List,
+    /// Print the tasks of a document as json, in the same format as exporting from the web.
+    Dump {
+        id: String,
+        /// Write to this file instead of stdout.
+        #[clap(long, short)]
+        output: Option<PathBuf>,
+    },
+    /// Import tasks from a json file into a document, creating it if needed.
+    ///
+    /// Tasks with the same id as existing ones replace them.
+    Import { id: String, file: PathBuf },
+    /// Delete a document, including any encrypted copy.
+    Delete {
+        id: String,
+        /// Confirm the document should be deleted.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Compact documents into a single snapshot and drop old sync states, all when no id is given.
+    Compact { id: Option<String> },
+    /// Check documents load and contain valid tasks, all when no id is given.
+    Verify { id: Option<String> },
+    /// Copy every document from the configured storage into another backend.
+    ///
+    /// Documents already in the other backend are merged with, so it can be run again.
+    Migrate {
+        #[clap(long, value_enum)]
+        to: Backend,
+        /// The database for the sqlite backend, defaults to documents.sqlite in documents_dir.
+        #[clap(long)]
+        sqlite_file: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum Backend {
+    Fs,
+    Sqlite,
+}
+
+/// Exclusive use of the documents directory, held until dropped.
+#[derive(Debug)]
+pub struct DocumentsLock(File);
+
+impl DocumentsLock {
+    /// Lock the documents directory, failing if someone else already has it locked.
+    pub fn acquire(documents_dir: &Path) -> Result<Self, String> {
+        let path = documents_dir.join(LOCK_FILE);
+        let file = File::create(&path)
+            .map_err(|err| format!("Failed to create lock file {}: {err}", path.display()))?;
+        file.try_lock_exclusive().map_err(|_| {
+            format!(
+                "{} is in use, stop the server before managing its documents",
+                documents_dir.display()
+            )
+        })?;
+        Ok(Self(file))
+    }
+}
+
+impl Drop for DocumentsLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+/// The documents the commands work on, in storage and the encrypted logs next to it.
+struct Documents {
+    dir: PathBuf,
+    storage: Storage,
+}
+
+pub fn run(config: &ServerConfig, command: DocumentsCommand) -> Result<(), String> {
+    let _lock = DocumentsLock::acquire(&config.documents_dir)?;
+    let documents = Documents {
+        dir: config.documents_dir.clone(),
+        storage: Storage::open(&config.storage, &config.documents_dir)
+            .map_err(|err| format!("Failed to open document storage: {err}"))?,
+    };
+    match command {
+        DocumentsCommand::List => documents.list(),
+        DocumentsCommand::Dump { id, output } => documents.dump(&id, output.as_deref()),
+        DocumentsCommand::Import { id, file } => documents.import(&id, &file),
+        DocumentsCommand::Delete { id, yes } => documents.delete(&id, yes),
+        DocumentsCommand::Compact { id } => {
+            for id in documents.ids_or_all(id)? {
+                documents.compact(&id)?;
+            }
+            Ok(())
+        }
+        DocumentsCommand::Verify { id } => {
+            let mut failed = 0;
+            for id in documents.ids_or_all(id)? {
+                match documents.verify(&id) {
+                    Ok(summary) => println!("{id}: ok, {summary}"),
+                    Err(err) => {
+                        println!("{id}: {err}");
+                        failed += 1;
+                    }
+                }
+            }
+            if failed == 0 {
+                Ok(())
+            } else {
+                Err(format!("{failed} documents failed verification"))
+            }
+        }
+        DocumentsCommand::Migrate { to, sqlite_file } => {
+            let target = match to {
+                Backend::Fs => StorageConfig::Fs,
+                Backend::Sqlite => StorageConfig::Sqlite { file: sqlite_file },
+            };
+            if target == config.storage {
+                return Err("The documents are already stored there".to_owned());
+            }
+            documents.migrate(&target)
+        }
+    }
+}
+
+fn tasks(document: &Document) -> Result<HashMap<TaskId, Task>, String> {
+    hydrate(document.document()).map_err(|err| format!("Failed to read tasks: {err}"))
+}
+
+impl Documents {
+    fn is_encrypted(&self, id: &str) -> bool {
+        EncryptedDocument::exists(&self.dir, id)
+    }
+
+    /// The ids of every document, plaintext and encrypted.
+    fn ids(&self) -> Result<Vec<String>, String> {
+        let mut ids = self
+            .storage
+            .document_ids()
+            .map_err(|err| format!("Failed to list documents: {err}"))?;
+        let entries = std::fs::read_dir(&self.dir)
+            .map_err(|err| format!("Failed to read {}: {err}", self.dir.display()))?;
+        ids.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| EncryptedDocument::is_log(path))
+                .filter_map(|path| {
+                    path.file_stem()
+                        .map(|name| name.to_string_lossy().into_owned())
+                }),
+        );
+        ids.sort();
+        ids.dedup();
+        Ok(ids)
+    }
+
+    fn ids_or_all(&self, id: Option<String>) -> Result<Vec<String>, String> {
+        match id {
+            Some(id) => Ok(vec![id]),
+            None => self.ids(),
+        }
+    }
+
+    fn exists(&self, id: &str) -> Result<bool, String> {
+        let stored = self
+            .storage
+            .exists(id)
+            .map_err(|err| format!("Failed to read document {id}: {err}"))?;
+        Ok(stored || self.is_encrypted(id))
+    }
+
+    fn load(&self, id: &str) -> Result<Document, String> {
+        if self.is_encrypted(id) {
+            return Err(format!(
+                "document {id} is end-to-end encrypted, its tasks can't be read"
+            ));
+        }
+        let persister = self
+            .storage
+            .persister(id)
+            .map_err(|err| format!("Failed to open document {id}: {err}"))?;
+        automerge_persistent::PersistentAutomerge::load(persister)
+            .map_err(|err| format!("Failed to load document {id}: {err}"))
+    }
+
+    fn load_existing(&self, id: &str) -> Result<Document, String> {
+        if !self.exists(id)? {
+            return Err(format!("No document {id}"));
+        }
+        self.load(id)
+    }
+
+    fn size(&self, id: &str) -> u64 {
+        if self.is_encrypted(id) {
+            disk_size(&EncryptedDocument::path(&self.dir, id))
+        } else {
+            self.storage.size(id).unwrap_or_default()
+        }
+    }
+
+    fn list(&self) -> Result<(), String> {
+        println!(
+            "{:<36}  {:>10}  {:>6}  {:<20}",
+            "ID", "BYTES", "TASKS", "LAST CHANGE"
+        );
+        for id in self.ids()? {
+            let (tasks, last_modified) = if self.is_encrypted(&id) {
+                (
+                    "enc".to_owned(),
+                    last_modified(&EncryptedDocument::path(&self.dir, &id)),
+                )
+            } else {
+                (
+                    self.load(&id)
+                        .and_then(|document| tasks(&document))
+                        .map_or_else(|_| "?".to_owned(), |tasks| tasks.len().to_string()),
+                    self.storage.last_modified(&id).ok().flatten(),
+                )
+            };
+            let last_change = last_modified.map_or_else(String::new, |modified| {
+                DateTime::<Utc>::from(modified)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            });
+            println!(
+                "{id:<36}  {:>10}  {tasks:>6}  {last_change:<20}",
+                self.size(&id)
+            );
+        }
+        Ok(())
+    }
+
+    fn dump(&self, id: &str, output: Option<&Path>) -> Result<(), String> {
+        let document = self.load_existing(id)?;
+        let json = serde_json::to_string_pretty(&tasks(&document)?)
+            .map_err(|err| format!("Failed to serialize tasks: {err}"))?;
+        match output {
+            Some(output) => std::fs::write(output, json)
+                .map_err(|err| format!("Failed to write {}: {err}", output.display())),
+            None => {
+                println!("{json}");
+                Ok(())
+            }
+        }
+    }
+
+    fn import(&self, id: &str, file: &Path) -> Result<(), String> {
+        let content = std::fs::read(file)
+            .map_err(|err| format!("Failed to read {}: {err}", file.display()))?;
+        let imported: HashMap<TaskId, Task> = serde_json::from_slice(&content)
+            .map_err(|err| format!("Failed to parse tasks from {}: {err}", file.display()))?;
+
+        let mut document = self.load(id)?;
+        let mut tasks = tasks(&document)?;
+        let count = imported.len();
+        tasks.extend(imported);
+        document
+            .transact(|tx| reconcile(tx, &tasks))
+            .map_err(|err| format!("Failed to import tasks: {err:?}"))?;
+        document
+            .flush()
+            .map_err(|err| format!("Failed to save document {id}: {err}"))?;
+        println!("Imported {count} tasks into {id}");
+        Ok(())
+    }
+
+    fn delete(&self, id: &str, yes: bool) -> Result<(), String> {
+        if !self.exists(id)? {
+            return Err(format!("No document {id}"));
+        }
+        if !yes {
+            return Err(format!(
+                "Deleting {id} can't be undone, pass --yes to confirm"
+            ));
+        }
+        self.storage
+            .delete(id)
+            .map_err(|err| format!("Failed to delete {id}: {err}"))?;
+        let log = EncryptedDocument::path(&self.dir, id);
+        if log.is_file() {
+            std::fs::remove_file(&log)
+                .map_err(|err| format!("Failed to delete {}: {err}", log.display()))?;
+        }
+        println!("Deleted {id}");
+        Ok(())
+    }
+
+    fn compact(&self, id: &str) -> Result<(), String> {
+        if self.is_encrypted(id) {
+            // encrypted documents are compacted by the clients, which can read them
+            println!("{id}: skipped, end-to-end encrypted");
+            return Ok(());
+        }
+        let before = self.size(id);
+        let mut document = self.load_existing(id)?;
+        // every connection syncs with a new peer id, so none of the stored states will be used again
+        let peer_ids = automerge_persistent::Persister::get_peer_ids(document.persister_mut())
+            .map_err(|err| format!("Failed to read sync states of {id}: {err}"))?;
+        let peer_ids = peer_ids.iter().map(Vec::as_slice).collect::<Vec<_>>();
+        document
+            .compact(&peer_ids)
+            .map_err(|err| format!("Failed to compact {id}: {err}"))?;
+        document
+            .flush()
+            .map_err(|err| format!("Failed to save document {id}: {err}"))?;
+        drop(document);
+        println!("{id}: {before} -> {} bytes", self.size(id));
+        Ok(())
+    }
+
+    /// Check the document can be loaded and read, returning a summary of it.
+    fn verify(&self, id: &str) -> Result<String, String> {
+        if self.is_encrypted(id) {
+            let document = EncryptedDocument::load(&self.dir, id)
+                .map_err(|err| format!("Failed to load encrypted log: {err}"))?;
+            document.verify()?;
+            return Ok(format!(
+                "encrypted, {} blobs",
+                document.blobs_after(0).len()
+            ));
+        }
+        let document = self.load_existing(id)?;
+        let tasks = tasks(&document)?;
+        if let Some((key, task)) = tasks.iter().find(|(key, task)| *key != task.id()) {
+            return Err(format!("task stored under {key} has id {}", task.id()));
+        }
+        Ok(format!("{} tasks", tasks.len()))
+    }
+
+    fn migrate(&self, target: &StorageConfig) -> Result<(), String> {
+        let target = Storage::open(target, &self.dir)
+            .map_err(|err| format!("Failed to open the storage to migrate to: {err}"))?;
+        let ids = self
+            .storage
+            .document_ids()
+            .map_err(|err| format!("Failed to list documents: {err}"))?;
+        for id in &ids {
+            let document = self.load(id)?;
+            let persister = target
+                .persister(id)
+                .map_err(|err| format!("Failed to create document {id}: {err}"))?;
+            let mut migrated = automerge_persistent::PersistentAutomerge::load(persister)
+                .map_err(|err| format!("Failed to load migrated document {id}: {err}"))?;
+            let changes = document
+                .document()
+                .get_changes(&[])
+                .map_err(|err| format!("Failed to read document {id}: {err}"))?
+                .into_iter()
+                .cloned();
+            migrated
+                .apply_changes(changes)
+                .map_err(|err| format!("Failed to migrate {id}: {err}"))?;
+            // store a snapshot rather than every change, the old sync states aren't needed
+            migrated
+                .compact(&[])
+                .map_err(|err| format!("Failed to migrate {id}: {err}"))?;
+            migrated
+                .flush()
+                .map_err(|err| format!("Failed to save migrated document {id}: {err}"))?;
+            println!("{id}: migrated");
+        }
+        println!(
+            "Migrated {} documents, update storage in the config to use them",
+            ids.len()
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_compact_and_migrate() {
+        let documents_dir =
+            std::env::temp_dir().join(format!("tasknet-admin-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&documents_dir).unwrap();
+        let mut config = ServerConfig {
+            documents_dir: documents_dir.clone(),
+            ..ServerConfig::default()
+        };
+        let id = uuid::Uuid::new_v4().to_string();
+
+        let mut task = Task::new();
+        task.set_description("imported".to_owned());
+        let file = documents_dir.join("tasks.json");
+        let task_id = &task.id().clone();
+        let imported = HashMap::from([(task_id.clone(), task)]);
+        std::fs::write(&file, serde_json::to_vec(&imported).unwrap()).unwrap();
+
+        run(
+            &config,
+            DocumentsCommand::Import {
+                id: id.clone(),
+                file,
+            },
+        )
+        .unwrap();
+        run(&config, DocumentsCommand::Compact { id: None }).unwrap();
+        run(&config, DocumentsCommand::Verify { id: None }).unwrap();
+        run(
+            &config,
+            DocumentsCommand::Migrate {
+                to: Backend::Sqlite,
+                sqlite_file: None,
+            },
+        )
+        .unwrap();
+
+        config.storage = StorageConfig::Sqlite { file: None };
+        let documents = Documents {
+            dir: documents_dir.clone(),
+            storage: Storage::open(&config.storage, &documents_dir).unwrap(),
+        };
+        assert_eq!(documents.ids().unwrap(), vec![id.clone()]);
+        let tasks = tasks(&documents.load_existing(&id).unwrap()).unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[task_id].description(), "imported");
+        drop(documents);
+
+        // refuse to touch the documents while they are in use
+        let lock = DocumentsLock::acquire(&documents_dir).unwrap();
+        assert!(run(&config, DocumentsCommand::List).is_err());
+        drop(lock);
+
+        std::fs::remove_dir_all(documents_dir).unwrap();
+    }
+}
@@ -1,11 +1,13 @@
 use reqwest::header::SET_COOKIE;
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use tasknet_shared::cookies::{AUTH_PROVIDER_COOKIE, DOCUMENT_ID_COOKIE, SESSION_COOKIE};
 
-use async_session::{async_trait, SessionStore};
+use async_session::{async_trait, Session, SessionStore};
 use axum::extract::TypedHeader;
-use axum::headers::Cookie;
-use axum::response::{Redirect, Response};
+use axum::headers::{Cookie, UserAgent};
+use axum::http::Request;
+use axum::middleware::Next;
+use axum::response::{Html, Redirect, Response};
 use axum::{
     extract::{FromRequestParts, State},
     http::HeaderMap,
@@ -14,28 +16,304 @@ use axum::{
 };
 use axum::{http::request::Parts, http::StatusCode, RequestPartsExt};
 use serde::{Deserialize, Serialize};
-use tasknet_shared::providers::{ProviderDefault, Providers};
+use tasknet_shared::providers::{ProviderDefault, ProviderLocal, Providers};
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::server::Server;
 
 pub mod google;
+pub mod local;
 pub mod public;
+pub mod sessions;
 
 pub async fn providers(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
-    let mut providers = Providers::default();
-    // always include public
-    providers.public = ProviderDefault { enabled: true };
     let server = server.lock().await;
-    providers.google = ProviderDefault {
-        enabled: server.google.is_some(),
+    let providers = Providers {
+        // always include public
+        public: ProviderDefault { enabled: true },
+        google: ProviderDefault {
+            enabled: server.google.is_some(),
+        },
+        local: ProviderLocal {
+            enabled: server.local.is_some(),
+            registration: server
+                .config
+                .local
+                .as_ref()
+                .map(|config| config.registration)
+                .unwrap_or_default(),
+        },
     };
     Json(providers)
 }
 
+/// Render a minimal page to tell the user what went wrong, with a link back to the app.
+pub fn error_page(status: StatusCode, message: &str) -> Response {
+    let body = format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head><meta charset="utf-8"><title>TaskNet</title></head>
+<body>
+<p>{message}</p>
+<p><a href="/">Back to TaskNet</a></p>
+</body>
+</html>"#
+    );
+    (status, Html(body)).into_response()
+}
+
+/// The `SameSite` attribute to set on cookies.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    #[default]
+    Lax,
+    /// Requires `secure` to be set for browsers to accept the cookies.
+    None,
+}
+
+impl From<SameSite> for cookie::SameSite {
+    fn from(same_site: SameSite) -> Self {
+        match same_site {
+            SameSite::Strict => Self::Strict,
+            SameSite::Lax => Self::Lax,
+            SameSite::None => Self::None,
+        }
+    }
+}
+
+/// The policy for sessions and the cookies that identify them.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Only send cookies over https.
+    pub secure: bool,
+    pub same_site: SameSite,
+    /// How long a session lasts, in seconds.
+    pub lifetime_secs: u64,
+    /// Extend the lifetime of a session whenever it is used.
+    pub sliding: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            secure: false,
+            same_site: SameSite::Lax,
+            lifetime_secs: 30 * 24 * 60 * 60,
+            sliding: true,
+        }
+    }
+}
+
+impl SessionConfig {
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.lifetime_secs)
+    }
+
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.lifetime_secs == 0 {
+            problems.push("session.lifetime_secs must be greater than 0".to_owned());
+        }
+        // browsers reject cross-site cookies that aren't secure
+        if self.same_site == SameSite::None && !self.secure {
+            problems.push("session.same_site of none requires session.secure".to_owned());
+        }
+    }
+
+    /// Build a cookie following the policy, `http_only` ones are hidden from the web client.
+    fn cookie(
+        &self,
+        name: &'static str,
+        value: String,
+        http_only: bool,
+    ) -> cookie::Cookie<'static> {
+        cookie::Cookie::build(name, value)
+            .path("/")
+            .http_only(http_only)
+            .secure(self.secure)
+            .same_site(self.same_site.into())
+            .max_age(cookie::time::Duration::seconds(
+                i64::try_from(self.lifetime_secs).unwrap_or(i64::MAX),
+            ))
+            .finish()
+    }
+
+    /// Build a cookie holding the state of a sign in with another site, only sent to the sign in's
+    /// routes under `path`.
+    fn sign_in_cookie(
+        &self,
+        name: &'static str,
+        value: String,
+        path: &'static str,
+        lifetime: Duration,
+    ) -> cookie::Cookie<'static> {
+        // strict cookies aren't sent when the other site redirects back
+        let same_site = match self.same_site {
+            SameSite::Strict => SameSite::Lax,
+            same_site => same_site,
+        };
+        cookie::Cookie::build(name, value)
+            .path(path)
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(same_site.into())
+            .max_age(cookie::time::Duration::seconds(
+                i64::try_from(lifetime.as_secs()).unwrap_or(i64::MAX),
+            ))
+            .finish()
+    }
+
+    /// The cookies describing a session.
+    ///
+    /// Only the session token is kept from the web client, it still reads the provider and
+    /// document cookies.
+    fn session_cookies(
+        &self,
+        session_cookie: String,
+        user_data: &UserSessionData,
+    ) -> Vec<cookie::Cookie<'static>> {
+        vec![
+            self.cookie(SESSION_COOKIE, session_cookie, true),
+            self.cookie(AUTH_PROVIDER_COOKIE, user_data.provider().to_owned(), false),
+            self.cookie(DOCUMENT_ID_COOKIE, user_data.doc_id().to_owned(), false),
+        ]
+    }
+}
+
+fn append_cookies(headers: &mut HeaderMap, cookies: Vec<cookie::Cookie<'static>>) {
+    for cookie in cookies {
+        let cookie = cookie.to_string();
+        debug!(cookie, "Setting cookie");
+        headers.append(SET_COOKIE, cookie.parse().unwrap());
+    }
+}
+
+/// Get the session token sent with the request, if any.
+pub fn session_cookie(cookie: &Option<TypedHeader<Cookie>>) -> Option<&str> {
+    cookie
+        .as_ref()
+        .and_then(|cookie| cookie.get(SESSION_COOKIE))
+        .filter(|session_cookie| !session_cookie.is_empty())
+}
+
+/// Start a new session for the user, returning the headers to set the session cookies.
+///
+/// Any session the request was already using is destroyed so that session tokens are never
+/// carried across a sign in.
+pub async fn start_session(
+    server: &Server,
+    previous_session_cookie: Option<&str>,
+    user_data: &UserSessionData,
+) -> HeaderMap {
+    server.metrics.auth_succeeded(user_data.provider());
+
+    if let Some(previous_session_cookie) = previous_session_cookie {
+        if let Ok(Some(session)) = server
+            .sessions
+            .load_session(previous_session_cookie.to_owned())
+            .await
+        {
+            debug!("Rotating session on sign in");
+            server.sessions.destroy_session(session).await.unwrap();
+        }
+    }
+
+    // Create a new session filled with user data
+    let mut session = Session::new();
+    session.insert("user_data", user_data).unwrap();
+    session.expire_in(server.config.session.lifetime());
+
+    // Store session and get corresponding cookie
+    let session_cookie = server
+        .sessions
+        .store_session(session)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut headers = HeaderMap::new();
+    append_cookies(
+        &mut headers,
+        server
+            .config
+            .session
+            .session_cookies(session_cookie, user_data),
+    );
+    headers
+}
+
+/// Middleware to record when sessions are used and extend them, when sliding sessions are
+/// enabled.
+///
+/// Sessions are renewed once over half of their lifetime has passed to avoid storing the session
+/// and setting cookies on every request.
+pub async fn track_session<B>(
+    State(server): State<Arc<Mutex<Server>>>,
+    cookie: Option<TypedHeader<Cookie>>,
+    user_agent: Option<TypedHeader<UserAgent>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let Some(session_cookie) = session_cookie(&cookie) else {
+        return next.run(request).await;
+    };
+
+    if let Ok(id) = Session::id_from_cookie_value(session_cookie) {
+        let sessions = server.lock().await.sessions.clone();
+        sessions
+            .touch(
+                &id,
+                user_agent.as_ref().map(|user_agent| user_agent.as_str()),
+            )
+            .await;
+    }
+
+    let mut response = next.run(request).await;
+
+    let server = server.lock().await;
+    let config = &server.config.session;
+    if !config.sliding {
+        return response;
+    }
+
+    // the handler may have signed out or rotated the session, in which case it won't load
+    let Ok(Some(mut session)) = server
+        .sessions
+        .load_session(session_cookie.to_owned())
+        .await
+    else {
+        return response;
+    };
+    let Some(user_data) = session.get::<UserSessionData>("user_data") else {
+        return response;
+    };
+    let half_expired = matches!(
+        session.expires_in(),
+        Some(expires_in) if expires_in <= config.lifetime() / 2
+    );
+    if !half_expired {
+        return response;
+    }
+
+    debug!("Renewing session");
+    session.expire_in(config.lifetime());
+    if let Err(err) = server.sessions.store_session(session).await {
+        warn!(%err, "Failed to renew session");
+        return response;
+    }
+    append_cookies(
+        response.headers_mut(),
+        config.session_cookies(session_cookie.to_owned(), &user_data),
+    );
+    response
+}
+
 pub struct UserIdFromSession {
     pub session_cookie: String,
+    pub session_id: String,
     pub session_data: UserSessionData,
 }
 
@@ -64,7 +342,7 @@ impl FromRequestParts<Arc<Mutex<Server>>> for UserIdFromSession {
         }
 
         // continue to decode the session cookie
-        let user_data = if let Some(session) = server
+        let (session_id, user_data) = if let Some(session) = server
             .sessions
             .load_session(session_cookie.to_owned())
             .await
@@ -75,7 +353,7 @@ impl FromRequestParts<Arc<Mutex<Server>>> for UserIdFromSession {
                     "UserIdFromSession: session decoded success, user_data={:?}",
                     user_data
                 );
-                user_data
+                (session.id().to_owned(), user_data)
             } else {
                 debug!("Failed to get user_data from session");
                 return Err((headers, Redirect::to("/")));
@@ -86,29 +364,40 @@ impl FromRequestParts<Arc<Mutex<Server>>> for UserIdFromSession {
                 SESSION_COOKIE, session_cookie
             );
 
-            clear_session_cookies(&mut headers).await;
+            clear_session_cookies(&server.config.session, &mut headers).await;
 
             return Err((headers, Redirect::to("/")));
         };
 
         Ok(UserIdFromSession {
             session_cookie: session_cookie.to_owned(),
+            session_id,
             session_data: user_data,
         })
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum UserSessionData {
     Google { google_id: String },
     Public { doc_id: String },
+    Local { username: String, doc_id: String },
 }
 
 impl UserSessionData {
     pub fn doc_id(&self) -> &str {
         match self {
             UserSessionData::Google { google_id } => google_id,
-            UserSessionData::Public { doc_id } => doc_id,
+            UserSessionData::Public { doc_id } | UserSessionData::Local { doc_id, .. } => doc_id,
+        }
+    }
+
+    /// The name of the provider the user signed in with, as the web client knows it.
+    pub const fn provider(&self) -> &'static str {
+        match self {
+            UserSessionData::Google { .. } => "google",
+            UserSessionData::Public { .. } => "public",
+            UserSessionData::Local { .. } => "local",
         }
     }
 }
@@ -168,23 +457,22 @@ impl FromRequestParts<Arc<Mutex<Server>>> for UserSessionData {
                 SESSION_COOKIE, session_cookie
             );
 
-            clear_session_cookies(&mut headers).await;
+            clear_session_cookies(&server.config.session, &mut headers).await;
 
             return Err((headers, error_response));
         }
     }
 }
 
-pub async fn clear_session_cookies(headers: &mut HeaderMap) {
-    let cookies = vec![
-        format!("{}=; Path=/", SESSION_COOKIE),
-        format!("{}=; Path=/", AUTH_PROVIDER_COOKIE),
-        format!("{}=; Path=/", DOCUMENT_ID_COOKIE),
+pub async fn clear_session_cookies(config: &SessionConfig, headers: &mut HeaderMap) {
+    let mut cookies = vec![
+        config.cookie(SESSION_COOKIE, String::new(), true),
+        config.cookie(AUTH_PROVIDER_COOKIE, String::new(), false),
+        config.cookie(DOCUMENT_ID_COOKIE, String::new(), false),
     ];
-
-    // Set cookies
-    for cookie in cookies {
-        debug!(cookie, "Setting cookie");
-        headers.append(SET_COOKIE, cookie.parse().unwrap());
+    for cookie in &mut cookies {
+        cookie.make_removal();
     }
+
+    append_cookies(headers, cookies);
 }
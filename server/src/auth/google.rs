@@ -1,23 +1,43 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use async_session::{Session, SessionStore};
+use async_session::SessionStore;
 use axum::{
-    extract::{Query, State},
-    http::HeaderMap,
-    response::{IntoResponse, Redirect},
+    extract::{Query, State, TypedHeader},
+    headers::Cookie,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
 };
-use openid::{DiscoveredClient, Options, Token};
-use reqwest::header::SET_COOKIE;
+use base64::Engine;
+use openid::{Bearer, DiscoveredClient, OAuth2Error, Options, Token};
+use rand::RngCore;
+use reqwest::header::ACCEPT;
 use serde::{Deserialize, Serialize};
-use tasknet_shared::cookies::{AUTH_PROVIDER_COOKIE, DOCUMENT_ID_COOKIE, SESSION_COOKIE};
+use sha2::{Digest, Sha256};
 use tokio::sync::Mutex;
-use tracing::debug;
+use tracing::{debug, warn};
 
 use crate::{auth::UserSessionData, server::Server};
 
-use super::{clear_session_cookies, UserIdFromSession};
+use super::{
+    append_cookies, clear_session_cookies, error_page, session_cookie, start_session,
+    UserIdFromSession,
+};
+
+/// Cookie binding an in-progress sign in to the browser that started it.
+const STATE_COOKIE: &str = "google-auth-state";
+const STATE_COOKIE_PATH: &str = "/auth/google";
+
+/// How long a user has to complete the sign in with Google.
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// The most sign ins to wait for at once, so starting them without finishing can't use up memory.
+const MAX_PENDING_SIGN_INS: usize = 1000;
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct GoogleConfig {
     client_id: String,
     client_secret: String,
@@ -26,8 +46,87 @@ pub struct GoogleConfig {
     scopes: Vec<String>,
 }
 
+// written out so the config can be logged without the secret
+impl std::fmt::Debug for GoogleConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GoogleConfig")
+            .field("client_id", &self.client_id)
+            .field("client_secret", &"<redacted>")
+            .field("issuer_uri", &self.issuer_uri)
+            .field("redirect_uri", &self.redirect_uri)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+/// A sign in that has been started but not yet completed by the callback.
+struct PendingSignIn {
+    nonce: String,
+    pkce_verifier: String,
+    started: Instant,
+}
+
 pub struct Google {
-    client: DiscoveredClient,
+    client: Arc<DiscoveredClient>,
+    /// Sign in attempts waiting for their callback, keyed by state.
+    pending: HashMap<String, PendingSignIn>,
+}
+
+impl GoogleConfig {
+    /// Signing in with Google's own accounts, still needing the credentials and redirect uri.
+    pub fn accounts_google() -> Self {
+        Self {
+            issuer_uri: "https://accounts.google.com".to_owned(),
+            scopes: vec!["openid".to_owned()],
+            ..Self::default()
+        }
+    }
+
+    pub fn apply_overrides(
+        &mut self,
+        client_id: Option<&str>,
+        client_secret: Option<&str>,
+        redirect_uri: Option<&str>,
+    ) {
+        if let Some(client_id) = client_id {
+            self.client_id = client_id.to_owned();
+        }
+        if let Some(client_secret) = client_secret {
+            self.client_secret = client_secret.to_owned();
+        }
+        if let Some(redirect_uri) = redirect_uri {
+            self.redirect_uri = redirect_uri.to_owned();
+        }
+    }
+
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.client_id.is_empty() {
+            problems.push("google.client_id must be set".to_owned());
+        }
+        if self.client_secret.is_empty() {
+            problems.push("google.client_secret must be set".to_owned());
+        }
+        if let Err(err) = reqwest::Url::parse(&self.issuer_uri) {
+            problems.push(format!(
+                "google.issuer_uri {:?} is not a valid url: {err}",
+                self.issuer_uri
+            ));
+        }
+        if self.redirect_uri.is_empty() {
+            problems.push(
+                "google.redirect_uri must be set, such as https://<host>/auth/google/callback"
+                    .to_owned(),
+            );
+        } else if let Err(err) = reqwest::Url::parse(&self.redirect_uri) {
+            problems.push(format!(
+                "google.redirect_uri {:?} is not a valid url: {err}",
+                self.redirect_uri
+            ));
+        }
+        if !self.scopes.iter().any(|scope| scope == "openid") {
+            problems.push("google.scopes must include openid".to_owned());
+        }
+    }
 }
 
 impl Google {
@@ -40,27 +139,99 @@ impl Google {
         )
         .await
         .unwrap();
-        Self { client }
+        Self {
+            client: Arc::new(client),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        self.pending
+            .retain(|_, pending| pending.started.elapsed() < SIGN_IN_TIMEOUT);
     }
 }
 
-pub async fn sign_in_handler(State(server): State<Arc<Mutex<Server>>>) -> impl IntoResponse {
-    let server = server.lock().await;
-    if let (Some(state), Some(config)) = (server.google.as_ref(), server.config.google.as_ref()) {
+/// Wait for the sign in's callback, giving up on the oldest sign in when waiting for too many.
+fn insert_pending(
+    pending: &mut HashMap<String, PendingSignIn>,
+    state: String,
+    sign_in: PendingSignIn,
+) {
+    if pending.len() >= MAX_PENDING_SIGN_INS {
+        let oldest = pending
+            .iter()
+            .min_by_key(|(_, pending)| pending.started)
+            .map(|(state, _)| state.clone());
+        if let Some(oldest) = oldest {
+            warn!("Too many pending Google sign ins, dropping the oldest");
+            pending.remove(&oldest);
+        }
+    }
+    pending.insert(state, sign_in);
+}
+
+/// Generate a random url-safe token, suitable for states, nonces and PKCE verifiers.
+fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    let digest = Sha256::digest(verifier.as_bytes());
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+}
+
+pub async fn sign_in_handler(State(server): State<Arc<Mutex<Server>>>) -> Response {
+    let mut server = server.lock().await;
+    let scopes = server
+        .config
+        .google
+        .as_ref()
+        .map(|config| config.scopes.join(" "));
+    if let (Some(google), Some(scopes)) = (server.google.as_mut(), scopes) {
         debug!("google auth sign in");
 
-        let auth_url: String = state
-            .client
-            .auth_url(&Options {
-                scope: Some(config.scopes.join(" ")),
-                ..Default::default()
-            })
-            .into();
+        google.remove_expired();
+
+        let state = random_token();
+        let nonce = random_token();
+        let pkce_verifier = random_token();
+
+        let mut auth_url = google.client.auth_url(&Options {
+            scope: Some(scopes),
+            state: Some(state.clone()),
+            nonce: Some(nonce.clone()),
+            ..Default::default()
+        });
+        auth_url
+            .query_pairs_mut()
+            .append_pair("code_challenge", &pkce_challenge(&pkce_verifier))
+            .append_pair("code_challenge_method", "S256");
+
+        insert_pending(
+            &mut google.pending,
+            state.clone(),
+            PendingSignIn {
+                nonce,
+                pkce_verifier,
+                started: Instant::now(),
+            },
+        );
+
+        let mut headers = HeaderMap::new();
+        let cookie = server.config.session.sign_in_cookie(
+            STATE_COOKIE,
+            state,
+            STATE_COOKIE_PATH,
+            SIGN_IN_TIMEOUT,
+        );
+        append_cookies(&mut headers, vec![cookie]);
 
         debug!("Redirecting to {}", auth_url);
-        Redirect::to(auth_url.as_ref())
+        (headers, Redirect::to(auth_url.as_ref())).into_response()
     } else {
-        Redirect::to("/")
+        Redirect::to("/").into_response()
     }
 }
 
@@ -77,71 +248,203 @@ pub async fn sign_out_handler(
 
     let mut headers = HeaderMap::new();
     // clear cookies
-    clear_session_cookies(&mut headers).await;
+    clear_session_cookies(&server.config.session, &mut headers).await;
 
     (headers, Redirect::to("/"))
 }
 
 #[derive(Debug, Deserialize)]
 pub struct AuthRequest {
-    code: String,
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Exchange the authorization code for tokens, proving we started the sign in with the PKCE
+/// verifier.
+async fn request_token(
+    client: &DiscoveredClient,
+    code: &str,
+    pkce_verifier: &str,
+) -> Result<Bearer, String> {
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("code_verifier", pkce_verifier),
+    ];
+    if let Some(redirect_uri) = client.redirect_uri.as_deref() {
+        form.push(("redirect_uri", redirect_uri));
+    }
+
+    let json = client
+        .http_client
+        .post(client.config().token_endpoint.clone())
+        .basic_auth(&client.client_id, client.client_secret.as_ref())
+        .header(ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if let Ok(error) = serde_json::from_value::<OAuth2Error>(json.clone()) {
+        return Err(error.to_string());
+    }
+    serde_json::from_value(json).map_err(|err| err.to_string())
 }
 
 pub async fn callback_handler(
     Query(query): Query<AuthRequest>,
+    cookie: Option<TypedHeader<Cookie>>,
     State(server): State<Arc<Mutex<Server>>>,
-) -> impl IntoResponse {
+) -> Response {
+    let response = callback(query, cookie, &server).await;
+    // successes are counted when the session starts
+    if response.status().is_client_error() || response.status().is_server_error() {
+        server.lock().await.metrics.auth_failed("google");
+    }
+    response
+}
+
+async fn callback(
+    query: AuthRequest,
+    cookie: Option<TypedHeader<Cookie>>,
+    server: &Mutex<Server>,
+) -> Response {
     debug!("Google auth callback");
 
+    if let Some(error) = query.error {
+        debug!(error, "Google sign in was not completed");
+        let message = if error == "access_denied" {
+            "Sign in with Google was cancelled."
+        } else {
+            "Google could not complete the sign in, please try again."
+        };
+        return error_page(StatusCode::UNAUTHORIZED, message);
+    }
+
+    let (Some(code), Some(state)) = (query.code, query.state) else {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "The sign in response from Google was incomplete, please try again.",
+        );
+    };
+
+    // the state must have been issued to this browser, otherwise someone else started this sign
+    // in and is trying to get us to complete it
+    let state_cookie = cookie
+        .as_ref()
+        .and_then(|cookie| cookie.get(STATE_COOKIE))
+        .unwrap_or_default();
+    if state_cookie != state {
+        warn!("Google callback state did not match the browser's state cookie");
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "This sign in was not started from this browser, please try again.",
+        );
+    }
+
+    let (client, pending) = {
+        let mut server = server.lock().await;
+        let Some(google) = server.google.as_mut() else {
+            return Redirect::to("/").into_response();
+        };
+        google.remove_expired();
+        (google.client.clone(), google.pending.remove(&state))
+    };
+    let Some(pending) = pending else {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "This sign in attempt has expired, please try again.",
+        );
+    };
+
+    // talk to Google without holding the server lock
+    let bearer = match request_token(&client, &code, &pending.pkce_verifier).await {
+        Ok(bearer) => bearer,
+        Err(err) => {
+            warn!(%err, "Failed to exchange Google authorization code");
+            return error_page(
+                StatusCode::BAD_GATEWAY,
+                "Failed to complete the sign in with Google, please try again.",
+            );
+        }
+    };
+    let mut token: Token = bearer.into();
+
+    let Some(id_token) = token.id_token.as_mut() else {
+        warn!("Google token response did not include an id token");
+        return error_page(
+            StatusCode::BAD_GATEWAY,
+            "Google did not identify the account, please try again.",
+        );
+    };
+    let validated = client
+        .decode_token(id_token)
+        .and_then(|()| client.validate_token(id_token, pending.nonce.as_str(), None));
+    if let Err(err) = validated {
+        warn!(%err, "Failed to validate Google id token");
+        return error_page(
+            StatusCode::UNAUTHORIZED,
+            "Google's response could not be verified, please try again.",
+        );
+    }
+    debug!("token: {:?}", id_token);
+    let Some(payload) = id_token.payload().ok() else {
+        return error_page(
+            StatusCode::UNAUTHORIZED,
+            "Google's response could not be verified, please try again.",
+        );
+    };
+
     let server = server.lock().await;
 
-    let mut headers = HeaderMap::new();
+    let user_data = UserSessionData::Google {
+        google_id: payload.sub.clone(),
+    };
+    let mut headers = start_session(&server, session_cookie(&cookie), &user_data).await;
+    // the sign in attempt is complete
+    let mut state_cookie = server.config.session.sign_in_cookie(
+        STATE_COOKIE,
+        String::new(),
+        STATE_COOKIE_PATH,
+        Duration::ZERO,
+    );
+    state_cookie.make_removal();
+    append_cookies(&mut headers, vec![state_cookie]);
+
+    (headers, Redirect::to("/")).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    if let Some(state) = server.google.as_ref() {
-        let mut token: Token = state
-            .client
-            .request_token(&query.code)
-            .await
-            .unwrap()
-            .into();
-
-        if let Some(id_token) = token.id_token.as_mut() {
-            state.client.decode_token(id_token).unwrap();
-            state.client.validate_token(id_token, None, None).unwrap();
-            debug!("token: {:?}", id_token);
-            let payload = id_token.payload().unwrap();
-
-            // Create a new session filled with user data
-            let mut session = Session::new();
-            let user_data = UserSessionData::Google {
-                google_id: payload.sub.clone(),
+    #[test]
+    fn test_pending_sign_ins_are_capped() {
+        let mut pending = HashMap::new();
+        let start = Instant::now();
+        for i in 0..=MAX_PENDING_SIGN_INS {
+            let sign_in = PendingSignIn {
+                nonce: String::new(),
+                pkce_verifier: String::new(),
+                started: start + Duration::from_millis(i as u64),
             };
-            session.insert("user_data", &user_data).unwrap();
-
-            // Store session and get corresponding cookie
-            let session_cookie = server
-                .sessions
-                .store_session(session)
-                .await
-                .unwrap()
-                .unwrap();
-
-            // Build the cookie
-            let cookies = vec![
-                format!(
-                    "{}={}; SameSite=Lax; Path=/",
-                    SESSION_COOKIE, session_cookie
-                ),
-                format!("{}={}; Path=/", AUTH_PROVIDER_COOKIE, "google"),
-                format!("{}={}; Path=/", DOCUMENT_ID_COOKIE, user_data.doc_id()),
-            ];
-
-            // Set cookies
-            for cookie in cookies {
-                headers.append(SET_COOKIE, cookie.parse().unwrap());
-            }
+            insert_pending(&mut pending, i.to_string(), sign_in);
         }
+        assert_eq!(pending.len(), MAX_PENDING_SIGN_INS);
+        assert!(!pending.contains_key("0"));
+        assert!(pending.contains_key(&MAX_PENDING_SIGN_INS.to_string()));
     }
 
-    (headers, Redirect::to("/"))
+    #[test]
+    fn test_pkce_challenge() {
+        // example from RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
 }
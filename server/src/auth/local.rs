@@ -0,0 +1,544 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+};
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use async_session::SessionStore;
+use axum::{
+    extract::{State, TypedHeader},
+    headers::Cookie,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Redirect, Response},
+    Form, Json,
+};
+use serde::{Deserialize, Serialize};
+use tasknet_shared::providers::{LocalAccount, Registration};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+use crate::{
+    auth::UserSessionData,
+    json_file::{self, JsonFileError},
+    server::Server,
+};
+
+use super::{clear_session_cookies, error_page, session_cookie, start_session, UserIdFromSession};
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+const MAX_USERNAME_LENGTH: usize = 64;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LocalConfig {
+    /// File to store the registered accounts in.
+    users_file: PathBuf,
+    /// Who is allowed to create new accounts.
+    #[serde(default)]
+    pub registration: Registration,
+    /// Single-use codes accepted when registration is invite only.
+    #[serde(default)]
+    invite_codes: Vec<String>,
+    /// Usernames that can reset the passwords of other accounts.
+    #[serde(default)]
+    admins: Vec<String>,
+}
+
+impl LocalConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        let dir = self
+            .users_file
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        if !dir.is_dir() {
+            problems.push(format!(
+                "local.users_file {} is not in an existing directory",
+                self.users_file.display()
+            ));
+        }
+        if self.registration == Registration::InviteOnly && self.invite_codes.is_empty() {
+            problems.push(
+                "local.registration is invite_only but there are no local.invite_codes, use disabled instead"
+                    .to_owned(),
+            );
+        }
+    }
+
+    pub fn users_file(&self) -> &Path {
+        &self.users_file
+    }
+
+    fn is_admin(&self, username: &str) -> bool {
+        self.admins.iter().any(|admin| admin == username)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LocalUser {
+    password_hash: String,
+    doc_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    invite_code: Option<String>,
+}
+
+pub struct Local {
+    users_file: PathBuf,
+    users: HashMap<String, LocalUser>,
+}
+
+impl Local {
+    pub fn load(config: &LocalConfig) -> Result<Self, JsonFileError> {
+        let users = json_file::load(&config.users_file)?.unwrap_or_else(|| {
+            debug!(file=?config.users_file, "No local users file, starting empty");
+            HashMap::new()
+        });
+        Ok(Self {
+            users_file: config.users_file.clone(),
+            users,
+        })
+    }
+
+    /// The accounts as stored in the users file.
+    pub fn users_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec_pretty(&self.users)
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        json_file::save(&self.users_file, &self.users)
+    }
+
+    fn invite_code_used(&self, code: &str) -> bool {
+        self.users
+            .values()
+            .any(|user| user.invite_code.as_deref() == Some(code))
+    }
+
+    /// Whether the document belongs to an account, so it can only be opened with the password.
+    pub fn owns_document(&self, doc_id: &str) -> bool {
+        self.users.values().any(|user| user.doc_id == doc_id)
+    }
+}
+
+fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Failed to hash password")
+        .to_string()
+}
+
+/// A hash to verify against for unknown usernames, so signing in takes as long whether or not the
+/// account exists.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not the password of any account"))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Hashing is deliberately slow so keep it off the async workers.
+async fn spawn_hashing<T: Send + 'static>(hashing: impl FnOnce() -> T + Send + 'static) -> T {
+    tokio::task::spawn_blocking(hashing)
+        .await
+        .expect("Password hashing panicked")
+}
+
+fn valid_username(username: &str) -> bool {
+    !username.is_empty()
+        && username.len() <= MAX_USERNAME_LENGTH
+        && username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterForm {
+    username: String,
+    password: String,
+    #[serde(default)]
+    invite_code: String,
+}
+
+pub async fn register_handler(
+    cookie: Option<TypedHeader<Cookie>>,
+    State(server): State<Arc<Mutex<Server>>>,
+    Form(form): Form<RegisterForm>,
+) -> Response {
+    debug!(username = form.username, "Local register handler");
+
+    if !valid_username(&form.username) {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Usernames may only contain letters, numbers, '-', '_' and '.'.",
+        );
+    }
+    if form.password.len() < MIN_PASSWORD_LENGTH {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Passwords must be at least 8 characters long.",
+        );
+    }
+
+    let password = form.password;
+    let password_hash = spawn_hashing(move || hash_password(&password)).await;
+
+    let mut server = server.lock().await;
+    let Some(config) = server.config.local.as_ref() else {
+        return Redirect::to("/").into_response();
+    };
+
+    let invite_code = match config.registration {
+        Registration::Open => None,
+        Registration::Disabled => {
+            return error_page(
+                StatusCode::FORBIDDEN,
+                "Registration of new accounts is disabled.",
+            );
+        }
+        Registration::InviteOnly => {
+            if !config.invite_codes.contains(&form.invite_code) {
+                return error_page(StatusCode::FORBIDDEN, "That invite code is not valid.");
+            }
+            Some(form.invite_code)
+        }
+    };
+
+    let Some(local) = server.local.as_mut() else {
+        return Redirect::to("/").into_response();
+    };
+
+    if let Some(code) = &invite_code {
+        if local.invite_code_used(code) {
+            return error_page(
+                StatusCode::FORBIDDEN,
+                "That invite code has already been used.",
+            );
+        }
+    }
+
+    if local.users.contains_key(&form.username) {
+        return error_page(StatusCode::CONFLICT, "That username is already taken.");
+    }
+
+    // kept apart from public document ids so they can't be signed in to without the password
+    let doc_id = format!("local-{}", uuid::Uuid::new_v4());
+    local.users.insert(
+        form.username.clone(),
+        LocalUser {
+            password_hash,
+            doc_id: doc_id.clone(),
+            invite_code,
+        },
+    );
+    if let Err(err) = local.save() {
+        warn!(%err, "Failed to save local users");
+        local.users.remove(&form.username);
+        return error_page(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to create the account, please try again later.",
+        );
+    }
+
+    let user_data = UserSessionData::Local {
+        username: form.username,
+        doc_id,
+    };
+    let headers = start_session(&server, session_cookie(&cookie), &user_data).await;
+    (headers, Redirect::to("/")).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SignInForm {
+    username: String,
+    password: String,
+}
+
+pub async fn sign_in_handler(
+    cookie: Option<TypedHeader<Cookie>>,
+    State(server): State<Arc<Mutex<Server>>>,
+    Form(form): Form<SignInForm>,
+) -> Response {
+    debug!(username = form.username, "Local sign in handler");
+
+    let user = {
+        let server = server.lock().await;
+        let Some(local) = server.local.as_ref() else {
+            return Redirect::to("/").into_response();
+        };
+        local.users.get(&form.username).cloned()
+    };
+
+    // verify outside of the lock as hashing is deliberately slow
+    let password_hash = user.as_ref().map(|user| user.password_hash.clone());
+    let verified = spawn_hashing(move || match password_hash {
+        Some(password_hash) => verify_password(&form.password, &password_hash),
+        None => {
+            verify_password(&form.password, dummy_hash());
+            false
+        }
+    })
+    .await;
+    let Some(user) = user.filter(|_| verified) else {
+        server.lock().await.metrics.auth_failed("local");
+        return error_page(StatusCode::UNAUTHORIZED, "Invalid username or password.");
+    };
+
+    let server = server.lock().await;
+    let user_data = UserSessionData::Local {
+        username: form.username,
+        doc_id: user.doc_id,
+    };
+    let headers = start_session(&server, session_cookie(&cookie), &user_data).await;
+    (headers, Redirect::to("/")).into_response()
+}
+
+pub async fn sign_out_handler(
+    user: UserIdFromSession,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> impl IntoResponse {
+    let server = server.lock().await;
+
+    // remove session
+    if let Ok(Some(session)) = server.sessions.load_session(user.session_cookie).await {
+        server.sessions.destroy_session(session).await.unwrap();
+    }
+
+    let mut headers = HeaderMap::new();
+    // clear cookies
+    clear_session_cookies(&server.config.session, &mut headers).await;
+
+    (headers, Redirect::to("/"))
+}
+
+pub async fn account_handler(
+    user: UserIdFromSession,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Response {
+    let UserSessionData::Local { username, .. } = user.session_data else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let server = server.lock().await;
+    let admin = server
+        .config
+        .local
+        .as_ref()
+        .is_some_and(|config| config.is_admin(&username));
+    Json(LocalAccount { username, admin }).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordForm {
+    current_password: String,
+    new_password: String,
+}
+
+pub async fn change_password_handler(
+    user: UserIdFromSession,
+    State(server): State<Arc<Mutex<Server>>>,
+    Form(form): Form<ChangePasswordForm>,
+) -> Response {
+    let UserSessionData::Local { username, .. } = user.session_data else {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Only local accounts have a password to change.",
+        );
+    };
+
+    if form.new_password.len() < MIN_PASSWORD_LENGTH {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Passwords must be at least 8 characters long.",
+        );
+    }
+
+    let current_hash = {
+        let server = server.lock().await;
+        server
+            .local
+            .as_ref()
+            .and_then(|local| local.users.get(&username))
+            .map(|user| user.password_hash.clone())
+    };
+    let verified = spawn_hashing(move || {
+        current_hash.is_some_and(|hash| verify_password(&form.current_password, &hash))
+    })
+    .await;
+    if !verified {
+        return error_page(
+            StatusCode::UNAUTHORIZED,
+            "The current password is incorrect.",
+        );
+    }
+
+    let new_password = form.new_password;
+    let password_hash = spawn_hashing(move || hash_password(&new_password)).await;
+    set_password(&server, &username, password_hash).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetPasswordForm {
+    username: String,
+    new_password: String,
+}
+
+pub async fn reset_password_handler(
+    user: UserIdFromSession,
+    State(server): State<Arc<Mutex<Server>>>,
+    Form(form): Form<ResetPasswordForm>,
+) -> Response {
+    let is_admin = match &user.session_data {
+        UserSessionData::Local { username, .. } => {
+            let server = server.lock().await;
+            server
+                .config
+                .local
+                .as_ref()
+                .is_some_and(|config| config.is_admin(username))
+        }
+        UserSessionData::Google { .. } | UserSessionData::Public { .. } => false,
+    };
+    if !is_admin {
+        return error_page(
+            StatusCode::FORBIDDEN,
+            "Only administrators can reset passwords.",
+        );
+    }
+
+    if form.new_password.len() < MIN_PASSWORD_LENGTH {
+        return error_page(
+            StatusCode::BAD_REQUEST,
+            "Passwords must be at least 8 characters long.",
+        );
+    }
+
+    debug!(username = form.username, "Admin resetting password");
+    let new_password = form.new_password;
+    let password_hash = spawn_hashing(move || hash_password(&new_password)).await;
+    let response = set_password(&server, &form.username, password_hash).await;
+
+    // whoever knew the old password shouldn't stay signed in
+    let server = server.lock().await;
+    let doc_id = server
+        .local
+        .as_ref()
+        .and_then(|local| local.users.get(&form.username))
+        .map(|user| user.doc_id.clone());
+    if let Some(doc_id) = doc_id {
+        let user_data = UserSessionData::Local {
+            username: form.username,
+            doc_id,
+        };
+        let revoked = server.sessions.revoke_all(&user_data).await;
+        debug!(revoked, "Revoked sessions after password reset");
+    }
+    response
+}
+
+async fn set_password(server: &Mutex<Server>, username: &str, password_hash: String) -> Response {
+    let mut server = server.lock().await;
+    let Some(local) = server.local.as_mut() else {
+        return Redirect::to("/").into_response();
+    };
+    let Some(user) = local.users.get_mut(username) else {
+        return error_page(
+            StatusCode::NOT_FOUND,
+            "No account with that username exists.",
+        );
+    };
+    let old_hash = std::mem::replace(&mut user.password_hash, password_hash);
+    if let Err(err) = local.save() {
+        warn!(%err, "Failed to save local users");
+        if let Some(user) = local.users.get_mut(username) {
+            user.password_hash = old_hash;
+        }
+        return error_page(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to update the password, please try again later.",
+        );
+    }
+    Redirect::to("/").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::harness::TestServer;
+
+    #[test]
+    fn test_password_roundtrip() {
+        let hash = hash_password("correct horse battery staple");
+        assert!(verify_password("correct horse battery staple", &hash));
+        assert!(!verify_password("incorrect horse battery staple", &hash));
+        assert!(!verify_password("anything", "not a hash"));
+    }
+
+    #[tokio::test]
+    async fn test_public_sign_in_refuses_local_documents() {
+        let users_file =
+            std::env::temp_dir().join(format!("tasknet-users-{}.json", uuid::Uuid::new_v4()));
+        let server = TestServer::start_with(|config| {
+            config.local = Some(LocalConfig {
+                users_file: users_file.clone(),
+                registration: Registration::Open,
+                ..LocalConfig::default()
+            });
+        })
+        .await;
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = client
+            .post(format!("http://{}/auth/local/register", server.address))
+            .form(&[("username", "alice"), ("password", "correct horse")])
+            .send()
+            .await
+            .unwrap();
+        assert!(res.status().is_redirection());
+
+        let doc_id = {
+            let mut server = server.server.lock().await;
+            let local = server.local.as_mut().unwrap();
+            // accounts registered before local documents had their own ids
+            local.users.insert(
+                "bob".to_owned(),
+                LocalUser {
+                    password_hash: hash_password("battery staple"),
+                    doc_id: uuid::Uuid::new_v4().to_string(),
+                    invite_code: None,
+                },
+            );
+            local.users["alice"].doc_id.clone()
+        };
+        assert!(doc_id.starts_with("local-"));
+        assert!(server.sign_in_public(&doc_id).await.is_empty());
+        let legacy_doc_id = server.server.lock().await.local.as_ref().unwrap().users["bob"]
+            .doc_id
+            .clone();
+        assert!(server.sign_in_public(&legacy_doc_id).await.is_empty());
+        assert!(!server
+            .sign_in_public(&uuid::Uuid::new_v4().to_string())
+            .await
+            .is_empty());
+
+        let _ = std::fs::remove_file(users_file);
+    }
+
+    #[test]
+    fn test_valid_username() {
+        assert!(valid_username("alice.smith-1_2"));
+        assert!(!valid_username(""));
+        assert!(!valid_username("alice smith"));
+        assert!(!valid_username(&"a".repeat(MAX_USERNAME_LENGTH + 1)));
+    }
+}
@@ -1,40 +1,29 @@
 use std::sync::Arc;
 
-use async_session::{Session, SessionStore};
+use async_session::SessionStore;
+use axum::headers::Cookie;
 use axum::http::StatusCode;
 use axum::{
-    extract::{Query, State},
+    extract::{Query, State, TypedHeader},
     http::HeaderMap,
     response::{IntoResponse, Redirect},
 };
-use reqwest::header::SET_COOKIE;
-use serde::{Deserialize, Serialize};
-use tasknet_shared::cookies::{AUTH_PROVIDER_COOKIE, DOCUMENT_ID_COOKIE, SESSION_COOKIE};
+use serde::Deserialize;
 use tokio::sync::Mutex;
 use tracing::debug;
 
 use crate::{auth::UserSessionData, server::Server};
 
-use super::{clear_session_cookies, UserIdFromSession};
-
-#[derive(Debug, Default, Serialize, Deserialize)]
-pub struct PublicConfig {
-    client_id: String,
-    client_secret: String,
-    issuer_uri: String,
-    redirect_uri: String,
-    scopes: Vec<String>,
-}
+use super::{clear_session_cookies, session_cookie, start_session, UserIdFromSession};
 
 pub async fn sign_in_handler(
     Query(query): Query<DocId>,
+    cookie: Option<TypedHeader<Cookie>>,
     State(server): State<Arc<Mutex<Server>>>,
 ) -> impl IntoResponse {
     debug!("Public sign in handler");
     let server = server.lock().await;
 
-    let mut headers = HeaderMap::new();
-
     let doc_id = if query.doc_id.is_empty() {
         // generate one
         uuid::Uuid::new_v4()
@@ -46,36 +35,19 @@ pub async fn sign_in_handler(
         };
         doc_id
     };
+    if server
+        .local
+        .as_ref()
+        .is_some_and(|local| local.owns_document(&doc_id.to_string()))
+    {
+        // only the account's password opens its document
+        return Err((StatusCode::FORBIDDEN, Redirect::to("/")));
+    }
 
-    // Create a new session filled with user data
-    let mut session = Session::new();
     let user_data = UserSessionData::Public {
         doc_id: doc_id.to_string(),
     };
-    session.insert("user_data", &user_data).unwrap();
-
-    // Store session and get corresponding cookie
-    let session_cookie = server
-        .sessions
-        .store_session(session)
-        .await
-        .unwrap()
-        .unwrap();
-
-    // Build the cookie
-    let cookies = vec![
-        format!(
-            "{}={}; SameSite=Lax; Path=/",
-            SESSION_COOKIE, session_cookie
-        ),
-        format!("{}={}; Path=/", AUTH_PROVIDER_COOKIE, "public"),
-        format!("{}={}; Path=/", DOCUMENT_ID_COOKIE, user_data.doc_id()),
-    ];
-
-    // Set cookies
-    for cookie in cookies {
-        headers.append(SET_COOKIE, cookie.parse().unwrap());
-    }
+    let headers = start_session(&server, session_cookie(&cookie), &user_data).await;
 
     Ok((headers, Redirect::to("/")))
 }
@@ -93,7 +65,7 @@ pub async fn sign_out_handler(
 
     let mut headers = HeaderMap::new();
     // clear cookies
-    clear_session_cookies(&mut headers).await;
+    clear_session_cookies(&server.config.session, &mut headers).await;
 
     (headers, Redirect::to("/"))
 }
@@ -0,0 +1,321 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use async_session::{async_trait, Session, SessionStore};
+use axum::{
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use tasknet_shared::sessions::SessionInfo;
+use tokio::sync::{watch, Mutex, RwLock};
+use tracing::debug;
+
+use crate::server::Server;
+
+use super::{UserIdFromSession, UserSessionData};
+
+/// A session along with what we know about the device using it.
+#[derive(Debug)]
+struct TrackedSession {
+    session: Session,
+    created: SystemTime,
+    last_seen: SystemTime,
+    user_agent: Option<String>,
+    websockets: usize,
+    /// Dropped when the session ends, closing the websockets using it.
+    ended: watch::Sender<()>,
+}
+
+impl TrackedSession {
+    fn user_data(&self) -> Option<UserSessionData> {
+        self.session.get("user_data")
+    }
+}
+
+/// The session store, tracking the devices signed in to each identity.
+///
+/// Sessions are only kept in memory so everyone is signed out when the server restarts.
+#[derive(Debug, Clone, Default)]
+pub struct Sessions {
+    inner: Arc<RwLock<HashMap<String, TrackedSession>>>,
+}
+
+#[async_trait]
+impl SessionStore for Sessions {
+    async fn load_session(&self, cookie_value: String) -> async_session::Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        Ok(self
+            .inner
+            .read()
+            .await
+            .get(&id)
+            .map(|tracked| tracked.session.clone())
+            .and_then(Session::validate))
+    }
+
+    async fn store_session(&self, session: Session) -> async_session::Result<Option<String>> {
+        match self.inner.write().await.entry(session.id().to_owned()) {
+            Entry::Occupied(mut entry) => {
+                entry.get_mut().session = session.clone();
+            }
+            Entry::Vacant(entry) => {
+                let now = SystemTime::now();
+                entry.insert(TrackedSession {
+                    session: session.clone(),
+                    created: now,
+                    last_seen: now,
+                    user_agent: None,
+                    websockets: 0,
+                    ended: watch::channel(()).0,
+                });
+            }
+        }
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> async_session::Result {
+        self.inner.write().await.remove(session.id());
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> async_session::Result {
+        self.inner.write().await.clear();
+        Ok(())
+    }
+}
+
+impl Sessions {
+    /// Remove sessions that have expired, closing any websockets still using them.
+    pub async fn cleanup(&self) {
+        let mut inner = self.inner.write().await;
+        let before = inner.len();
+        inner.retain(|_, tracked| !tracked.session.is_expired());
+        debug!(
+            removed = before - inner.len(),
+            "Cleaned up expired sessions"
+        );
+    }
+
+    pub async fn count(&self) -> usize {
+        self.inner.read().await.len()
+    }
+
+    /// Record that the session was just used from a device with the given user agent.
+    pub async fn touch(&self, id: &str, user_agent: Option<&str>) {
+        if let Some(tracked) = self.inner.write().await.get_mut(id) {
+            tracked.last_seen = SystemTime::now();
+            if user_agent.is_some() {
+                tracked.user_agent = user_agent.map(ToOwned::to_owned);
+            }
+        }
+    }
+
+    /// Register a websocket using the session.
+    ///
+    /// The returned receiver is notified of closure when the session ends.
+    pub async fn connect_websocket(&self, id: &str) -> Option<watch::Receiver<()>> {
+        let mut inner = self.inner.write().await;
+        let tracked = inner.get_mut(id)?;
+        tracked.websockets += 1;
+        tracked.last_seen = SystemTime::now();
+        Some(tracked.ended.subscribe())
+    }
+
+    pub async fn disconnect_websocket(&self, id: &str) {
+        if let Some(tracked) = self.inner.write().await.get_mut(id) {
+            tracked.websockets = tracked.websockets.saturating_sub(1);
+            tracked.last_seen = SystemTime::now();
+        }
+    }
+
+    /// List the live sessions belonging to the same identity as `user_data`.
+    pub async fn list(&self, user_data: &UserSessionData, current_id: &str) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .inner
+            .read()
+            .await
+            .iter()
+            .filter(|(id, tracked)| {
+                !tracked.session.is_expired()
+                    && tracked.user_data().as_ref() == Some(user_data)
+                    && visible(user_data, id, current_id)
+            })
+            .map(|(id, tracked)| SessionInfo {
+                id: id.clone(),
+                current: id == current_id,
+                created: unix_seconds(tracked.created),
+                last_seen: unix_seconds(tracked.last_seen),
+                user_agent: tracked.user_agent.clone(),
+                websockets: tracked.websockets,
+            })
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+        sessions
+    }
+
+    /// End every session belonging to the identity, returning how many there were.
+    pub async fn revoke_all(&self, user_data: &UserSessionData) -> usize {
+        let mut inner = self.inner.write().await;
+        let before = inner.len();
+        inner.retain(|_, tracked| tracked.user_data().as_ref() != Some(user_data));
+        before - inner.len()
+    }
+
+    /// End the session with the given id if it belongs to the same identity as `user_data`.
+    pub async fn revoke(&self, id: &str, user_data: &UserSessionData, current_id: &str) -> bool {
+        let mut inner = self.inner.write().await;
+        let owned = visible(user_data, id, current_id)
+            && inner
+                .get(id)
+                .is_some_and(|tracked| tracked.user_data().as_ref() == Some(user_data));
+        if owned {
+            inner.remove(id);
+        }
+        owned
+    }
+}
+
+/// Whether the session can be seen and revoked from the current one.
+///
+/// Everyone who knows a public document's id shares its identity, so they only see their own
+/// session.
+fn visible(user_data: &UserSessionData, id: &str, current_id: &str) -> bool {
+    !matches!(user_data, UserSessionData::Public { .. }) || id == current_id
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+pub async fn list_handler(
+    user: UserIdFromSession,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Json<Vec<SessionInfo>> {
+    let server = server.lock().await;
+    Json(
+        server
+            .sessions
+            .list(&user.session_data, &user.session_id)
+            .await,
+    )
+}
+
+/// Whether the session is still signed in, so clients can tell an expired session apart from the
+/// server being unreachable.
+pub async fn check_handler(user: Result<UserSessionData, (HeaderMap, Response)>) -> Response {
+    match user {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        // the headers clear the session cookies
+        Err((headers, _)) => (StatusCode::UNAUTHORIZED, headers).into_response(),
+    }
+}
+
+pub async fn revoke_handler(
+    user: UserIdFromSession,
+    Path(id): Path<String>,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> StatusCode {
+    let server = server.lock().await;
+    if server
+        .sessions
+        .revoke(&id, &user.session_data, &user.session_id)
+        .await
+    {
+        debug!(id, "Revoked session");
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_session::{Session, SessionStore};
+    use reqwest::{header::COOKIE, StatusCode};
+
+    use super::{Sessions, UserSessionData};
+    use crate::harness::TestServer;
+
+    async fn store(sessions: &Sessions, user_data: &UserSessionData) -> String {
+        let mut session = Session::new();
+        session.insert("user_data", user_data).unwrap();
+        let id = session.id().to_owned();
+        sessions.store_session(session).await.unwrap();
+        id
+    }
+
+    #[tokio::test]
+    async fn test_revoke_all() {
+        let sessions = Sessions::default();
+        let alice = UserSessionData::Local {
+            username: "alice".to_owned(),
+            doc_id: "a".to_owned(),
+        };
+        let bob = UserSessionData::Local {
+            username: "bob".to_owned(),
+            doc_id: "b".to_owned(),
+        };
+        store(&sessions, &alice).await;
+        store(&sessions, &alice).await;
+        let bob_id = store(&sessions, &bob).await;
+
+        assert_eq!(sessions.revoke_all(&alice).await, 2);
+        assert_eq!(sessions.count().await, 1);
+        assert_eq!(sessions.list(&bob, &bob_id).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_public_sessions_are_private() {
+        let sessions = Sessions::default();
+        let public = UserSessionData::Public {
+            doc_id: "doc".to_owned(),
+        };
+        let mine = store(&sessions, &public).await;
+        let theirs = store(&sessions, &public).await;
+
+        let listed = sessions.list(&public, &mine).await;
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, mine);
+        assert!(!sessions.revoke(&theirs, &public, &mine).await);
+        assert!(sessions.revoke(&mine, &public, &mine).await);
+        assert_eq!(sessions.count().await, 1);
+    }
+
+    #[tokio::test]
+    async fn test_check_session() {
+        let server = TestServer::start().await;
+        let cookies = server
+            .sign_in_public(&uuid::Uuid::new_v4().to_string())
+            .await;
+        let client = reqwest::Client::new();
+        let check = |cookies: String| {
+            client
+                .get(format!("http://{}/auth/session", server.address))
+                .header(COOKIE, cookies)
+                .send()
+        };
+
+        let res = check(cookies.clone()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = check(String::new()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        client
+            .get(format!("http://{}/auth/public/sign_out", server.address))
+            .header(COOKIE, cookies.clone())
+            .send()
+            .await
+            .unwrap();
+        let res = check(cookies).await.unwrap();
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,687 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use automerge_persistent::Persister;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    admin::DocumentsLock, config::ServerConfig, encrypted::EncryptedDocument, server::Server,
+    storage::Storage,
+};
+
+const ARCHIVE_PREFIX: &str = "tasknet-";
+const ARCHIVE_EXTENSION: &str = ".tar.gz";
+const CHECKSUM_EXTENSION: &str = ".sha256";
+const TIMESTAMP_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+const MANIFEST_FILE: &str = "manifest.json";
+const DOCUMENTS_DIR: &str = "documents/";
+const ENCRYPTED_DIR: &str = "encrypted/";
+const USERS_FILE: &str = "users.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+    /// Where to write the archives.
+    pub dir: PathBuf,
+    /// How often to take a backup.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    #[serde(default)]
+    pub keep: Retention,
+}
+
+const fn default_interval_secs() -> u64 {
+    60 * 60
+}
+
+/// How many backups to keep, the newest in each of the most recent hours, days and weeks.
+///
+/// A backup is kept if any of the rules keeps it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Retention {
+    pub hourly: usize,
+    pub daily: usize,
+    pub weekly: usize,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Self {
+            hourly: 24,
+            daily: 7,
+            weekly: 4,
+        }
+    }
+}
+
+impl BackupConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.dir.exists() && !self.dir.is_dir() {
+            problems.push(format!(
+                "backup.dir {} exists but is not a directory",
+                self.dir.display()
+            ));
+        }
+        if self.interval_secs == 0 {
+            problems.push("backup.interval_secs must be more than 0".to_owned());
+        }
+        if self.keep.hourly + self.keep.daily + self.keep.weekly == 0 {
+            problems.push("backup.keep must keep at least one backup".to_owned());
+        }
+    }
+}
+
+/// Lists what is in an archive, with the checksum of each file.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    created: DateTime<Utc>,
+    files: BTreeMap<String, String>,
+}
+
+/// The contents of a backup.
+#[derive(Debug)]
+pub struct Snapshot {
+    created: DateTime<Utc>,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+/// What the running server holds in memory, copied under its lock so the backup has the latest
+/// changes without keeping the server waiting while the rest is read from storage.
+#[derive(Debug, Default)]
+struct Loaded {
+    documents: HashMap<String, Vec<u8>>,
+    encrypted: HashMap<String, Vec<u8>>,
+    users: Option<Vec<u8>>,
+}
+
+impl Loaded {
+    fn copy(server: &mut Server) -> Result<Self, String> {
+        let documents = server
+            .documents
+            .iter_mut()
+            .map(|(id, document)| (id.clone(), document.document_mut().save()))
+            .collect();
+        let encrypted = server
+            .encrypted_documents
+            .iter()
+            .map(|(id, document)| {
+                document
+                    .to_bytes()
+                    .map(|bytes| (id.clone(), bytes))
+                    .map_err(|err| format!("Failed to encode encrypted document {id}: {err}"))
+            })
+            .collect::<Result<_, _>>()?;
+        let users = server
+            .local
+            .as_ref()
+            .map(crate::auth::local::Local::users_json)
+            .transpose()
+            .map_err(|err| format!("Failed to serialize users: {err}"))?;
+        Ok(Self {
+            documents,
+            encrypted,
+            users,
+        })
+    }
+}
+
+impl Snapshot {
+    /// Collect every document, using the copies from memory where there are some and reading
+    /// the rest from storage.
+    fn take(storage: &Storage, documents_dir: &Path, loaded: Loaded) -> Result<Self, String> {
+        let Loaded {
+            mut documents,
+            mut encrypted,
+            users,
+        } = loaded;
+        let mut files = Vec::new();
+        let ids = storage
+            .document_ids()
+            .map_err(|err| format!("Failed to list documents: {err}"))?;
+        for id in ids {
+            let bytes = match documents.remove(&id) {
+                Some(bytes) => bytes,
+                None => {
+                    let persister = storage
+                        .persister(&id)
+                        .map_err(|err| format!("Failed to open document {id}: {err}"))?;
+                    automerge_persistent::PersistentAutomerge::load(persister)
+                        .map_err(|err| format!("Failed to load document {id}: {err}"))?
+                        .document_mut()
+                        .save()
+                }
+            };
+            files.push((format!("{DOCUMENTS_DIR}{id}"), bytes));
+        }
+
+        let entries = std::fs::read_dir(documents_dir)
+            .map_err(|err| format!("Failed to read {}: {err}", documents_dir.display()))?;
+        for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            if !EncryptedDocument::is_log(&path) {
+                continue;
+            }
+            let Some(id) = path.file_stem().map(|id| id.to_string_lossy().into_owned()) else {
+                continue;
+            };
+            let bytes = match encrypted.remove(&id) {
+                Some(bytes) => bytes,
+                None => std::fs::read(&path)
+                    .map_err(|err| format!("Failed to read {}: {err}", path.display()))?,
+            };
+            files.push((format!("{ENCRYPTED_DIR}{id}"), bytes));
+        }
+
+        if let Some(users) = users {
+            files.push((USERS_FILE.to_owned(), users));
+        }
+
+        Ok(Self {
+            created: Utc::now(),
+            files,
+        })
+    }
+
+    /// Write the snapshot as a compressed archive in the directory, along with its checksum.
+    fn write(self, dir: &Path) -> std::io::Result<PathBuf> {
+        std::fs::create_dir_all(dir)?;
+        let name = archive_name(self.created);
+        let path = dir.join(&name);
+
+        let manifest = Manifest {
+            created: self.created,
+            files: self
+                .files
+                .iter()
+                .map(|(name, bytes)| (name.clone(), sha256_hex(bytes)))
+                .collect(),
+        };
+
+        // write to a temporary file first so a crash never leaves a truncated archive
+        let tmp_path = path.with_extension("tmp");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&tmp_path)?,
+            Compression::default(),
+        ));
+        let mut append = |name: &str, bytes: &[u8]| {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o600);
+            header.set_mtime(self.created.timestamp().try_into().unwrap_or_default());
+            header.set_cksum();
+            builder.append_data(&mut header, name, bytes)
+        };
+        append(MANIFEST_FILE, &serde_json::to_vec_pretty(&manifest)?)?;
+        for (name, bytes) in &self.files {
+            append(name, bytes)?;
+        }
+        builder.into_inner()?.finish()?.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        // in the format sha256sum understands, so backups can be checked without the server
+        let checksum = sha256_hex(&std::fs::read(&path)?);
+        std::fs::write(checksum_path(&path), format!("{checksum}  {name}\n"))?;
+        Ok(path)
+    }
+}
+
+fn archive_name(created: DateTime<Utc>) -> String {
+    format!(
+        "{ARCHIVE_PREFIX}{}{ARCHIVE_EXTENSION}",
+        created.format(TIMESTAMP_FORMAT)
+    )
+}
+
+fn checksum_path(archive: &Path) -> PathBuf {
+    let mut name = archive.as_os_str().to_owned();
+    name.push(CHECKSUM_EXTENSION);
+    PathBuf::from(name)
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    crate::hex::encode(&Sha256::digest(bytes))
+}
+
+/// The backups in the directory along with when they were taken, newest first.
+fn list_backups(dir: &Path) -> std::io::Result<Vec<(DateTime<Utc>, PathBuf)>> {
+    let mut backups = std::fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let timestamp = name
+                .strip_prefix(ARCHIVE_PREFIX)?
+                .strip_suffix(ARCHIVE_EXTENSION)?;
+            let created = chrono::NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+                .ok()?
+                .and_utc();
+            Some((created, entry.path()))
+        })
+        .collect::<Vec<_>>();
+    backups.sort_by_key(|(created, _)| std::cmp::Reverse(*created));
+    Ok(backups)
+}
+
+/// Identifies the hour, day or week a backup was taken in.
+type Period = fn(&DateTime<Utc>) -> (i32, u32, u32);
+
+/// Which of the backups, given newest first, the retention rules keep.
+fn kept(created: &[DateTime<Utc>], retention: Retention) -> HashSet<usize> {
+    let rules: [(usize, Period); 3] = [
+        (retention.hourly, |t| (t.year(), t.ordinal(), t.hour())),
+        (retention.daily, |t| (t.year(), t.ordinal(), 0)),
+        (retention.weekly, |t| {
+            let week = t.iso_week();
+            (week.year(), week.week(), 0)
+        }),
+    ];
+    let mut kept = HashSet::new();
+    for (count, period) in rules {
+        let mut periods = HashSet::new();
+        for (i, created) in created.iter().enumerate() {
+            if periods.len() == count {
+                break;
+            }
+            // the first backup seen in each period is its newest
+            if periods.insert(period(created)) {
+                kept.insert(i);
+            }
+        }
+    }
+    kept
+}
+
+/// Delete the backups the retention rules no longer keep.
+fn prune(dir: &Path, retention: Retention) -> std::io::Result<()> {
+    let backups = list_backups(dir)?;
+    let created = backups
+        .iter()
+        .map(|(created, _)| *created)
+        .collect::<Vec<_>>();
+    let kept = kept(&created, retention);
+    for (i, (_, path)) in backups.iter().enumerate() {
+        if kept.contains(&i) {
+            continue;
+        }
+        debug!(?path, "Removing old backup");
+        std::fs::remove_file(path)?;
+        let checksum = checksum_path(path);
+        if checksum.exists() {
+            std::fs::remove_file(checksum)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read an archive, checking it against its checksum file and manifest.
+fn read_archive(path: &Path) -> Result<(Manifest, HashMap<String, Vec<u8>>), String> {
+    let bytes =
+        std::fs::read(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    match std::fs::read_to_string(checksum_path(path)) {
+        Ok(checksum) => {
+            let expected = checksum.split_whitespace().next().unwrap_or_default();
+            if expected != sha256_hex(&bytes) {
+                return Err(format!(
+                    "{} does not match its checksum, it may be corrupt",
+                    path.display()
+                ));
+            }
+        }
+        Err(err) => warn!(%err, ?path, "No checksum file for backup, relying on the manifest"),
+    }
+
+    let mut files = HashMap::new();
+    let mut archive = tar::Archive::new(GzDecoder::new(bytes.as_slice()));
+    let entries = archive
+        .entries()
+        .map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    for entry in entries {
+        let mut entry = entry.map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+        let name = entry
+            .path()
+            .map_err(|err| format!("Failed to read {}: {err}", path.display()))?
+            .to_string_lossy()
+            .into_owned();
+        let mut content = Vec::new();
+        entry
+            .read_to_end(&mut content)
+            .map_err(|err| format!("Failed to read {name} from {}: {err}", path.display()))?;
+        files.insert(name, content);
+    }
+
+    let manifest: Manifest = files
+        .remove(MANIFEST_FILE)
+        .ok_or_else(|| format!("{} has no manifest", path.display()))
+        .and_then(|manifest| {
+            serde_json::from_slice(&manifest)
+                .map_err(|err| format!("Failed to parse the manifest: {err}"))
+        })?;
+    for (name, checksum) in &manifest.files {
+        match files.get(name) {
+            Some(content) if sha256_hex(content) == *checksum => {}
+            Some(_) => return Err(format!("{name} does not match its checksum")),
+            None => return Err(format!("{name} is missing from the archive")),
+        }
+    }
+    Ok((manifest, files))
+}
+
+/// Take a backup of the running server and prune old ones.
+async fn backup(server: &Mutex<Server>, config: &BackupConfig) -> Result<PathBuf, String> {
+    let (loaded, storage, documents_dir) = {
+        let mut server = server.lock().await;
+        (
+            Loaded::copy(&mut server)?,
+            server.storage.clone(),
+            server.config.documents_dir.clone(),
+        )
+    };
+    let config = config.clone();
+    tokio::task::spawn_blocking(move || {
+        let snapshot = Snapshot::take(&storage, &documents_dir, loaded)?;
+        let path = snapshot
+            .write(&config.dir)
+            .map_err(|err| format!("Failed to write backup: {err}"))?;
+        prune(&config.dir, config.keep).map_err(|err| format!("Failed to prune backups: {err}"))?;
+        Ok(path)
+    })
+    .await
+    .map_err(|err| format!("Backup task failed: {err}"))?
+}
+
+/// Take backups on the configured interval.
+pub async fn schedule(server: Arc<Mutex<Server>>, config: BackupConfig) {
+    let period = Duration::from_secs(config.interval_secs);
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    loop {
+        interval.tick().await;
+        match backup(&server, &config).await {
+            Ok(path) => info!(?path, "Backed up documents"),
+            Err(err) => warn!(%err, "Failed to back up documents"),
+        }
+    }
+}
+
+/// Commands for the backups in the configured directory.
+#[derive(Debug, clap::Subcommand)]
+pub enum BackupsCommand {
+    /// Take a backup now, while the server is stopped.
+    Create,
+    /// List the backups, newest first.
+    List,
+    /// Check a backup against its checksums.
+    Verify { archive: PathBuf },
+    /// Restore documents from a backup, replacing the current copies.
+    Restore {
+        archive: PathBuf,
+        /// Only restore this document, otherwise everything in the backup is restored.
+        #[clap(long)]
+        document: Option<String>,
+        /// Confirm the current copies should be replaced.
+        #[clap(long)]
+        yes: bool,
+    },
+}
+
+pub fn run(config: &ServerConfig, command: BackupsCommand) -> Result<(), String> {
+    let backup_dir = config
+        .backup
+        .as_ref()
+        .map(|backup| backup.dir.clone())
+        .ok_or_else(|| "No backup section in the config".to_owned())?;
+    match command {
+        BackupsCommand::List => {
+            let backups = list_backups(&backup_dir)
+                .map_err(|err| format!("Failed to read {}: {err}", backup_dir.display()))?;
+            for (created, path) in backups {
+                let size = std::fs::metadata(&path)
+                    .map(|m| m.len())
+                    .unwrap_or_default();
+                println!("{}  {size:>10}  {}", created.to_rfc3339(), path.display());
+            }
+            Ok(())
+        }
+        BackupsCommand::Verify { archive } => {
+            let (manifest, _) = read_archive(&archive)?;
+            println!(
+                "{}: ok, {} files from {}",
+                archive.display(),
+                manifest.files.len(),
+                manifest.created.to_rfc3339()
+            );
+            Ok(())
+        }
+        BackupsCommand::Create => {
+            let _lock = DocumentsLock::acquire(&config.documents_dir)?;
+            let storage = Storage::open(&config.storage, &config.documents_dir)
+                .map_err(|err| format!("Failed to open document storage: {err}"))?;
+            let loaded = Loaded {
+                users: config
+                    .local
+                    .as_ref()
+                    .and_then(|local| std::fs::read(local.users_file()).ok()),
+                ..Loaded::default()
+            };
+            let snapshot = Snapshot::take(&storage, &config.documents_dir, loaded)?;
+            let path = snapshot
+                .write(&backup_dir)
+                .map_err(|err| format!("Failed to write backup: {err}"))?;
+            println!("Backed up to {}", path.display());
+            Ok(())
+        }
+        BackupsCommand::Restore {
+            archive,
+            document,
+            yes,
+        } => {
+            let _lock = DocumentsLock::acquire(&config.documents_dir)?;
+            let (_, files) = read_archive(&archive)?;
+            if !yes {
+                return Err(
+                    "Restoring replaces the current documents, pass --yes to confirm".to_owned(),
+                );
+            }
+            restore(config, &files, document.as_deref())
+        }
+    }
+}
+
+fn restore(
+    config: &ServerConfig,
+    files: &HashMap<String, Vec<u8>>,
+    only: Option<&str>,
+) -> Result<(), String> {
+    let storage = Storage::open(&config.storage, &config.documents_dir)
+        .map_err(|err| format!("Failed to open document storage: {err}"))?;
+    let mut restored = 0;
+    for (name, content) in files {
+        let (id, encrypted) = if let Some(id) = name.strip_prefix(DOCUMENTS_DIR) {
+            (id, false)
+        } else if let Some(id) = name.strip_prefix(ENCRYPTED_DIR) {
+            (id, true)
+        } else {
+            continue;
+        };
+        if only.is_some_and(|only| only != id) {
+            continue;
+        }
+
+        // replace whatever is there now, which may have been encrypted since or not
+        storage
+            .delete(id)
+            .map_err(|err| format!("Failed to remove the current {id}: {err}"))?;
+        let log = EncryptedDocument::path(&config.documents_dir, id);
+        if log.exists() {
+            std::fs::remove_file(&log)
+                .map_err(|err| format!("Failed to remove the current {id}: {err}"))?;
+        }
+
+        if encrypted {
+            std::fs::write(&log, content)
+                .map_err(|err| format!("Failed to restore {id}: {err}"))?;
+        } else {
+            automerge::Automerge::load(content)
+                .map_err(|err| format!("Backup of {id} is not a valid document: {err}"))?;
+            let mut persister = storage
+                .persister(id)
+                .map_err(|err| format!("Failed to create document {id}: {err}"))?;
+            persister
+                .set_document(content.clone())
+                .and_then(|()| persister.flush())
+                .map_err(|err| format!("Failed to restore {id}: {err}"))?;
+        }
+        println!("{id}: restored");
+        restored += 1;
+    }
+
+    match only {
+        Some(id) if restored == 0 => return Err(format!("No document {id} in the backup")),
+        Some(_) => {}
+        None => {
+            if let (Some(local), Some(users)) = (&config.local, files.get(USERS_FILE)) {
+                std::fs::write(local.users_file(), users)
+                    .map_err(|err| format!("Failed to restore users: {err}"))?;
+                println!("users: restored");
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::transaction::Transactable;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::harness::{SyncClient, TestServer};
+
+    #[test]
+    fn test_retention_keeps_newest_per_period() {
+        // every 30 minutes for 3 days, newest first
+        let start = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+        let created = (0..144)
+            .rev()
+            .map(|i| start + chrono::Duration::minutes(30 * i))
+            .collect::<Vec<_>>();
+        let kept = kept(
+            &created,
+            Retention {
+                hourly: 2,
+                daily: 2,
+                weekly: 0,
+            },
+        );
+        let mut kept = kept.into_iter().map(|i| created[i]).collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(
+            kept,
+            vec![
+                Utc.with_ymd_and_hms(2023, 6, 2, 23, 30, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 6, 3, 22, 30, 0).unwrap(),
+                Utc.with_ymd_and_hms(2023, 6, 3, 23, 30, 0).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_backup_and_restore() {
+        let root = std::env::temp_dir().join(format!("tasknet-backup-{}", uuid::Uuid::new_v4()));
+        let documents_dir = root.join("documents");
+        std::fs::create_dir_all(&documents_dir).unwrap();
+        let config: ServerConfig = serde_json::from_value(serde_json::json!({
+            "documents_dir": documents_dir,
+            "backup": { "dir": root.join("backups") },
+        }))
+        .unwrap();
+        let storage = Storage::open(&config.storage, &documents_dir).unwrap();
+
+        let mut document =
+            automerge_persistent::PersistentAutomerge::load(storage.persister("doc").unwrap())
+                .unwrap();
+        document
+            .transact(|tx| automerge::transaction::Transactable::put(tx, automerge::ROOT, "a", 1))
+            .unwrap();
+        document.flush().unwrap();
+        drop(document);
+        std::fs::write(EncryptedDocument::path(&documents_dir, "secret"), "{}\n").unwrap();
+
+        run(&config, BackupsCommand::Create).unwrap();
+        let (_, archive) = list_backups(&root.join("backups")).unwrap().remove(0);
+        run(
+            &config,
+            BackupsCommand::Verify {
+                archive: archive.clone(),
+            },
+        )
+        .unwrap();
+
+        storage.delete("doc").unwrap();
+        std::fs::remove_file(EncryptedDocument::path(&documents_dir, "secret")).unwrap();
+        run(
+            &config,
+            BackupsCommand::Restore {
+                archive: archive.clone(),
+                document: Some("doc".to_owned()),
+                yes: true,
+            },
+        )
+        .unwrap();
+        assert!(storage.exists("doc").unwrap());
+        assert!(!EncryptedDocument::exists(&documents_dir, "secret"));
+
+        run(
+            &config,
+            BackupsCommand::Restore {
+                archive: archive.clone(),
+                document: None,
+                yes: true,
+            },
+        )
+        .unwrap();
+        assert!(EncryptedDocument::exists(&documents_dir, "secret"));
+
+        // a corrupted archive is refused
+        let mut bytes = std::fs::read(&archive).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&archive, bytes).unwrap();
+        assert!(run(&config, BackupsCommand::Verify { archive }).is_err());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_backup_copies_loaded_documents() {
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        client.doc.put(automerge::ROOT, "a", 1).unwrap();
+        client.sync().await;
+
+        let config = BackupConfig {
+            dir: std::env::temp_dir().join(format!("tasknet-backup-{}", uuid::Uuid::new_v4())),
+            interval_secs: default_interval_secs(),
+            keep: Retention::default(),
+        };
+        let archive = backup(&server.server, &config).await.unwrap();
+        let (_, files) = read_archive(&archive).unwrap();
+        let document =
+            automerge::Automerge::load(&files[&format!("{DOCUMENTS_DIR}{doc_id}")]).unwrap();
+        assert_eq!(document.get_heads(), client.doc.get_heads());
+        assert!(!files.contains_key("sessions.json"));
+
+        std::fs::remove_dir_all(config.dir).unwrap();
+    }
+}
@@ -1,27 +1,305 @@
 use std::{
+    fmt::Display,
     fs::File,
     io::Read,
+    net::IpAddr,
     path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
+use tasknet_shared::limits::Limits;
+use tracing::debug;
 
-use crate::auth::google::GoogleConfig;
+use crate::{
+    auth::{google::GoogleConfig, local::LocalConfig, SessionConfig},
+    backup::BackupConfig,
+    limits,
+    metrics::MetricsConfig,
+    reminders::RemindersConfig,
+    replication::ReplicationConfig,
+    storage::StorageConfig,
+    tls::TlsConfig,
+    webhooks::WebhooksConfig,
+    ServerOptions,
+};
+
+/// The config file used when none is given, which is optional.
+pub const DEFAULT_CONFIG_FILE: &str = "config.json";
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ServerConfig {
     pub address: String,
     pub port: u16,
     pub serve_dir: PathBuf,
     pub documents_dir: PathBuf,
+    pub storage: StorageConfig,
+    pub backup: Option<BackupConfig>,
+    pub limits: Limits,
+    pub webhooks: WebhooksConfig,
+    pub reminders: Option<RemindersConfig>,
+    pub replication: Option<ReplicationConfig>,
+
+    pub session: SessionConfig,
+    pub tls: Option<TlsConfig>,
+    pub metrics: Option<MetricsConfig>,
+    /// How long to wait for sync connections to close when shutting down.
+    pub shutdown_timeout_secs: u64,
 
     pub google: Option<GoogleConfig>,
+    pub local: Option<LocalConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_owned(),
+            port: 3000,
+            serve_dir: PathBuf::from("web/dist"),
+            documents_dir: PathBuf::from("documents"),
+            storage: StorageConfig::default(),
+            backup: None,
+            limits: Limits::default(),
+            webhooks: WebhooksConfig::default(),
+            reminders: None,
+            replication: None,
+            session: SessionConfig::default(),
+            tls: None,
+            metrics: None,
+            shutdown_timeout_secs: 10,
+            google: None,
+            local: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read {
+        file: PathBuf,
+        err: std::io::Error,
+    },
+    Parse {
+        file: PathBuf,
+        err: serde_json::Error,
+    },
+    Invalid(Vec<String>),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Read { file, err } => {
+                write!(f, "Failed to read config file {}: {}", file.display(), err)
+            }
+            Self::Parse { file, err } => {
+                write!(f, "Failed to parse config file {}: {}", file.display(), err)
+            }
+            Self::Invalid(problems) => {
+                write!(f, "Invalid configuration:")?;
+                for problem in problems {
+                    write!(f, "\n  - {problem}")?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 impl ServerConfig {
-    pub fn load(file: &Path) -> Self {
+    /// Load the config file and apply overrides from the environment and command line.
+    ///
+    /// The result still needs validating before serving with it.
+    pub fn load(options: &ServerOptions) -> Result<Self, ConfigError> {
+        let file = options
+            .config_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_FILE));
+        let mut config = match Self::read(&file) {
+            Ok(config) => config,
+            // the default file is optional, everything can come from the environment instead
+            Err(ConfigError::Read { err, .. })
+                if options.config_file.is_none() && err.kind() == std::io::ErrorKind::NotFound =>
+            {
+                debug!(file=?file, "No config file, using defaults");
+                Self::default()
+            }
+            Err(err) => return Err(err),
+        };
+        config.apply_overrides(options);
+        Ok(config)
+    }
+
+    fn read(file: &Path) -> Result<Self, ConfigError> {
         let mut bytes = Vec::new();
-        let _ = File::open(file).and_then(|mut f| f.read_to_end(&mut bytes));
-        serde_json::from_slice(&bytes).expect("Failed to read config file")
+        File::open(file)
+            .and_then(|mut f| f.read_to_end(&mut bytes))
+            .map_err(|err| ConfigError::Read {
+                file: file.to_owned(),
+                err,
+            })?;
+        serde_json::from_slice(&bytes).map_err(|err| ConfigError::Parse {
+            file: file.to_owned(),
+            err,
+        })
+    }
+
+    fn apply_overrides(&mut self, options: &ServerOptions) {
+        if let Some(address) = &options.address {
+            self.address = address.clone();
+        }
+        if let Some(port) = options.port {
+            self.port = port;
+        }
+        if let Some(serve_dir) = &options.serve_dir {
+            self.serve_dir = serve_dir.clone();
+        }
+        if let Some(documents_dir) = &options.documents_dir {
+            self.documents_dir = documents_dir.clone();
+        }
+        if options.google_client_id.is_some()
+            || options.google_client_secret.is_some()
+            || options.google_redirect_uri.is_some()
+        {
+            // the overrides alone still enable Google, validation asks for anything else it needs
+            self.google
+                .get_or_insert_with(GoogleConfig::accounts_google)
+                .apply_overrides(
+                    options.google_client_id.as_deref(),
+                    options.google_client_secret.as_deref(),
+                    options.google_redirect_uri.as_deref(),
+                );
+        }
+    }
+
+    /// Check the config makes sense, collecting every problem rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.address.parse::<IpAddr>().is_err() {
+            problems.push(format!(
+                "address {:?} is not an IP address, such as 127.0.0.1 or ::",
+                self.address
+            ));
+        }
+        if self.port == 0 {
+            problems.push("port must not be 0".to_owned());
+        }
+        if !self.serve_dir.is_dir() {
+            problems.push(format!(
+                "serve_dir {} is not a directory, build the web client or point it at the built files",
+                self.serve_dir.display()
+            ));
+        }
+        if self.documents_dir.exists() && !self.documents_dir.is_dir() {
+            problems.push(format!(
+                "documents_dir {} exists but is not a directory",
+                self.documents_dir.display()
+            ));
+        }
+
+        self.storage.validate(&mut problems);
+        if let Some(backup) = &self.backup {
+            backup.validate(&mut problems);
+        }
+        limits::validate(&self.limits, &mut problems);
+        self.webhooks.validate(&mut problems);
+        if let Some(reminders) = &self.reminders {
+            reminders.validate(&mut problems);
+        }
+        if let Some(replication) = &self.replication {
+            replication.validate(&mut problems);
+        }
+        self.session.validate(&mut problems);
+        if let Some(tls) = &self.tls {
+            tls.validate(self.port, &mut problems);
+        }
+        if let Some(metrics) = &self.metrics {
+            metrics.validate(self.port, &mut problems);
+        }
+        if let Some(google) = &self.google {
+            google.validate(&mut problems);
+        }
+        if let Some(local) = &self.local {
+            local.validate(&mut problems);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_validate_collects_problems() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{
+                "address": "localhost",
+                "port": 0,
+                "serve_dir": "does/not/exist",
+                "session": { "same_site": "none", "secure": false }
+            }"#,
+        )
+        .unwrap();
+        let Err(ConfigError::Invalid(problems)) = config.validate() else {
+            panic!("config should be invalid");
+        };
+        assert_eq!(problems.len(), 4, "{problems:?}");
+    }
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let config: ServerConfig = serde_json::from_str(
+            r#"{
+                "google": {
+                    "client_id": "id",
+                    "client_secret": "hunter2",
+                    "issuer_uri": "https://accounts.google.com",
+                    "redirect_uri": "http://localhost:3000/auth/google/callback",
+                    "scopes": []
+                }
+            }"#,
+        )
+        .unwrap();
+        assert!(!format!("{config:?}").contains("hunter2"));
+    }
+
+    #[test]
+    fn test_google_overrides_without_section() {
+        let mut config = ServerConfig::default();
+        config.apply_overrides(&ServerOptions::parse_from([
+            "tasknet-server",
+            "--google-client-id",
+            "id",
+            "--google-client-secret",
+            "hunter2",
+        ]));
+        let google = config
+            .google
+            .as_mut()
+            .expect("overrides should enable google");
+        let mut problems = Vec::new();
+        google.validate(&mut problems);
+        assert_eq!(
+            problems,
+            vec!["google.redirect_uri must be set, such as https://<host>/auth/google/callback"]
+        );
+
+        google.apply_overrides(
+            None,
+            None,
+            Some("https://tasknet.example/auth/google/callback"),
+        );
+        let mut problems = Vec::new();
+        google.validate(&mut problems);
+        assert!(problems.is_empty(), "{problems:?}");
     }
 }
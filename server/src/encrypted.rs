@@ -0,0 +1,582 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket},
+        State, TypedHeader, WebSocketUpgrade,
+    },
+    headers::Cookie,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use tasknet_shared::sync::{
+    EncryptedBlob, EncryptedSyncError, EncryptedSyncMessage, StoredBlob, CLOSE_LIMIT_EXCEEDED,
+    CLOSE_SERVER_RESTARTING, CLOSE_SESSION_ENDED,
+};
+use tokio::sync::{broadcast::error::RecvError, Mutex};
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::UserSessionData,
+    limits::LimitExceeded,
+    metrics::Metrics,
+    server::{session_id, Server},
+};
+
+/// Extension of the files holding the logs of encrypted documents, next to the plaintext document
+/// directories.
+const ENCRYPTED_EXTENSION: &str = "encrypted";
+
+#[derive(Debug)]
+pub enum Error {
+    Rejected(EncryptedSyncError),
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// An end-to-end encrypted document, stored as a log of blobs the server can't read.
+///
+/// The log is kept in a file of json lines, one [`StoredBlob`] per line.
+#[derive(Debug)]
+pub struct EncryptedDocument {
+    path: PathBuf,
+    blobs: Vec<StoredBlob>,
+}
+
+impl EncryptedDocument {
+    pub fn path(documents_dir: &Path, id: &str) -> PathBuf {
+        documents_dir.join(format!("{id}.{ENCRYPTED_EXTENSION}"))
+    }
+
+    /// Whether the file is the log of an encrypted document.
+    pub fn is_log(path: &Path) -> bool {
+        path.is_file()
+            && path
+                .extension()
+                .is_some_and(|ext| ext == ENCRYPTED_EXTENSION)
+    }
+
+    /// Whether the document with the given id has been encrypted.
+    pub fn exists(documents_dir: &Path, id: &str) -> bool {
+        Self::path(documents_dir, id).exists()
+    }
+
+    pub fn load(documents_dir: &Path, id: &str) -> std::io::Result<Self> {
+        let path = Self::path(documents_dir, id);
+        let mut blobs = Vec::new();
+        match File::open(&path) {
+            Ok(f) => {
+                for line in BufReader::new(f).lines() {
+                    let line = line?;
+                    if line.is_empty() {
+                        continue;
+                    }
+                    blobs.push(serde_json::from_str(&line)?);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Self { path, blobs })
+    }
+
+    /// The id of the key the document is currently encrypted with, taken from the oldest blob.
+    pub fn key_id(&self) -> Option<&str> {
+        self.blobs.first().map(|stored| stored.blob.key_id.as_str())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    /// Bytes of ciphertext in the log.
+    pub fn size(&self) -> u64 {
+        self.blobs
+            .iter()
+            .map(|stored| stored.blob.ciphertext.len() as u64)
+            .sum()
+    }
+
+    pub fn latest_seq(&self) -> u64 {
+        self.blobs
+            .iter()
+            .map(|stored| stored.seq)
+            .max()
+            .unwrap_or_default()
+    }
+
+    pub fn blobs_after(&self, after: u64) -> Vec<StoredBlob> {
+        self.blobs
+            .iter()
+            .filter(|stored| stored.seq > after)
+            .cloned()
+            .collect()
+    }
+
+    /// The log as it is stored.
+    pub fn to_bytes(&self) -> std::io::Result<Vec<u8>> {
+        encode_lines(&self.blobs)
+    }
+
+    /// Check the log is in order and uses a single key, as pushing and compacting keep it.
+    pub fn verify(&self) -> Result<(), String> {
+        if let Some(pair) = self
+            .blobs
+            .windows(2)
+            .find(|pair| pair[0].seq >= pair[1].seq)
+        {
+            return Err(format!(
+                "blob {} comes after blob {}",
+                pair[1].seq, pair[0].seq
+            ));
+        }
+        if let Some(stored) = self
+            .blobs
+            .iter()
+            .find(|stored| Some(stored.blob.key_id.as_str()) != self.key_id())
+        {
+            return Err(format!("blob {} uses a different key", stored.seq));
+        }
+        Ok(())
+    }
+
+    /// Append blobs to the log, which must use the document's current key.
+    ///
+    /// Documents are only encrypted by compacting, so pushing to an empty log is rejected.
+    /// Returns the sequence number of the last blob.
+    pub fn push(&mut self, blobs: Vec<EncryptedBlob>) -> Result<u64, Error> {
+        let Some(key_id) = self.key_id() else {
+            return Err(Error::Rejected(EncryptedSyncError::NotEncrypted));
+        };
+        if blobs.iter().any(|blob| blob.key_id != key_id) {
+            return Err(Error::Rejected(EncryptedSyncError::KeyMismatch));
+        }
+
+        let mut seq = self.latest_seq();
+        let stored = blobs
+            .into_iter()
+            .map(|blob| {
+                seq += 1;
+                StoredBlob { seq, blob }
+            })
+            .collect::<Vec<_>>();
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        f.write_all(&encode_lines(&stored)?)?;
+        f.sync_data()?;
+
+        self.blobs.extend(stored);
+        Ok(seq)
+    }
+
+    /// Replace the whole log, which must end at `up_to`, with a snapshot.
+    ///
+    /// The snapshot takes the next sequence number so every client pulls it, and covering the
+    /// whole log keeps it in order and leaves no blobs encrypted with an old key.
+    pub fn compact(&mut self, up_to: u64, snapshot: EncryptedBlob) -> Result<(), Error> {
+        let latest_seq = self.latest_seq();
+        if up_to != latest_seq {
+            return Err(Error::Rejected(EncryptedSyncError::OutOfDate));
+        }
+
+        let blobs = vec![StoredBlob {
+            seq: latest_seq + 1,
+            blob: snapshot,
+        }];
+
+        // write to a temporary file first so a crash never loses the log
+        let tmp_file = self.path.with_extension("tmp");
+        let mut f = File::create(&tmp_file)?;
+        f.write_all(&encode_lines(&blobs)?)?;
+        f.sync_all()?;
+        std::fs::rename(tmp_file, &self.path)?;
+
+        self.blobs = blobs;
+        Ok(())
+    }
+}
+
+fn encode_lines(blobs: &[StoredBlob]) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for blob in blobs {
+        serde_json::to_writer(&mut bytes, blob)?;
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+pub async fn sync_handler(
+    ws: WebSocketUpgrade,
+    user: UserSessionData,
+    cookie: Option<TypedHeader<Cookie>>,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Response {
+    let session_id = session_id(&cookie);
+    if server.lock().await.shutdown.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server restarting").into_response();
+    }
+    ws.on_upgrade(|socket| handle_sync_socket(socket, server, user, session_id))
+}
+
+async fn send(
+    socket: &mut WebSocket,
+    metrics: &Metrics,
+    msg: EncryptedSyncMessage,
+) -> Result<(), axum::Error> {
+    match Vec::try_from(msg) {
+        Ok(bytes) => {
+            metrics.sync_sent(bytes.len());
+            socket.send(Message::Binary(bytes)).await
+        }
+        Err(err) => {
+            warn!(%err, "Failed to convert encrypted sync message to bytes");
+            Ok(())
+        }
+    }
+}
+
+/// Handle a message from the client, returning the reply to send.
+async fn handle_message(
+    server: &Mutex<Server>,
+    doc_id: &str,
+    msg: EncryptedSyncMessage,
+    cursor: &mut Option<u64>,
+) -> Option<EncryptedSyncMessage> {
+    let mut server = server.lock().await;
+    let result = match msg {
+        EncryptedSyncMessage::Pull { after } => {
+            *cursor = Some(after);
+            return None;
+        }
+        EncryptedSyncMessage::Push { blobs } => {
+            debug!(count = blobs.len(), "Pushing encrypted blobs");
+            let max_bytes = server.config.limits.max_document_bytes;
+            server
+                .load_encrypted_document(doc_id)
+                .map_err(Error::Io)
+                .and_then(|document| {
+                    let pushed = blobs
+                        .iter()
+                        .map(|blob| blob.ciphertext.len() as u64)
+                        .sum::<u64>();
+                    // compacting is still allowed as it only ever shrinks the log
+                    if document.size() + pushed > max_bytes {
+                        return Err(Error::Rejected(EncryptedSyncError::TooLarge));
+                    }
+                    document
+                        .push(blobs)
+                        .map(|seq| (false, Some(EncryptedSyncMessage::Pushed { seq })))
+                })
+        }
+        EncryptedSyncMessage::Compact { up_to, snapshot } => {
+            debug!(up_to, "Compacting encrypted document");
+            server
+                .load_encrypted_document(doc_id)
+                .map_err(Error::Io)
+                .and_then(|document| {
+                    // the first snapshot is what converts a plaintext document
+                    let converting = document.is_empty();
+                    document
+                        .compact(up_to, snapshot)
+                        .map(|()| (converting, None))
+                })
+        }
+        EncryptedSyncMessage::Welcome { .. }
+        | EncryptedSyncMessage::Pushed { .. }
+        | EncryptedSyncMessage::Blobs { .. }
+        | EncryptedSyncMessage::Rejected { .. } => {
+            debug!("Ignoring server message from client");
+            return None;
+        }
+    };
+    match result {
+        Ok((converted, reply)) => {
+            if converted {
+                // the server must not keep a readable copy once the document is encrypted, and
+                // this also closes any plaintext connections to it
+                server.remove_plaintext_document(doc_id);
+            }
+            let _ = server.changed.send(());
+            reply
+        }
+        Err(Error::Rejected(error)) => {
+            debug!(?error, "Rejected encrypted sync message");
+            Some(EncryptedSyncMessage::Rejected { error })
+        }
+        Err(Error::Io(err)) => {
+            warn!(id = doc_id, %err, "Failed to store encrypted document");
+            None
+        }
+    }
+}
+
+async fn handle_sync_socket(
+    mut socket: WebSocket,
+    server: Arc<Mutex<Server>>,
+    user: UserSessionData,
+    session_id: String,
+) {
+    let (sessions, metrics, max_message_bytes, shutdown) = {
+        let server = server.lock().await;
+        (
+            server.sessions.clone(),
+            server.metrics.clone(),
+            server.config.limits.max_message_bytes,
+            server.shutdown.clone(),
+        )
+    };
+    let _connection = shutdown.connection();
+    let Some(mut session_ended) = sessions.connect_websocket(&session_id).await else {
+        debug!("Session ended before the encrypted sync connection started");
+        return;
+    };
+    metrics.sync_connections.inc();
+    info!("New encrypted sync connection");
+
+    let doc_id = user.doc_id();
+    let (welcome, mut changed) = {
+        let mut server = server.lock().await;
+        let changed = server.changed.subscribe();
+        match server.load_encrypted_document(doc_id) {
+            Ok(document) => (
+                EncryptedSyncMessage::Welcome {
+                    key_id: document.key_id().map(ToOwned::to_owned),
+                    latest_seq: document.latest_seq(),
+                },
+                changed,
+            ),
+            Err(err) => {
+                warn!(id = doc_id, %err, "Failed to load encrypted document");
+                drop(server);
+                sessions.disconnect_websocket(&session_id).await;
+                metrics.sync_connections.dec();
+                return;
+            }
+        }
+    };
+
+    // the blobs the client has, once it has asked for them
+    let mut cursor = None;
+    if send(&mut socket, &metrics, welcome).await.is_ok() {
+        loop {
+            let reply = tokio::select! {
+                msg = socket.recv() => match msg {
+                    Some(Ok(Message::Binary(bytes))) if bytes.len() > max_message_bytes => {
+                        let err = LimitExceeded::Message {
+                            bytes: bytes.len(),
+                            max: max_message_bytes,
+                        };
+                        info!(%err, "Closing encrypted sync connection over the limits");
+                        let _ = socket
+                            .send(Message::Close(Some(CloseFrame {
+                                code: CLOSE_LIMIT_EXCEEDED,
+                                reason: err.to_string().into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    Some(Ok(Message::Binary(bytes))) => {
+                        metrics.sync_received(bytes.len());
+                        match EncryptedSyncMessage::try_from(bytes.as_slice()) {
+                            Ok(msg) => handle_message(&server, doc_id, msg, &mut cursor).await,
+                            Err(err) => {
+                                warn!(%err, "Failed to parse encrypted sync message");
+                                None
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | None => break,
+                    Some(Ok(_)) => None,
+                    Some(Err(err)) => {
+                        warn!(%err, "Failed to receive message");
+                        None
+                    }
+                },
+                res = changed.recv() => match res {
+                    Ok(()) | Err(RecvError::Lagged(_)) => None,
+                    Err(RecvError::Closed) => break,
+                },
+                () = shutdown.stopping() => {
+                    debug!("server stopping, closing connection");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_SERVER_RESTARTING,
+                            reason: "Server restarting".into(),
+                        })))
+                        .await;
+                    break;
+                }
+                _ = session_ended.changed() => {
+                    debug!("session ended, closing connection");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: CLOSE_SESSION_ENDED,
+                            reason: "Session ended".into(),
+                        })))
+                        .await;
+                    break;
+                }
+            };
+            if let Some(reply) = reply {
+                if send(&mut socket, &metrics, reply).await.is_err() {
+                    break;
+                }
+            }
+
+            // send anything the client hasn't seen yet, including what it just pushed
+            if let Some(after) = cursor {
+                let blobs = match server.lock().await.load_encrypted_document(doc_id) {
+                    Ok(document) => document.blobs_after(after),
+                    Err(err) => {
+                        warn!(id = doc_id, %err, "Failed to load encrypted document");
+                        break;
+                    }
+                };
+                if let Some(latest) = blobs.iter().map(|stored| stored.seq).max() {
+                    cursor = Some(latest);
+                    if send(&mut socket, &metrics, EncryptedSyncMessage::Blobs { blobs })
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    sessions.disconnect_websocket(&session_id).await;
+    metrics.sync_connections.dec();
+    info!("Closed encrypted sync connection");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use tasknet_shared::{sync::CLOSE_DOCUMENT_ENCRYPTED, task::Task};
+
+    use super::*;
+    use crate::harness::{SyncClient, TestServer};
+
+    fn blob(key_id: &str, ciphertext: &[u8]) -> EncryptedBlob {
+        EncryptedBlob {
+            key_id: key_id.to_owned(),
+            nonce: vec![0; 24],
+            ciphertext: ciphertext.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_push_compact_and_reload() {
+        let dir = std::env::temp_dir().join(format!("tasknet-encrypted-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut document = EncryptedDocument::load(&dir, "doc").unwrap();
+        assert_eq!(document.key_id(), None);
+        // only a snapshot can start the log
+        assert!(matches!(
+            document.push(vec![blob("a", b"1")]),
+            Err(Error::Rejected(EncryptedSyncError::NotEncrypted))
+        ));
+        document.compact(0, blob("a", b"0")).unwrap();
+        assert_eq!(
+            document
+                .push(vec![blob("a", b"1"), blob("a", b"2")])
+                .unwrap(),
+            3
+        );
+        assert!(matches!(
+            document.push(vec![blob("b", b"3")]),
+            Err(Error::Rejected(EncryptedSyncError::KeyMismatch))
+        ));
+        assert_eq!(document.latest_seq(), 3);
+
+        // compacting has to cover everything, whether or not the key changes
+        for key_id in ["a", "b"] {
+            assert!(matches!(
+                document.compact(2, blob(key_id, b"snapshot")),
+                Err(Error::Rejected(EncryptedSyncError::OutOfDate))
+            ));
+        }
+        document.verify().unwrap();
+        document.compact(3, blob("b", b"snapshot")).unwrap();
+        document.verify().unwrap();
+        document.push(vec![blob("b", b"4")]).unwrap();
+
+        let document = EncryptedDocument::load(&dir, "doc").unwrap();
+        assert_eq!(document.key_id(), Some("b"));
+        assert_eq!(document.latest_seq(), 5);
+        assert_eq!(document.size(), 9);
+        let seqs = document
+            .blobs_after(0)
+            .iter()
+            .map(|stored| stored.seq)
+            .collect::<Vec<_>>();
+        assert_eq!(seqs, vec![4, 5]);
+        assert_eq!(document.blobs_after(4).len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compacting_converts_plaintext_document() {
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        let mut task = Task::new();
+        task.set_description("made before encrypting".to_owned());
+        let tasks = HashMap::from([(task.id().clone(), task)]);
+        autosurgeon::reconcile(&mut client.doc, &tasks).unwrap();
+        client.sync().await;
+        assert!(server.server.lock().await.storage.exists(&doc_id).unwrap());
+
+        let push = EncryptedSyncMessage::Push {
+            blobs: vec![blob("a", b"1")],
+        };
+        let reply = handle_message(&server.server, &doc_id, push, &mut None).await;
+        assert!(matches!(
+            reply,
+            Some(EncryptedSyncMessage::Rejected {
+                error: EncryptedSyncError::NotEncrypted
+            })
+        ));
+        assert!(!server.server.lock().await.is_encrypted(&doc_id));
+
+        let compact = EncryptedSyncMessage::Compact {
+            up_to: 0,
+            snapshot: blob("a", b"0"),
+        };
+        assert!(handle_message(&server.server, &doc_id, compact, &mut None)
+            .await
+            .is_none());
+        let frame = client.closed().await.unwrap();
+        assert_eq!(u16::from(frame.code), CLOSE_DOCUMENT_ENCRYPTED);
+        // pushes are acknowledged once stored
+        let push = EncryptedSyncMessage::Push {
+            blobs: vec![blob("a", b"1")],
+        };
+        let reply = handle_message(&server.server, &doc_id, push, &mut None).await;
+        assert!(matches!(
+            reply,
+            Some(EncryptedSyncMessage::Pushed { seq: 2 })
+        ));
+        let server = server.server.lock().await;
+        assert!(server.is_encrypted(&doc_id));
+        assert!(!server.storage.exists(&doc_id).unwrap());
+    }
+}
@@ -0,0 +1,232 @@
+//! Runs the server in-process so tests can go through the same routes as the web client.
+
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use automerge::{
+    sync::{self, SyncDoc},
+    AutoCommit, Automerge, ChangeHash,
+};
+use futures::{SinkExt, StreamExt};
+use reqwest::header::{COOKIE, SET_COOKIE};
+use tasknet_shared::{
+    presence::{Peer, Presence},
+    sync::SyncMessage,
+};
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, protocol::CloseFrame, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    auth::sessions::Sessions, config::ServerConfig, metrics::Metrics, presence::Presences,
+    reminders::Reminders, server::Server, shutdown::Shutdown, storage::Storage, webhooks::Webhooks,
+};
+
+/// How long the server has to be quiet before a client is taken to be in sync.
+const QUIET: Duration = Duration::from_millis(300);
+
+/// A server listening on a random local port, with its documents in a temporary directory.
+pub struct TestServer {
+    pub address: SocketAddr,
+    pub server: Arc<Mutex<Server>>,
+    storage: Storage,
+    dir: PathBuf,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start a server, changing the config from the defaults first.
+    pub async fn start_with(configure: impl FnOnce(&mut ServerConfig)) -> Self {
+        let dir = std::env::temp_dir().join(format!("tasknet-harness-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut config = ServerConfig {
+            documents_dir: dir.clone(),
+            serve_dir: dir.join("web"),
+            ..ServerConfig::default()
+        };
+        configure(&mut config);
+        let replication = config.replication.clone();
+
+        let storage = Storage::open(&config.storage, &config.documents_dir).unwrap();
+        let (changed, _) = tokio::sync::broadcast::channel(1);
+        let server = Arc::new(Mutex::new(Server {
+            documents: HashMap::new(),
+            encrypted_documents: HashMap::new(),
+            changed,
+            webhooks: Webhooks::load(&config.webhooks, &config.documents_dir).unwrap(),
+            reminders: Reminders::load(&config.documents_dir).unwrap(),
+            presence: Presences::default(),
+            shutdown: Shutdown::default(),
+            storage: storage.clone(),
+            google: None,
+            local: config
+                .local
+                .as_ref()
+                .map(|config| crate::auth::local::Local::load(config).unwrap()),
+            sessions: Sessions::default(),
+            metrics: Metrics::default(),
+            config,
+        }));
+
+        if let Some(replication) = replication {
+            crate::replication::run(server.clone(), replication).await;
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = crate::router(server.clone(), dir.join("web"));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        Self {
+            address,
+            server,
+            storage,
+            dir,
+        }
+    }
+
+    /// Sign in to a public document, returning the cookies of the new session.
+    pub async fn sign_in_public(&self, doc_id: &str) -> String {
+        let client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let res = client
+            .get(format!(
+                "http://{}/auth/public/sign_in?doc_id={doc_id}",
+                self.address
+            ))
+            .send()
+            .await
+            .unwrap();
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.split(';').next())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+
+    /// The document as it was flushed to storage, rather than the server's loaded copy.
+    pub fn stored_document(&self, id: &str) -> Automerge {
+        let persister = self.storage.persister(id).unwrap();
+        automerge_persistent::PersistentAutomerge::load(persister)
+            .unwrap()
+            .document()
+            .clone()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A native client of `/sync`, speaking the same protocol as the web client.
+pub struct SyncClient {
+    pub doc: AutoCommit,
+    /// The other devices on the document, as last sent by the server.
+    pub peers: Vec<Peer>,
+    state: sync::State,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl SyncClient {
+    pub async fn connect(server: &TestServer, cookies: &str) -> Self {
+        Self::connect_with(server, cookies, AutoCommit::new()).await
+    }
+
+    /// Connect with an existing replica, such as after being disconnected.
+    pub async fn connect_with(server: &TestServer, cookies: &str, doc: AutoCommit) -> Self {
+        Self::try_connect_with(server, cookies, doc).await.unwrap()
+    }
+
+    pub async fn try_connect_with(
+        server: &TestServer,
+        cookies: &str,
+        doc: AutoCommit,
+    ) -> Result<Self, tungstenite::Error> {
+        let mut request = format!("ws://{}/sync", server.address)
+            .into_client_request()
+            .unwrap();
+        request
+            .headers_mut()
+            .insert(COOKIE, cookies.parse().unwrap());
+        let (socket, _) = tokio_tungstenite::connect_async(request).await?;
+        Ok(Self {
+            doc,
+            peers: Vec::new(),
+            // a new connection is a new peer to the server so starts from nothing
+            state: sync::State::new(),
+            socket,
+        })
+    }
+
+    async fn send(&mut self) {
+        if let Some(msg) = self.doc.sync().generate_sync_message(&mut self.state) {
+            let bytes = Vec::try_from(SyncMessage::Message(msg.encode())).unwrap();
+            self.socket.send(Message::Binary(bytes)).await.unwrap();
+        }
+    }
+
+    pub async fn send_presence(&mut self, presence: Presence) {
+        let bytes = Vec::try_from(SyncMessage::Presence(presence)).unwrap();
+        self.socket.send(Message::Binary(bytes)).await.unwrap();
+    }
+
+    /// Send bytes as they are, such as a message the server can't parse.
+    pub async fn send_raw(&mut self, bytes: Vec<u8>) {
+        self.socket.send(Message::Binary(bytes)).await.unwrap();
+    }
+
+    /// Exchange sync messages until the server has nothing more to send.
+    pub async fn sync(&mut self) {
+        self.send().await;
+        while let Ok(Some(msg)) = tokio::time::timeout(QUIET, self.socket.next()).await {
+            if let Message::Binary(bytes) = msg.unwrap() {
+                match SyncMessage::try_from(&bytes).unwrap() {
+                    SyncMessage::Message(bytes) => {
+                        let msg = sync::Message::decode(&bytes).unwrap();
+                        self.doc
+                            .sync()
+                            .receive_sync_message(&mut self.state, msg)
+                            .unwrap();
+                        self.send().await;
+                    }
+                    SyncMessage::Peers(peers) => self.peers = peers,
+                    SyncMessage::Presence(_) => panic!("server sent presence"),
+                }
+            }
+        }
+    }
+
+    /// Wait for the server to close the connection, returning its close frame.
+    pub async fn closed(&mut self) -> Option<CloseFrame<'static>> {
+        while let Some(Ok(msg)) = self.socket.next().await {
+            if let Message::Close(frame) = msg {
+                return frame;
+            }
+        }
+        None
+    }
+
+    pub fn heads(&mut self) -> Vec<ChangeHash> {
+        let mut heads = self.doc.get_heads();
+        heads.sort();
+        heads
+    }
+
+    /// Drop the connection without closing it, as when a device loses its network.
+    pub fn disconnect(self) -> AutoCommit {
+        self.doc
+    }
+}
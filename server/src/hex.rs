@@ -0,0 +1,9 @@
+use std::fmt::Write as _;
+
+/// Encode bytes as lowercase hex, as used for checksums and secrets.
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
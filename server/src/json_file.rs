@@ -0,0 +1,88 @@
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Failed to read one of the JSON files the server keeps its state in.
+#[derive(Debug)]
+pub struct JsonFileError {
+    path: PathBuf,
+    kind: JsonFileErrorKind,
+}
+
+#[derive(Debug)]
+enum JsonFileErrorKind {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+}
+
+impl Display for JsonFileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            JsonFileErrorKind::Io(err) => {
+                write!(f, "Failed to read {}: {err}", self.path.display())
+            }
+            JsonFileErrorKind::Json(err) => {
+                write!(f, "Failed to parse {}: {err}", self.path.display())
+            }
+        }
+    }
+}
+
+impl std::error::Error for JsonFileError {}
+
+/// Read a JSON file, giving `None` when it doesn't exist yet.
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, JsonFileError> {
+    let error = |kind| JsonFileError {
+        path: path.to_owned(),
+        kind,
+    };
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(error(JsonFileErrorKind::Io(err))),
+    };
+    serde_json::from_slice(&bytes)
+        .map(Some)
+        .map_err(|err| error(JsonFileErrorKind::Json(err)))
+}
+
+/// Write the value as JSON, replacing the file.
+pub fn save<T: Serialize>(path: &Path, value: &T) -> std::io::Result<()> {
+    // write to a temporary file first so a crash never leaves a truncated file
+    let tmp_file = path.with_extension("tmp");
+    let bytes = serde_json::to_vec_pretty(value)?;
+    let mut f = File::create(&tmp_file)?;
+    f.write_all(&bytes)?;
+    f.sync_all()?;
+    std::fs::rename(tmp_file, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn test_load_and_save() {
+        let dir = std::env::temp_dir().join(format!("tasknet-json-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("values.json");
+
+        assert!(load::<HashMap<String, u32>>(&path).unwrap().is_none());
+        let values = HashMap::from([("a".to_owned(), 1)]);
+        save(&path, &values).unwrap();
+        assert_eq!(load(&path).unwrap(), Some(values));
+
+        std::fs::write(&path, b"{not json").unwrap();
+        let err = load::<HashMap<String, u32>>(&path).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to parse"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use std::{fmt::Display, sync::Arc};
+
+use automerge::{Automerge, Change, ReadDoc, ScalarValue, Value, ROOT};
+use automerge_persistent::Persister;
+use axum::{extract::State, http::StatusCode, response::IntoResponse, Json};
+use tasknet_shared::limits::{Limits, Quota};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use crate::{
+    auth::UserSessionData,
+    server::{Document, Server},
+};
+
+pub fn validate(limits: &Limits, problems: &mut Vec<String>) {
+    if limits.max_message_bytes == 0 {
+        problems.push("limits.max_message_bytes must be more than 0".to_owned());
+    }
+    if limits.max_document_bytes == 0 {
+        problems.push("limits.max_document_bytes must be more than 0".to_owned());
+    }
+    if limits.max_tasks == 0 {
+        problems.push("limits.max_tasks must be more than 0".to_owned());
+    }
+    if limits.max_description_length == 0 {
+        problems.push("limits.max_description_length must be more than 0".to_owned());
+    }
+}
+
+/// A limit a client tried to go over.
+///
+/// Displayed as the reason of the close frame, which must fit in 123 bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Message { bytes: usize, max: usize },
+    Document { bytes: u64, max: u64 },
+    Tasks { count: usize, max: usize },
+    Description { length: usize, max: usize },
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Message { bytes, max } => {
+                write!(
+                    f,
+                    "Sync message of {bytes} bytes is over the limit of {max}"
+                )
+            }
+            Self::Document { bytes, max } => write!(
+                f,
+                "Document would be {bytes} bytes, over the limit of {max}, remove some tasks"
+            ),
+            Self::Tasks { count, max } => {
+                write!(
+                    f,
+                    "Document would have {count} tasks, over the limit of {max}"
+                )
+            }
+            Self::Description { length, max } => write!(
+                f,
+                "A description of {length} characters is over the limit of {max}"
+            ),
+        }
+    }
+}
+
+/// The bytes the document takes up in storage, not counting sync states.
+pub fn document_bytes(document: &Document) -> u64 {
+    let sizes = document.persister().sizes();
+    sizes.changes + sizes.document
+}
+
+fn description(doc: &Automerge, task_id: &str) -> Option<String> {
+    let (_, task) = doc.get(ROOT, task_id).ok()??;
+    match doc.get(&task, "description").ok()?? {
+        (Value::Scalar(scalar), _) => match scalar.as_ref() {
+            ScalarValue::Str(description) => Some(description.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether the changes set a top level key the document doesn't have, which may add a task, or
+/// put a description.
+///
+/// The legacy op types aren't exported, so keys are compared by converting into them.
+fn may_add_tasks_or_descriptions(current: &Automerge, changes: &[Change]) -> bool {
+    let existing = current.keys(ROOT).collect::<Vec<_>>();
+    changes.iter().any(|change| {
+        change.decode().operations.iter().any(|op| {
+            op.key.is_map_key()
+                && (op.key == "description".into()
+                    || (op.obj.to_string() == "_root"
+                        && !existing.iter().any(|key| op.key == key.as_str().into())))
+        })
+    })
+}
+
+/// Check the changes in a sync message keep the document within the limits.
+///
+/// Only what the changes make worse is refused, so a document that was over a limit before it was
+/// lowered can still be edited back under it.
+pub fn check_changes(
+    limits: &Limits,
+    current: &Automerge,
+    stored_bytes: u64,
+    changes: &[Change],
+) -> Result<(), LimitExceeded> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let bytes = stored_bytes
+        + changes
+            .iter()
+            .map(|change| change.raw_bytes().len() as u64)
+            .sum::<u64>();
+    // applying the changes to a copy is only needed when they could go over a limit
+    if bytes <= limits.max_document_bytes && !may_add_tasks_or_descriptions(current, changes) {
+        return Ok(());
+    }
+
+    let mut candidate = current.clone();
+    if let Err(err) = candidate.apply_changes(changes.iter().cloned()) {
+        // applying them for real will fail the same way and be reported there
+        warn!(%err, "Failed to apply changes to check limits");
+        return Ok(());
+    }
+
+    let tasks_before = current.length(ROOT);
+    let tasks_after = candidate.length(ROOT);
+    if tasks_after > limits.max_tasks && tasks_after > tasks_before {
+        return Err(LimitExceeded::Tasks {
+            count: tasks_after,
+            max: limits.max_tasks,
+        });
+    }
+
+    for task_id in candidate.keys(ROOT) {
+        let Some(after) = description(&candidate, &task_id) else {
+            continue;
+        };
+        let length = after.chars().count();
+        if length > limits.max_description_length
+            && description(current, &task_id).as_ref() != Some(&after)
+        {
+            return Err(LimitExceeded::Description {
+                length,
+                max: limits.max_description_length,
+            });
+        }
+    }
+
+    // removing tasks is always allowed so there is a way to get back under the limit
+    if bytes > limits.max_document_bytes && tasks_after >= tasks_before {
+        return Err(LimitExceeded::Document {
+            bytes,
+            max: limits.max_document_bytes,
+        });
+    }
+    Ok(())
+}
+
+pub async fn quota_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> impl IntoResponse {
+    let mut server = server.lock().await;
+    let limits = server.config.limits.clone();
+    let doc_id = user.doc_id();
+    let quota = if server.is_encrypted(doc_id) {
+        server
+            .load_encrypted_document(doc_id)
+            .map(|document| Quota {
+                limits,
+                document_bytes: document.size(),
+                tasks: None,
+            })
+            .map_err(|err| err.to_string())
+    } else {
+        server
+            .load_document(doc_id)
+            .map(|document| Quota {
+                limits,
+                document_bytes: document_bytes(document),
+                tasks: Some(document.document().length(ROOT)),
+            })
+            .map_err(|err| err.to_string())
+    };
+    match quota {
+        Ok(quota) => Json(quota).into_response(),
+        Err(err) => {
+            warn!(id = doc_id, %err, "Failed to load document for quota");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{
+        transaction::{Transactable, Transaction, UnObserved},
+        ObjType,
+    };
+
+    use super::*;
+
+    fn changes(
+        doc: &mut Automerge,
+        f: impl FnOnce(&mut Transaction<'_, UnObserved>),
+    ) -> Vec<Change> {
+        let heads = doc.get_heads();
+        let mut tx = doc.transaction();
+        f(&mut tx);
+        tx.commit();
+        doc.get_changes(&heads)
+            .unwrap()
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    fn put_task(tx: &mut impl Transactable, id: &str, description: &str) {
+        let task = tx.put_object(ROOT, id, ObjType::Map).unwrap();
+        tx.put(&task, "description", description).unwrap();
+    }
+
+    #[test]
+    fn test_check_changes() {
+        let limits = Limits {
+            max_message_bytes: 1024,
+            max_document_bytes: 1024,
+            max_tasks: 2,
+            max_description_length: 5,
+        };
+        let mut doc = Automerge::new();
+        let mut client = Automerge::new();
+
+        let ok = changes(&mut client, |tx| {
+            put_task(tx, "a", "short");
+            put_task(tx, "b", "short");
+        });
+        assert_eq!(check_changes(&limits, &doc, 0, &ok), Ok(()));
+        doc.apply_changes(ok).unwrap();
+
+        let too_many = changes(&mut client.clone(), |tx| put_task(tx, "c", "short"));
+        assert_eq!(
+            check_changes(&limits, &doc, 0, &too_many),
+            Err(LimitExceeded::Tasks { count: 3, max: 2 })
+        );
+
+        let too_long = changes(&mut client.clone(), |tx| put_task(tx, "a", "far too long"));
+        assert_eq!(
+            check_changes(&limits, &doc, 0, &too_long),
+            Err(LimitExceeded::Description { length: 12, max: 5 })
+        );
+
+        // a full document can still have tasks removed
+        let edit = changes(&mut client.clone(), |tx| put_task(tx, "a", "edit"));
+        assert!(matches!(
+            check_changes(&limits, &doc, 2048, &edit),
+            Err(LimitExceeded::Document { .. })
+        ));
+        let remove = changes(&mut client.clone(), |tx| tx.delete(ROOT, "a").unwrap());
+        assert_eq!(check_changes(&limits, &doc, 2048, &remove), Ok(()));
+    }
+
+    #[test]
+    fn test_may_add_tasks_or_descriptions() {
+        let mut doc = Automerge::new();
+        let mut client = Automerge::new();
+        let add = changes(&mut client, |tx| put_task(tx, "a", "short"));
+        assert!(may_add_tasks_or_descriptions(&doc, &add));
+        doc.apply_changes(add).unwrap();
+
+        let task = doc.get(ROOT, "a").unwrap().unwrap().1;
+        let other_field = changes(&mut client.clone(), |tx| {
+            tx.put(&task, "project", "home").unwrap();
+        });
+        assert!(!may_add_tasks_or_descriptions(&doc, &other_field));
+        let description = changes(&mut client.clone(), |tx| {
+            tx.put(&task, "description", "edit").unwrap();
+        });
+        assert!(may_add_tasks_or_descriptions(&doc, &description));
+        let remove = changes(&mut client, |tx| tx.delete(ROOT, "a").unwrap());
+        assert!(!may_add_tasks_or_descriptions(&doc, &remove));
+    }
+}
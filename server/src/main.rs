@@ -1,6 +1,6 @@
-use async_session::MemoryStore;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::time::Duration;
 use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 use tokio::signal;
 use tower_http::services::{ServeDir, ServeFile};
@@ -8,18 +8,93 @@ use tower_http::trace::TraceLayer;
 use tracing::debug;
 use tracing::info;
 
-use axum::{routing::get, Router};
+use axum::{
+    middleware,
+    routing::{delete, get, post},
+    Router,
+};
 use clap::Parser;
 use tokio::sync::Mutex;
 
+mod admin;
 mod auth;
+mod backup;
 mod config;
+mod encrypted;
+#[cfg(test)]
+mod harness;
+mod hex;
+mod json_file;
+mod limits;
+mod metrics;
+mod presence;
+mod reminders;
+mod replication;
 mod server;
+mod shutdown;
+mod storage;
+mod tls;
+mod webhooks;
 
-#[derive(Debug, clap::Parser)]
-struct ServerOptions {
-    #[clap(long, short, default_value = "config.json")]
-    config_file: PathBuf,
+/// How often expired sessions are removed from the store.
+const SESSION_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Options override the config file, and can also be given as `TASKNET_*` environment variables.
+#[derive(clap::Parser)]
+pub struct ServerOptions {
+    /// The config file to load, defaults to config.json if it exists.
+    #[clap(long, short, env = "TASKNET_CONFIG_FILE")]
+    config_file: Option<PathBuf>,
+    #[clap(long, env = "TASKNET_ADDRESS")]
+    address: Option<String>,
+    #[clap(long, env = "TASKNET_PORT")]
+    port: Option<u16>,
+    #[clap(long, env = "TASKNET_SERVE_DIR")]
+    serve_dir: Option<PathBuf>,
+    #[clap(long, env = "TASKNET_DOCUMENTS_DIR")]
+    documents_dir: Option<PathBuf>,
+    #[clap(long, env = "TASKNET_GOOGLE_CLIENT_ID")]
+    google_client_id: Option<String>,
+    #[clap(long, env = "TASKNET_GOOGLE_CLIENT_SECRET", hide_env_values = true)]
+    google_client_secret: Option<String>,
+    #[clap(long, env = "TASKNET_GOOGLE_REDIRECT_URI")]
+    google_redirect_uri: Option<String>,
+    /// Check the configuration and exit.
+    #[clap(long)]
+    check_config: bool,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// written out so the options can be logged without the secret
+impl std::fmt::Debug for ServerOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerOptions")
+            .field("config_file", &self.config_file)
+            .field("address", &self.address)
+            .field("port", &self.port)
+            .field("serve_dir", &self.serve_dir)
+            .field("documents_dir", &self.documents_dir)
+            .field("google_client_id", &self.google_client_id)
+            .field(
+                "google_client_secret",
+                &self.google_client_secret.as_ref().map(|_| "<redacted>"),
+            )
+            .field("google_redirect_uri", &self.google_redirect_uri)
+            .field("check_config", &self.check_config)
+            .field("command", &self.command)
+            .finish()
+    }
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Manage the documents in documents_dir, only while the server is stopped.
+    #[clap(subcommand)]
+    Documents(admin::DocumentsCommand),
+    /// Take, check and restore backups of the documents.
+    #[clap(subcommand)]
+    Backups(backup::BackupsCommand),
 }
 
 #[tokio::main]
@@ -30,14 +105,57 @@ async fn main() {
 
     debug!(?options, "Parsed CLI options");
 
-    let config = config::ServerConfig::load(&options.config_file);
+    let config = match config::ServerConfig::load(&options) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    debug!(?config, "Loaded config");
+
+    if let Some(command) = options.command {
+        let result = match command {
+            Command::Documents(command) => admin::run(&config, command),
+            Command::Backups(command) => backup::run(&config, command),
+        };
+        if let Err(err) = result {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = config.validate() {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+
+    if options.check_config {
+        println!("Configuration is valid");
+        return;
+    }
 
-    debug!(?config, "Loaded config file");
+    std::fs::create_dir_all(&config.documents_dir).expect("Failed to create documents_dir");
+    // held until the server exits so admin commands don't change documents under it
+    let _lock = match admin::DocumentsLock::acquire(&config.documents_dir) {
+        Ok(lock) => lock,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
 
     let (changed, _) = tokio::sync::broadcast::channel(1);
 
     let address = config.address.clone();
     let port = config.port;
+    let tls = config.tls.clone();
+    let backup = config.backup.clone();
+    let reminders = config.reminders.clone();
+    let replication = config.replication.clone();
+    let metrics_config = config.metrics.clone();
 
     let google = if let Some(config) = config.google.as_ref() {
         Some(auth::google::Google::new(config).await)
@@ -45,38 +163,164 @@ async fn main() {
         None
     };
 
-    let app = Router::new()
+    let loaded = config
+        .local
+        .as_ref()
+        .map(auth::local::Local::load)
+        .transpose()
+        .and_then(|local| {
+            let webhooks = webhooks::Webhooks::load(&config.webhooks, &config.documents_dir)?;
+            let reminders = reminders::Reminders::load(&config.documents_dir)?;
+            Ok((local, webhooks, reminders))
+        });
+    let (local, webhooks, reminder_settings) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(1);
+        }
+    };
+
+    let storage = storage::Storage::open(&config.storage, &config.documents_dir)
+        .expect("Failed to open document storage");
+
+    let serve_dir = config.serve_dir.clone();
+    let sessions = auth::sessions::Sessions::default();
+    let server = Arc::new(Mutex::new(server::Server {
+        documents: HashMap::new(),
+        encrypted_documents: HashMap::new(),
+        changed,
+        config,
+        storage,
+        google,
+        local,
+        sessions: sessions.clone(),
+        metrics: metrics::Metrics::default(),
+        webhooks,
+        reminders: reminder_settings,
+        presence: presence::Presences::default(),
+        shutdown: shutdown::Shutdown::default(),
+    }));
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SESSION_CLEANUP_INTERVAL);
+        loop {
+            interval.tick().await;
+            sessions.cleanup().await;
+        }
+    });
+
+    if let Some(backup) = backup {
+        tokio::spawn(backup::schedule(server.clone(), backup));
+    }
+
+    if let Some(reminders) = reminders {
+        tokio::spawn(reminders::schedule(server.clone(), reminders));
+    }
+
+    if let Some(replication) = replication {
+        replication::run(server.clone(), replication).await;
+    }
+
+    if let Some(metrics_config) = metrics_config {
+        tokio::spawn(metrics::serve(server.clone(), metrics_config));
+    }
+
+    let shutdown = server.lock().await.shutdown.clone();
+    let app = router(server.clone(), serve_dir);
+
+    let ip = address.parse::<IpAddr>().unwrap();
+    let addr = SocketAddr::from((ip, port));
+    if let Some(tls) = tls {
+        let rustls_config = tls.load().await.expect("Failed to load TLS certificate");
+        if let Some(redirect_port) = tls.redirect_http_port {
+            tokio::spawn(tls::serve_redirect(
+                SocketAddr::from((ip, redirect_port)),
+                port,
+            ));
+        }
+        tokio::spawn(tls.watch(rustls_config.clone()));
+
+        let handle = axum_server::Handle::new();
+        let shutdown_handle = handle.clone();
+        tokio::spawn(async move {
+            shutdown_signal(shutdown).await;
+            shutdown_handle.graceful_shutdown(None);
+        });
+
+        info!("Listening on https://{}:{}", ip, port);
+        axum_server::bind_rustls(addr, rustls_config)
+            .handle(handle)
+            .serve(app.into_make_service())
+            .await
+            .unwrap();
+    } else {
+        info!("Listening on http://{}:{}", ip, port);
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service())
+            .with_graceful_shutdown(shutdown_signal(shutdown))
+            .await
+            .unwrap();
+    }
+
+    shutdown::finish(server).await;
+}
+
+/// Every route the server handles.
+fn router(server: Arc<Mutex<server::Server>>, serve_dir: PathBuf) -> Router {
+    Router::new()
         .route("/sync", get(server::sync_handler))
+        .route("/sync/encrypted", get(encrypted::sync_handler))
+        .route("/quota", get(limits::quota_handler))
+        .route(
+            "/reminders",
+            get(reminders::settings_handler).put(reminders::update_settings_handler),
+        )
+        .route("/reminders/confirm", post(reminders::confirm_handler))
+        .route(
+            "/webhooks",
+            get(webhooks::list_handler).post(webhooks::subscribe_handler),
+        )
+        .route("/webhooks/:id", delete(webhooks::unsubscribe_handler))
+        .route(
+            "/webhooks/:id/deliveries",
+            get(webhooks::deliveries_handler),
+        )
         .route("/auth/providers", get(auth::providers))
+        .route("/auth/session", get(auth::sessions::check_handler))
+        .route("/auth/sessions", get(auth::sessions::list_handler))
+        .route("/auth/sessions/:id", delete(auth::sessions::revoke_handler))
         .route("/auth/google/sign_in", get(auth::google::sign_in_handler))
         .route("/auth/google/sign_out", get(auth::google::sign_out_handler))
         .route("/auth/google/callback", get(auth::google::callback_handler))
         .route("/auth/public/sign_in", get(auth::public::sign_in_handler))
         .route("/auth/public/sign_out", get(auth::public::sign_out_handler))
+        .route("/auth/local/register", post(auth::local::register_handler))
+        .route("/auth/local/sign_in", post(auth::local::sign_in_handler))
+        .route("/auth/local/sign_out", get(auth::local::sign_out_handler))
+        .route("/auth/local/account", get(auth::local::account_handler))
+        .route(
+            "/auth/local/change_password",
+            post(auth::local::change_password_handler),
+        )
+        .route(
+            "/auth/local/reset_password",
+            post(auth::local::reset_password_handler),
+        )
+        // only the routes above use sessions, so static files skip the lock
+        .layer(middleware::from_fn_with_state(
+            server.clone(),
+            auth::track_session,
+        ))
         .nest_service(
             "/",
-            ServeDir::new(&config.serve_dir).not_found_service(ServeFile::new("index.html")),
+            ServeDir::new(serve_dir).not_found_service(ServeFile::new("index.html")),
         )
-        .with_state(Arc::new(Mutex::new(server::Server {
-            documents: HashMap::new(),
-            changed,
-            config,
-            google,
-            sessions: MemoryStore::new(),
-        })))
-        .layer(TraceLayer::new_for_http());
-
-    let ip = address.parse::<IpAddr>().unwrap();
-    let addr = SocketAddr::from((ip, port));
-    info!("Listening on http://{}:{}", ip, port);
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+        .with_state(server)
+        .layer(TraceLayer::new_for_http())
 }
 
-async fn shutdown_signal() {
+async fn shutdown_signal(shutdown: shutdown::Shutdown) {
     let ctrl_c = async {
         signal::ctrl_c()
             .await
@@ -100,4 +344,5 @@ async fn shutdown_signal() {
     }
 
     info!("Signal received, starting graceful shutdown");
+    shutdown.begin();
 }
@@ -0,0 +1,307 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::State,
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+use crate::{encrypted::EncryptedDocument, server::Server, storage::Storage};
+
+/// Serving metrics on a listener of their own, away from the public address.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default = "default_address")]
+    pub address: String,
+    pub port: u16,
+    /// How often to measure the documents on disk, which reads the size of every one.
+    #[serde(default = "default_disk_interval_secs")]
+    pub disk_interval_secs: u64,
+}
+
+fn default_address() -> String {
+    "127.0.0.1".to_owned()
+}
+
+const fn default_disk_interval_secs() -> u64 {
+    5 * 60
+}
+
+impl MetricsConfig {
+    pub fn validate(&self, port: u16, problems: &mut Vec<String>) {
+        if self.address.parse::<IpAddr>().is_err() {
+            problems.push(format!(
+                "metrics.address {:?} is not an IP address, such as 127.0.0.1 or ::",
+                self.address
+            ));
+        }
+        if self.port == 0 || self.port == port {
+            problems.push("metrics.port must not be 0 or the same as port".to_owned());
+        }
+        if self.disk_interval_secs == 0 {
+            problems.push("metrics.disk_interval_secs must be greater than 0".to_owned());
+        }
+    }
+}
+
+/// Metrics about the server, exposed for Prometheus at `/metrics`.
+///
+/// Counters are updated as things happen, the documents on disk are measured periodically and
+/// the rest of the server's state is taken when scraped.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub sync_connections: IntGauge,
+    pub sync_messages: IntCounterVec,
+    pub sync_bytes: IntCounterVec,
+    pub flush_seconds: Histogram,
+    documents_loaded: IntGauge,
+    documents_on_disk: IntGauge,
+    documents_disk_bytes: IntGauge,
+    document_disk_bytes_max: IntGauge,
+    sessions: IntGauge,
+    auth_attempts: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        let registry = Registry::new();
+        let register = |metric: Box<dyn prometheus::core::Collector>| {
+            registry
+                .register(metric)
+                .expect("Failed to register metric");
+        };
+
+        let sync_connections = IntGauge::new(
+            "tasknet_sync_connections",
+            "Websocket peers connected to sync documents",
+        )
+        .unwrap();
+        register(Box::new(sync_connections.clone()));
+        let sync_messages = IntCounterVec::new(
+            Opts::new(
+                "tasknet_sync_messages_total",
+                "Sync messages sent and received",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        register(Box::new(sync_messages.clone()));
+        let sync_bytes = IntCounterVec::new(
+            Opts::new(
+                "tasknet_sync_bytes_total",
+                "Bytes of sync messages sent and received",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        register(Box::new(sync_bytes.clone()));
+        let flush_seconds = Histogram::with_opts(HistogramOpts::new(
+            "tasknet_document_flush_seconds",
+            "Time taken to flush documents to disk",
+        ))
+        .unwrap();
+        register(Box::new(flush_seconds.clone()));
+        let documents_loaded =
+            IntGauge::new("tasknet_documents_loaded", "Documents loaded in memory").unwrap();
+        register(Box::new(documents_loaded.clone()));
+        let documents_on_disk =
+            IntGauge::new("tasknet_documents_on_disk", "Documents stored on disk").unwrap();
+        register(Box::new(documents_on_disk.clone()));
+        let documents_disk_bytes = IntGauge::new(
+            "tasknet_documents_disk_bytes",
+            "Total size of the documents on disk",
+        )
+        .unwrap();
+        register(Box::new(documents_disk_bytes.clone()));
+        let document_disk_bytes_max = IntGauge::new(
+            "tasknet_document_disk_bytes_max",
+            "Size of the largest document on disk",
+        )
+        .unwrap();
+        register(Box::new(document_disk_bytes_max.clone()));
+        let sessions = IntGauge::new("tasknet_sessions", "Live sessions").unwrap();
+        register(Box::new(sessions.clone()));
+        let auth_attempts = IntCounterVec::new(
+            Opts::new(
+                "tasknet_auth_attempts_total",
+                "Sign in attempts by provider",
+            ),
+            &["provider", "result"],
+        )
+        .unwrap();
+        register(Box::new(auth_attempts.clone()));
+
+        Self {
+            registry,
+            sync_connections,
+            sync_messages,
+            sync_bytes,
+            flush_seconds,
+            documents_loaded,
+            documents_on_disk,
+            documents_disk_bytes,
+            document_disk_bytes_max,
+            sessions,
+            auth_attempts,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn sync_received(&self, bytes: usize) {
+        self.sync_messages.with_label_values(&["in"]).inc();
+        self.sync_bytes
+            .with_label_values(&["in"])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn sync_sent(&self, bytes: usize) {
+        self.sync_messages.with_label_values(&["out"]).inc();
+        self.sync_bytes
+            .with_label_values(&["out"])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn auth_succeeded(&self, provider: &str) {
+        self.auth_attempts
+            .with_label_values(&[provider, "success"])
+            .inc();
+    }
+
+    pub fn auth_failed(&self, provider: &str) {
+        self.auth_attempts
+            .with_label_values(&[provider, "failure"])
+            .inc();
+    }
+}
+
+/// Size of everything under `path`, which may be a single file.
+pub(crate) fn disk_size(path: &Path) -> u64 {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if metadata.is_dir() {
+        std::fs::read_dir(path)
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .map(|entry| disk_size(&entry.path()))
+                    .sum()
+            })
+            .unwrap_or_default()
+    } else {
+        metadata.len()
+    }
+}
+
+/// Sizes of every stored document.
+fn document_sizes(storage: &Storage, documents_dir: &Path) -> Vec<u64> {
+    let mut sizes = storage
+        .document_ids()
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|id| storage.size(id).ok())
+        .collect::<Vec<_>>();
+    // encrypted documents are always logs in the documents directory
+    if let Ok(entries) = std::fs::read_dir(documents_dir) {
+        sizes.extend(
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter(|path| EncryptedDocument::is_log(path))
+                .map(|path| disk_size(&path)),
+        );
+    }
+    sizes
+}
+
+/// Measure the documents on disk every `interval`, rather than on every scrape.
+async fn measure_documents(
+    metrics: Metrics,
+    storage: Storage,
+    documents_dir: PathBuf,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let documents_dir = documents_dir.clone();
+        let sizes = tokio::task::spawn_blocking(move || document_sizes(&storage, &documents_dir))
+            .await
+            .unwrap_or_default();
+        metrics.documents_on_disk.set(sizes.len() as i64);
+        metrics
+            .documents_disk_bytes
+            .set(sizes.iter().sum::<u64>() as i64);
+        metrics
+            .document_disk_bytes_max
+            .set(sizes.iter().copied().max().unwrap_or_default() as i64);
+    }
+}
+
+/// Serve `/metrics` on the configured address until the server exits.
+pub async fn serve(server: Arc<Mutex<Server>>, config: MetricsConfig) {
+    let (metrics, storage, documents_dir) = {
+        let server = server.lock().await;
+        (
+            server.metrics.clone(),
+            server.storage.clone(),
+            server.config.documents_dir.clone(),
+        )
+    };
+    tokio::spawn(measure_documents(
+        metrics,
+        storage,
+        documents_dir,
+        Duration::from_secs(config.disk_interval_secs),
+    ));
+
+    let ip = config.address.parse::<IpAddr>().unwrap();
+    let address = SocketAddr::from((ip, config.port));
+    info!("Serving metrics on http://{}/metrics", address);
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(server);
+    axum::Server::bind(&address)
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+async fn metrics_handler(State(server): State<Arc<Mutex<Server>>>) -> Response {
+    let metrics = {
+        let server = server.lock().await;
+        server
+            .metrics
+            .documents_loaded
+            .set((server.documents.len() + server.encrypted_documents.len()) as i64);
+        server
+            .metrics
+            .sessions
+            .set(server.sessions.count().await as i64);
+        server.metrics.clone()
+    };
+
+    let encoder = TextEncoder::new();
+    let mut bytes = Vec::new();
+    if let Err(err) = encoder.encode(&metrics.registry.gather(), &mut bytes) {
+        warn!(%err, "Failed to encode metrics");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    ([(CONTENT_TYPE, encoder.format_type().to_owned())], bytes).into_response()
+}
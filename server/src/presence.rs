@@ -0,0 +1,187 @@
+use std::{collections::HashMap, time::Duration};
+
+use tasknet_shared::presence::{Peer, Presence, MAX_DEVICE_NAME_LENGTH};
+use tokio::{sync::broadcast, time::Instant};
+use uuid::Uuid;
+
+/// How often one connection's presence is passed on, anything sooner waits for the interval.
+const MIN_PRESENCE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The connections to one document.
+#[derive(Debug)]
+struct DocumentPresence {
+    peers: HashMap<Uuid, Presence>,
+    /// Notified when the presence of the document's connections changes.
+    changed: broadcast::Sender<()>,
+}
+
+impl Default for DocumentPresence {
+    fn default() -> Self {
+        let (changed, _) = broadcast::channel(16);
+        Self {
+            peers: HashMap::new(),
+            changed,
+        }
+    }
+}
+
+/// The presence of each sync connection, by document.
+#[derive(Debug, Default)]
+pub struct Presences {
+    documents: HashMap<String, DocumentPresence>,
+}
+
+impl Presences {
+    /// Be notified when the presence on a document changes.
+    pub fn subscribe(&mut self, doc_id: &str) -> broadcast::Receiver<()> {
+        self.documents
+            .entry(doc_id.to_owned())
+            .or_default()
+            .changed
+            .subscribe()
+    }
+
+    /// Record what a connection is doing, as of now.
+    pub fn set(&mut self, doc_id: &str, peer_id: Uuid, mut presence: Presence) {
+        if let Some((end, _)) = presence.device.char_indices().nth(MAX_DEVICE_NAME_LENGTH) {
+            presence.device.truncate(end);
+        }
+        presence.last_active = Some(chrono::Utc::now());
+        let document = self.documents.entry(doc_id.to_owned()).or_default();
+        document.peers.insert(peer_id, presence);
+        let _ = document.changed.send(());
+    }
+
+    /// Forget a connection once it closes.
+    pub fn remove(&mut self, doc_id: &str, peer_id: Uuid) {
+        let Some(document) = self.documents.get_mut(doc_id) else {
+            return;
+        };
+        if document.peers.remove(&peer_id).is_some() {
+            let _ = document.changed.send(());
+        }
+        if document.peers.is_empty() && document.changed.receiver_count() == 0 {
+            self.documents.remove(doc_id);
+        }
+    }
+
+    /// The connections to a document other than the given one, oldest activity first.
+    pub fn peers(&self, doc_id: &str, except: Uuid) -> Vec<Peer> {
+        let mut peers = self
+            .documents
+            .get(doc_id)
+            .into_iter()
+            .flat_map(|document| &document.peers)
+            .filter(|(id, _)| **id != except)
+            .map(|(id, presence)| Peer {
+                id: id.to_string(),
+                presence: presence.clone(),
+            })
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|peer| peer.presence.last_active);
+        peers
+    }
+}
+
+/// Limits how often one connection's presence is passed on, keeping the latest that arrived too
+/// soon so the other peers still see it.
+#[derive(Debug, Default)]
+pub struct PresenceThrottle {
+    last: Option<Instant>,
+    pending: Option<Presence>,
+}
+
+impl PresenceThrottle {
+    /// Give the presence back if it can be passed on now, otherwise hold it for [`Self::ready`].
+    pub fn accept(&mut self, presence: Presence) -> Option<Presence> {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now < last + MIN_PRESENCE_INTERVAL)
+        {
+            self.pending = Some(presence);
+            return None;
+        }
+        self.pending = None;
+        self.last = Some(now);
+        Some(presence)
+    }
+
+    /// Wait until the held presence can be passed on, never finishing if there is none.
+    pub async fn ready(&mut self) -> Presence {
+        let Some(last) = self.last.filter(|_| self.pending.is_some()) else {
+            return std::future::pending().await;
+        };
+        tokio::time::sleep_until(last + MIN_PRESENCE_INTERVAL).await;
+        self.last = Some(Instant::now());
+        self.pending.take().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peers_exclude_self() {
+        let mut presences = Presences::default();
+        let mut changed = presences.subscribe("doc");
+        let (a, b) = (Uuid::new_v4(), Uuid::new_v4());
+        presences.set(
+            "doc",
+            a,
+            Presence {
+                device: "x".repeat(100),
+                ..Presence::default()
+            },
+        );
+        presences.set("doc", b, Presence::default());
+        presences.set("other", b, Presence::default());
+
+        let peers = presences.peers("doc", b);
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].id, a.to_string());
+        assert_eq!(peers[0].presence.device.len(), MAX_DEVICE_NAME_LENGTH);
+        assert!(peers[0].presence.last_active.is_some());
+
+        presences.remove("doc", a);
+        assert!(presences.peers("doc", b).is_empty());
+        let mut notified = 0;
+        while changed.try_recv().is_ok() {
+            notified += 1;
+        }
+        // not told about the other document
+        assert_eq!(notified, 3);
+
+        presences.remove("other", b);
+        assert!(!presences.documents.contains_key("other"));
+        // kept while the connection is still listening
+        presences.remove("doc", b);
+        assert!(presences.documents.contains_key("doc"));
+        drop(changed);
+        presences.remove("doc", b);
+        assert!(presences.documents.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_throttle_keeps_latest_presence() {
+        let mut throttle = PresenceThrottle::default();
+        let presence = |device: &str| Presence {
+            device: device.to_owned(),
+            ..Presence::default()
+        };
+        assert!(throttle.accept(presence("first")).is_some());
+        assert!(throttle.accept(presence("second")).is_none());
+        assert!(throttle.accept(presence("third")).is_none());
+
+        let start = Instant::now();
+        assert_eq!(throttle.ready().await.device, "third");
+        assert!(start.elapsed() >= MIN_PRESENCE_INTERVAL / 2);
+        // nothing else is held
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), throttle.ready())
+                .await
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,867 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{Display, Write as _},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use automerge::Automerge;
+use autosurgeon::hydrate;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, FixedOffset, NaiveDate, Timelike, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tasknet_shared::{
+    reminders::{ConfirmEmail, ReminderMode, ReminderSettings, ReminderStatus},
+    task::{Status, Task, TaskId},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::UserSessionData,
+    json_file::{self, JsonFileError},
+    server::Server,
+    storage::Storage,
+};
+
+pub mod smtp;
+
+use smtp::{Email, SmtpConfig};
+
+/// The file reminder settings are kept in, inside `documents_dir`.
+const REMINDERS_FILE: &str = "reminders.json";
+
+/// How long a confirmation code can be entered for.
+const CONFIRMATION_EXPIRY_HOURS: i64 = 24;
+
+/// How long to wait before emailing another confirmation code to the same address.
+const CONFIRMATION_RESEND_SECS: i64 = 60;
+
+/// How many wrong codes can be entered before another has to be sent.
+const CONFIRMATION_ATTEMPTS: u32 = 5;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemindersConfig {
+    pub smtp: SmtpConfig,
+    /// How often to look for tasks to remind about.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// How long before a task is due or scheduled to remind about it.
+    #[serde(default = "default_lead_minutes")]
+    pub lead_minutes: i64,
+}
+
+const fn default_interval_secs() -> u64 {
+    5 * 60
+}
+
+const fn default_lead_minutes() -> i64 {
+    60
+}
+
+impl RemindersConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        self.smtp.validate(problems);
+        if self.interval_secs == 0 {
+            problems.push("reminders.interval_secs must be more than 0".to_owned());
+        }
+        if self.lead_minutes < 0 {
+            problems.push("reminders.lead_minutes must not be negative".to_owned());
+        }
+    }
+}
+
+/// A time a task is due or scheduled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Reminder {
+    /// Identifies the reminder so it is only sent once, changing if the time is moved.
+    key: String,
+    description: String,
+    kind: &'static str,
+    at: DateTime<Utc>,
+}
+
+fn reminders(tasks: &HashMap<TaskId, Task>) -> Vec<Reminder> {
+    let mut reminders = tasks
+        .values()
+        .filter(|task| matches!(task.status(), Status::Pending | Status::Waiting))
+        .flat_map(|task| {
+            [("due", task.due()), ("scheduled", task.scheduled())]
+                .into_iter()
+                .filter_map(move |(kind, at)| {
+                    let at = at.as_ref()?.0;
+                    Some(Reminder {
+                        key: format!("{}:{kind}:{}", task.id(), at.timestamp_millis()),
+                        description: task.description().to_owned(),
+                        kind,
+                        at,
+                    })
+                })
+        })
+        .collect::<Vec<_>>();
+    reminders.sort_by_key(|reminder| reminder.at);
+    reminders
+}
+
+/// What to send a document's owner now.
+#[derive(Debug)]
+struct Plan {
+    email: Option<Email>,
+    /// The reminders in the email, to not send them again.
+    sent: Vec<String>,
+    /// Every reminder the document currently has, to forget the rest.
+    current: HashSet<String>,
+    digest: Option<NaiveDate>,
+}
+
+/// A code emailed to an address, which must be entered before reminders are sent there.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Confirmation {
+    email: String,
+    code: String,
+    sent: DateTime<Utc>,
+    /// How many wrong codes have been entered.
+    #[serde(default)]
+    attempts: u32,
+}
+
+/// Why a confirmation code wasn't accepted.
+#[derive(Debug)]
+pub enum ConfirmError {
+    NotSent,
+    Expired,
+    Wrong,
+    TooManyAttempts,
+    Save(std::io::Error),
+}
+
+impl Display for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotSent => write!(f, "No code has been sent, save the settings to send one."),
+            Self::Expired => write!(
+                f,
+                "The code has expired, save the settings to send another."
+            ),
+            Self::Wrong => write!(f, "The code is wrong."),
+            Self::TooManyAttempts => write!(
+                f,
+                "Too many wrong codes, save the settings to send another."
+            ),
+            Self::Save(err) => write!(f, "Failed to save reminders: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfirmError {}
+
+/// A document's reminder settings and what has already been sent for it.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Subscriber {
+    settings: ReminderSettings,
+    /// The address a code was confirmed for, reminders are only sent to it.
+    #[serde(default)]
+    confirmed_email: Option<String>,
+    #[serde(default)]
+    confirmation: Option<Confirmation>,
+    #[serde(default)]
+    sent: HashSet<String>,
+    /// The local date the last digest was sent on.
+    #[serde(default)]
+    last_digest: Option<NaiveDate>,
+}
+
+impl Subscriber {
+    fn email_confirmed(&self) -> bool {
+        self.confirmed_email.as_deref() == Some(self.settings.email.as_str())
+    }
+
+    fn active(&self) -> bool {
+        self.settings.enabled && self.email_confirmed()
+    }
+
+    fn plan(
+        &self,
+        tasks: &HashMap<TaskId, Task>,
+        now: DateTime<Utc>,
+        lead: chrono::Duration,
+    ) -> Option<Plan> {
+        let settings = &self.settings;
+        if !self.active() {
+            return None;
+        }
+        let offset = FixedOffset::east_opt(settings.utc_offset_minutes * 60)?;
+        let local_now = now.with_timezone(&offset);
+        if settings
+            .quiet_hours
+            .is_some_and(|quiet_hours| quiet_hours.contains(local_now.hour()))
+        {
+            return None;
+        }
+
+        let reminders = reminders(tasks);
+        let current = reminders.iter().map(|r| r.key.clone()).collect();
+        let (reminders, digest) = match settings.mode {
+            ReminderMode::Immediate => (
+                reminders
+                    .into_iter()
+                    .filter(|r| r.at - lead <= now && !self.sent.contains(&r.key))
+                    .collect::<Vec<_>>(),
+                None,
+            ),
+            ReminderMode::Digest { hour } => {
+                let today = local_now.date_naive();
+                if local_now.hour() < hour || self.last_digest == Some(today) {
+                    return None;
+                }
+                (
+                    reminders
+                        .into_iter()
+                        .filter(|r| r.at <= now + chrono::Duration::days(1))
+                        .collect(),
+                    Some(today),
+                )
+            }
+        };
+        if reminders.is_empty() && digest.is_none() {
+            return None;
+        }
+
+        let email = (!reminders.is_empty()).then(|| {
+            let mut body = String::new();
+            for reminder in &reminders {
+                let state = if reminder.at <= now { "was" } else { "is" };
+                let _ = writeln!(
+                    body,
+                    "- {} {state} {} {}",
+                    reminder.description,
+                    reminder.kind,
+                    reminder.at.with_timezone(&offset).format("%a %e %b %H:%M")
+                );
+            }
+            let count = reminders.len();
+            Email {
+                to: settings.email.clone(),
+                subject: format!(
+                    "TaskNet: {count} {} to look at",
+                    if count == 1 { "task" } else { "tasks" }
+                ),
+                body,
+            }
+        });
+        Some(Plan {
+            email,
+            sent: reminders.into_iter().map(|r| r.key).collect(),
+            current,
+            digest,
+        })
+    }
+}
+
+/// The reminder settings of every document, stored in `reminders.json`.
+#[derive(Debug)]
+pub struct Reminders {
+    file: PathBuf,
+    subscribers: HashMap<String, Subscriber>,
+}
+
+impl Reminders {
+    pub fn load(documents_dir: &Path) -> Result<Self, JsonFileError> {
+        let file = documents_dir.join(REMINDERS_FILE);
+        let subscribers = json_file::load(&file)?.unwrap_or_else(|| {
+            debug!(?file, "No reminders file, starting empty");
+            HashMap::new()
+        });
+        Ok(Self { file, subscribers })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        json_file::save(&self.file, &self.subscribers)
+    }
+
+    pub fn status(&self, document: &str) -> ReminderStatus {
+        self.subscribers
+            .get(document)
+            .map(|subscriber| ReminderStatus {
+                settings: subscriber.settings.clone(),
+                email_confirmed: subscriber.email_confirmed(),
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn set_settings(
+        &mut self,
+        document: &str,
+        settings: ReminderSettings,
+    ) -> std::io::Result<()> {
+        let subscriber = self.subscribers.entry(document.to_owned()).or_default();
+        let previous = std::mem::replace(&mut subscriber.settings, settings);
+        if let Err(err) = self.save() {
+            if let Some(subscriber) = self.subscribers.get_mut(document) {
+                subscriber.settings = previous;
+            }
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Start confirming the email if reminders are enabled for one that isn't confirmed,
+    /// giving the email with the code to send.
+    pub fn confirmation_email(
+        &mut self,
+        document: &str,
+        now: DateTime<Utc>,
+    ) -> std::io::Result<Option<Email>> {
+        let Some(subscriber) = self.subscribers.get_mut(document) else {
+            return Ok(None);
+        };
+        if !subscriber.settings.enabled || subscriber.email_confirmed() {
+            return Ok(None);
+        }
+        let email = &subscriber.settings.email;
+        if subscriber
+            .confirmation
+            .as_ref()
+            .is_some_and(|confirmation| {
+                &confirmation.email == email
+                    && now - confirmation.sent < chrono::Duration::seconds(CONFIRMATION_RESEND_SECS)
+            })
+        {
+            return Ok(None);
+        }
+        let code = format!("{:06}", rand::thread_rng().gen_range(0..1_000_000));
+        let message = Email {
+            to: email.clone(),
+            subject: "TaskNet: confirm your email".to_owned(),
+            body: format!(
+                "Enter {code} in TaskNet's settings to get reminders at this address.\n\n\
+                 The code expires in {CONFIRMATION_EXPIRY_HOURS} hours. If you didn't ask for \
+                 reminders you can ignore this email.\n"
+            ),
+        };
+        subscriber.confirmation = Some(Confirmation {
+            email: email.clone(),
+            code,
+            sent: now,
+            attempts: 0,
+        });
+        self.save()?;
+        Ok(Some(message))
+    }
+
+    /// Forget the code sent to `email` as it couldn't be sent, so another can be sent straight
+    /// away.
+    pub fn confirmation_unsent(&mut self, document: &str, email: &str) -> std::io::Result<()> {
+        let Some(subscriber) = self.subscribers.get_mut(document) else {
+            return Ok(());
+        };
+        if subscriber
+            .confirmation
+            .as_ref()
+            .is_some_and(|confirmation| confirmation.email == email)
+        {
+            subscriber.confirmation = None;
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// Confirm the email the code was sent to.
+    pub fn confirm(
+        &mut self,
+        document: &str,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), ConfirmError> {
+        let confirmation = self
+            .subscribers
+            .get_mut(document)
+            .and_then(|subscriber| subscriber.confirmation.as_mut())
+            .ok_or(ConfirmError::NotSent)?;
+        if now - confirmation.sent > chrono::Duration::hours(CONFIRMATION_EXPIRY_HOURS) {
+            return Err(ConfirmError::Expired);
+        }
+        if confirmation.attempts >= CONFIRMATION_ATTEMPTS {
+            return Err(ConfirmError::TooManyAttempts);
+        }
+        if confirmation.code != code.trim() {
+            confirmation.attempts += 1;
+            self.save().map_err(ConfirmError::Save)?;
+            return Err(ConfirmError::Wrong);
+        }
+        if let Some(subscriber) = self.subscribers.get_mut(document) {
+            subscriber.confirmed_email = subscriber
+                .confirmation
+                .take()
+                .map(|confirmation| confirmation.email);
+        }
+        self.save().map_err(ConfirmError::Save)
+    }
+
+    fn enabled_documents(&self) -> Vec<String> {
+        self.subscribers
+            .iter()
+            .filter(|(_, subscriber)| subscriber.active())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    fn plan(
+        &self,
+        document: &str,
+        tasks: &HashMap<TaskId, Task>,
+        now: DateTime<Utc>,
+        lead: chrono::Duration,
+    ) -> Option<Plan> {
+        self.subscribers.get(document)?.plan(tasks, now, lead)
+    }
+
+    /// Record that the plan was sent.
+    fn sent(&mut self, document: &str, plan: Plan) -> std::io::Result<()> {
+        let Some(subscriber) = self.subscribers.get_mut(document) else {
+            return Ok(());
+        };
+        subscriber.sent.retain(|key| plan.current.contains(key));
+        subscriber.sent.extend(plan.sent);
+        if plan.digest.is_some() {
+            subscriber.last_digest = plan.digest;
+        }
+        self.save()
+    }
+}
+
+/// Read the tasks of each document, from the server's copy if it has the document open and
+/// otherwise from storage without opening it on the server.
+fn read_tasks(
+    storage: &Storage,
+    documents: Vec<(String, Option<Automerge>)>,
+) -> Vec<(String, HashMap<TaskId, Task>)> {
+    documents
+        .into_iter()
+        .filter_map(|(id, open)| {
+            let tasks = match open {
+                Some(document) => hydrate(&document),
+                None => {
+                    let stored = storage
+                        .persister(&id)
+                        .map_err(|err| err.to_string())
+                        .and_then(|persister| {
+                            automerge_persistent::PersistentAutomerge::load(persister)
+                                .map_err(|err| err.to_string())
+                        });
+                    match stored {
+                        Ok(document) => hydrate(document.document()),
+                        Err(err) => {
+                            warn!(id, %err, "Failed to load document for reminders");
+                            return None;
+                        }
+                    }
+                }
+            };
+            tasks
+                .map_err(|err| warn!(id, %err, "Failed to read tasks for reminders"))
+                .ok()
+                .map(|tasks| (id, tasks))
+        })
+        .collect()
+}
+
+/// Email everyone the reminders they are due.
+async fn remind(server: &Mutex<Server>, config: &RemindersConfig) {
+    let now = Utc::now();
+    let lead = chrono::Duration::minutes(config.lead_minutes);
+    let (documents, storage) = {
+        let server = server.lock().await;
+        let mut documents = Vec::new();
+        for id in server.reminders.enabled_documents() {
+            if server.is_encrypted(&id) {
+                debug!(id, "Not reminding about an encrypted document");
+                continue;
+            }
+            // only copied under the lock, the tasks are read after
+            let open = server
+                .documents
+                .get(&id)
+                .map(|document| document.document().clone());
+            if open.is_some() || server.storage.exists(&id).unwrap_or_default() {
+                documents.push((id, open));
+            }
+        }
+        (documents, server.storage.clone())
+    };
+    let tasks = match tokio::task::spawn_blocking(move || read_tasks(&storage, documents)).await {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            warn!(%err, "Failed to read tasks for reminders");
+            return;
+        }
+    };
+    let plans = {
+        let server = server.lock().await;
+        tasks
+            .into_iter()
+            .filter_map(|(id, tasks)| {
+                let plan = server.reminders.plan(&id, &tasks, now, lead)?;
+                Some((id, plan))
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // sent without holding the lock, anything that fails is tried again next time
+    for (id, plan) in plans {
+        if let Some(email) = &plan.email {
+            if let Err(err) = smtp::send(&config.smtp, email).await {
+                warn!(id, %err, "Failed to send reminder email");
+                continue;
+            }
+            info!(id, "Sent reminder email");
+        }
+        if let Err(err) = server.lock().await.reminders.sent(&id, plan) {
+            warn!(%err, "Failed to save reminders");
+        }
+    }
+}
+
+/// Send reminders every `interval_secs` while the server runs.
+pub async fn schedule(server: Arc<Mutex<Server>>, config: RemindersConfig) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+    loop {
+        interval.tick().await;
+        remind(&server, &config).await;
+    }
+}
+
+pub async fn settings_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Response {
+    let server = server.lock().await;
+    if server.config.reminders.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(server.reminders.status(user.doc_id())).into_response()
+}
+
+/// Save the settings, emailing a code to the address if it still needs confirming.
+pub async fn update_settings_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+    Json(settings): Json<ReminderSettings>,
+) -> Response {
+    if let Err(err) = settings.validate() {
+        return (StatusCode::BAD_REQUEST, err).into_response();
+    }
+    let (config, email) = {
+        let mut server = server.lock().await;
+        let Some(config) = server.config.reminders.clone() else {
+            return StatusCode::NOT_FOUND.into_response();
+        };
+        let email = server
+            .reminders
+            .set_settings(user.doc_id(), settings)
+            .and_then(|()| {
+                server
+                    .reminders
+                    .confirmation_email(user.doc_id(), Utc::now())
+            });
+        match email {
+            Ok(email) => (config, email),
+            Err(err) => {
+                warn!(%err, "Failed to save reminders");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+
+    // sent without holding the lock
+    if let Some(email) = email {
+        if let Err(err) = smtp::send(&config.smtp, &email).await {
+            warn!(%err, "Failed to send confirmation email");
+            let mut server = server.lock().await;
+            if let Err(err) = server
+                .reminders
+                .confirmation_unsent(user.doc_id(), &email.to)
+            {
+                warn!(%err, "Failed to save reminders");
+            }
+            return (
+                StatusCode::BAD_GATEWAY,
+                "Failed to send the confirmation email, try saving again later.",
+            )
+                .into_response();
+        }
+        info!("Sent confirmation email");
+    }
+    Json(server.lock().await.reminders.status(user.doc_id())).into_response()
+}
+
+pub async fn confirm_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+    Json(confirm): Json<ConfirmEmail>,
+) -> Response {
+    let mut server = server.lock().await;
+    if server.config.reminders.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    match server
+        .reminders
+        .confirm(user.doc_id(), &confirm.code, Utc::now())
+    {
+        Ok(()) => Json(server.reminders.status(user.doc_id())).into_response(),
+        Err(ConfirmError::Save(err)) => {
+            warn!(%err, "Failed to save reminders");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tasknet_shared::{reminders::QuietHours, task};
+
+    use super::*;
+    use crate::harness::{SyncClient, TestServer};
+
+    fn task(description: &str, due: DateTime<Utc>) -> (TaskId, Task) {
+        let mut task = Task::new();
+        task.set_description(description.to_owned());
+        task.set_due(Some(task::DateTime(due)));
+        (task.id().clone(), task)
+    }
+
+    /// Confirm the email as though the code sent to it was entered.
+    fn confirm(reminders: &mut Reminders, document: &str) {
+        reminders.confirmation_email(document, Utc::now()).unwrap();
+        let code = reminders.subscribers[document]
+            .confirmation
+            .as_ref()
+            .unwrap()
+            .code
+            .clone();
+        reminders.confirm(document, &code, Utc::now()).unwrap();
+    }
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(&format!("2023-06-01T{hour:02}:00:00Z"))
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[tokio::test]
+    async fn test_immediate_reminders_are_sent_once() {
+        let (smtp, received) = smtp::tests::stand_in().await;
+        let dir = std::env::temp_dir().join(format!("tasknet-reminders-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut reminders = Reminders::load(&dir).unwrap();
+        reminders
+            .set_settings(
+                "doc",
+                ReminderSettings {
+                    enabled: true,
+                    email: "someone@example.com".to_owned(),
+                    quiet_hours: Some(QuietHours { start: 22, end: 7 }),
+                    ..ReminderSettings::default()
+                },
+            )
+            .unwrap();
+        confirm(&mut reminders, "doc");
+        let mut completed = task("done", at(10));
+        completed.1.complete();
+        let tasks = HashMap::from([
+            task("soon", at(10)),
+            task("later", at(18)),
+            task("overdue", at(1)),
+            completed,
+        ]);
+        let lead = chrono::Duration::minutes(60);
+
+        // nothing is sent in quiet hours
+        assert!(reminders.plan("doc", &tasks, at(23), lead).is_none());
+
+        let plan = reminders.plan("doc", &tasks, at(9), lead).unwrap();
+        smtp::send(&smtp, plan.email.as_ref().unwrap())
+            .await
+            .unwrap();
+        reminders.sent("doc", plan).unwrap();
+        let message = received.lock().await.pop().unwrap();
+        assert!(message.contains("Subject: TaskNet: 2 tasks to look at"));
+        assert!(message.contains("- overdue was due Thu  1 Jun 01:00"));
+        assert!(message.contains("- soon is due Thu  1 Jun 10:00"));
+        assert!(!message.contains("later"));
+        assert!(!message.contains("done"));
+
+        // survives a restart without sending them again
+        let reminders = Reminders::load(&dir).unwrap();
+        assert!(reminders.plan("doc", &tasks, at(9), lead).is_none());
+        let plan = reminders.plan("doc", &tasks, at(17), lead).unwrap();
+        assert_eq!(plan.sent.len(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_digest_once_a_day_in_local_time() {
+        let subscriber = Subscriber {
+            settings: ReminderSettings {
+                enabled: true,
+                email: "someone@example.com".to_owned(),
+                mode: ReminderMode::Digest { hour: 8 },
+                quiet_hours: None,
+                // 8 local is 6 UTC
+                utc_offset_minutes: 120,
+            },
+            confirmed_email: Some("someone@example.com".to_owned()),
+            ..Subscriber::default()
+        };
+        let tasks = HashMap::from([task("today", at(18)), task("overdue", at(1))]);
+        let lead = chrono::Duration::minutes(60);
+
+        assert!(subscriber.plan(&tasks, at(5), lead).is_none());
+        let plan = subscriber.plan(&tasks, at(6), lead).unwrap();
+        assert_eq!(plan.sent.len(), 2);
+        assert_eq!(plan.digest, NaiveDate::from_ymd_opt(2023, 6, 1));
+        assert!(plan
+            .email
+            .unwrap()
+            .body
+            .contains("today is due Thu  1 Jun 20:00"));
+
+        let subscriber = Subscriber {
+            last_digest: NaiveDate::from_ymd_opt(2023, 6, 1),
+            ..subscriber
+        };
+        assert!(subscriber.plan(&tasks, at(12), lead).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reminding_leaves_documents_closed() {
+        let (smtp, received) = smtp::tests::stand_in().await;
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        let (id, task) = task("soon", Utc::now() + chrono::Duration::minutes(30));
+        autosurgeon::reconcile_prop(&mut client.doc, automerge::ROOT, id.as_ref(), &task).unwrap();
+        client.sync().await;
+        drop(client);
+
+        {
+            let mut server = server.server.lock().await;
+            // as after a restart, with the document only in storage
+            server.documents.remove(&doc_id);
+            server
+                .reminders
+                .set_settings(
+                    &doc_id,
+                    ReminderSettings {
+                        enabled: true,
+                        email: "someone@example.com".to_owned(),
+                        ..ReminderSettings::default()
+                    },
+                )
+                .unwrap();
+            confirm(&mut server.reminders, &doc_id);
+        }
+        let config = RemindersConfig {
+            smtp,
+            interval_secs: default_interval_secs(),
+            lead_minutes: default_lead_minutes(),
+        };
+        remind(&server.server, &config).await;
+
+        let message = received.lock().await.pop().unwrap();
+        assert!(message.contains("- soon is due"));
+        assert!(!server.server.lock().await.documents.contains_key(&doc_id));
+    }
+
+    #[test]
+    fn test_email_must_be_confirmed() {
+        let dir = std::env::temp_dir().join(format!("tasknet-reminders-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut reminders = Reminders::load(&dir).unwrap();
+        let settings = ReminderSettings {
+            enabled: true,
+            email: "someone@example.com".to_owned(),
+            ..ReminderSettings::default()
+        };
+        reminders.set_settings("doc", settings.clone()).unwrap();
+        let tasks = HashMap::from([task("soon", at(10))]);
+        let lead = chrono::Duration::minutes(60);
+        assert!(reminders.plan("doc", &tasks, at(9), lead).is_none());
+        assert!(matches!(
+            reminders.confirm("doc", "123456", at(9)),
+            Err(ConfirmError::NotSent)
+        ));
+
+        let email = reminders.confirmation_email("doc", at(9)).unwrap().unwrap();
+        assert_eq!(email.to, "someone@example.com");
+        let code = reminders.subscribers["doc"]
+            .confirmation
+            .as_ref()
+            .unwrap()
+            .code
+            .clone();
+        assert!(email.body.contains(&code));
+        // not sent again straight away
+        assert!(reminders
+            .confirmation_email("doc", at(9))
+            .unwrap()
+            .is_none());
+
+        let wrong = if code == "000000" { "111111" } else { "000000" };
+        for _ in 0..CONFIRMATION_ATTEMPTS {
+            assert!(matches!(
+                reminders.confirm("doc", wrong, at(9)),
+                Err(ConfirmError::Wrong)
+            ));
+        }
+        assert!(matches!(
+            reminders.confirm("doc", &code, at(9)),
+            Err(ConfirmError::TooManyAttempts)
+        ));
+
+        let email = reminders
+            .confirmation_email("doc", at(10))
+            .unwrap()
+            .unwrap();
+        let code = reminders.subscribers["doc"]
+            .confirmation
+            .as_ref()
+            .unwrap()
+            .code
+            .clone();
+        assert!(email.body.contains(&code));
+        assert!(matches!(
+            reminders.confirm("doc", &code, at(10) + chrono::Duration::days(2)),
+            Err(ConfirmError::Expired)
+        ));
+        reminders.confirm("doc", &code, at(10)).unwrap();
+        assert!(reminders.status("doc").email_confirmed);
+        assert!(reminders.plan("doc", &tasks, at(9), lead).is_some());
+
+        // changing the address needs it confirming again
+        reminders
+            .set_settings(
+                "doc",
+                ReminderSettings {
+                    email: "someone.else@example.com".to_owned(),
+                    ..settings
+                },
+            )
+            .unwrap();
+        assert!(!reminders.status("doc").email_confirmed);
+        assert!(reminders.plan("doc", &tasks, at(9), lead).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::{fmt::Display, time::Duration};
+
+use lettre::{
+    address::AddressError, message::header::ContentType,
+    transport::smtp::authentication::Credentials, AsyncSmtpTransport, AsyncTransport, Message,
+    Tokio1Executor,
+};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+/// How long to wait for the server before giving up on an email.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub security: Security,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The address reminders are sent from.
+    pub from: String,
+}
+
+// written out so the config can be logged without the password
+impl std::fmt::Debug for SmtpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmtpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("security", &self.security)
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("from", &self.from)
+            .finish()
+    }
+}
+
+const fn default_port() -> u16 {
+    587
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Security {
+    /// Upgrade the connection with STARTTLS, failing if the server doesn't offer it.
+    #[default]
+    StartTls,
+    /// Connect with TLS from the start, usually on port 465.
+    Tls,
+    /// Send in plaintext, only for a relay on the same machine.
+    None,
+}
+
+impl SmtpConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.host.is_empty() {
+            problems.push("reminders.smtp.host must be set".to_owned());
+        }
+        if !self.from.contains('@') {
+            problems.push(format!(
+                "reminders.smtp.from {:?} is not an email address",
+                self.from
+            ));
+        }
+        if self.username.is_some() != self.password.is_some() {
+            problems.push(
+                "reminders.smtp.username and reminders.smtp.password must be set together"
+                    .to_owned(),
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SmtpError {
+    /// The from or to address isn't one.
+    Address(AddressError),
+    Message(lettre::error::Error),
+    Transport(lettre::transport::smtp::Error),
+}
+
+impl Display for SmtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Address(err) => write!(f, "invalid address: {err}"),
+            Self::Message(err) => err.fmt(f),
+            Self::Transport(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SmtpError {}
+
+impl From<AddressError> for SmtpError {
+    fn from(err: AddressError) -> Self {
+        Self::Address(err)
+    }
+}
+
+impl From<lettre::error::Error> for SmtpError {
+    fn from(err: lettre::error::Error) -> Self {
+        Self::Message(err)
+    }
+}
+
+impl From<lettre::transport::smtp::Error> for SmtpError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        Self::Transport(err)
+    }
+}
+
+/// A plain text email.
+#[derive(Debug, Clone)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+fn transport(config: &SmtpConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>, SmtpError> {
+    let builder = match config.security {
+        Security::StartTls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?,
+        Security::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        Security::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    };
+    let builder = builder.port(config.port).timeout(Some(TIMEOUT));
+    let builder = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+    Ok(builder.build())
+}
+
+/// Send an email with the configured server.
+pub async fn send(config: &SmtpConfig, email: &Email) -> Result<(), SmtpError> {
+    let message = Message::builder()
+        .from(config.from.parse()?)
+        .to(email.to.parse()?)
+        .subject(&email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body.clone())?;
+    let response = transport(config)?.send(message).await?;
+    debug!(code = %response.code(), "Sent email");
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::sync::Arc;
+
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::Mutex,
+    };
+
+    use super::*;
+
+    /// A local SMTP server accepting everything, recording the messages it receives.
+    pub async fn stand_in() -> (SmtpConfig, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let messages = received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let (read, mut write) = socket.into_split();
+                let mut lines = BufReader::new(read).lines();
+                write.write_all(b"220 stand-in ready\r\n").await.unwrap();
+                let mut data = None::<String>;
+                while let Ok(Some(line)) = lines.next_line().await {
+                    if let Some(message) = &mut data {
+                        if line == "." {
+                            messages.lock().await.push(std::mem::take(message));
+                            data = None;
+                            write.write_all(b"250 queued\r\n").await.unwrap();
+                        } else {
+                            message.push_str(&line);
+                            message.push('\n');
+                        }
+                        continue;
+                    }
+                    let reply: &[u8] = match line.split(' ').next().unwrap_or_default() {
+                        "EHLO" => b"250-stand-in\r\n250-AUTH PLAIN\r\n250 8BITMIME\r\n",
+                        "AUTH" => b"235 ok\r\n",
+                        "DATA" => {
+                            data = Some(String::new());
+                            b"354 go ahead\r\n"
+                        }
+                        "QUIT" => b"221 bye\r\n",
+                        _ => b"250 ok\r\n",
+                    };
+                    write.write_all(reply).await.unwrap();
+                }
+            }
+        });
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_owned(),
+            port,
+            security: Security::None,
+            username: Some("user".to_owned()),
+            password: Some("password".to_owned()),
+            from: "tasknet@example.com".to_owned(),
+        };
+        (config, received)
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (config, received) = stand_in().await;
+        let email = Email {
+            to: "someone@example.com".to_owned(),
+            subject: "Tasks due\r\nBcc: someone-else@example.com".to_owned(),
+            body: "First line\n.hidden dot".to_owned(),
+        };
+        send(&config, &email).await.unwrap();
+
+        let received = received.lock().await;
+        assert_eq!(received.len(), 1);
+        let message = &received[0];
+        assert!(message.contains("To: someone@example.com\n"));
+        // user text can't add headers
+        assert!(!message.contains("\nBcc:"));
+        assert!(message.contains("\nFirst line\n..hidden dot\n"));
+
+        // starttls is required unless turned off
+        let config = SmtpConfig {
+            security: Security::StartTls,
+            ..config
+        };
+        assert!(matches!(
+            send(&config, &email).await,
+            Err(SmtpError::Transport(_))
+        ));
+    }
+}
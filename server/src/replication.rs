@@ -0,0 +1,456 @@
+use std::{collections::HashSet, fmt::Display, sync::Arc, time::Duration};
+
+use futures::{SinkExt, StreamExt};
+use reqwest::{
+    header::{HeaderValue, COOKIE, SET_COOKIE},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tasknet_shared::sync::{SyncMessage, CLOSE_DOCUMENT_ENCRYPTED};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast::error::RecvError, Mutex},
+};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    limits::LimitExceeded,
+    server::{ApplyError, LoadError, Server},
+};
+
+/// Keep documents in sync with another tasknet server by syncing with it like a client would.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicationConfig {
+    /// The server to replicate with, such as `https://tasks.example.com`.
+    pub upstream: String,
+    pub documents: Vec<ReplicatedDocument>,
+    /// How long to wait before reconnecting, doubling after each failure.
+    #[serde(default = "default_retry_secs")]
+    pub retry_secs: u64,
+    #[serde(default = "default_max_retry_secs")]
+    pub max_retry_secs: u64,
+}
+
+const fn default_retry_secs() -> u64 {
+    5
+}
+
+const fn default_max_retry_secs() -> u64 {
+    5 * 60
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicatedDocument {
+    /// The id of the document on this server.
+    pub id: String,
+    /// How to sign in to the upstream, which decides the document it is synced with there.
+    #[serde(default)]
+    pub auth: UpstreamAuth,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(tag = "provider", rename_all = "snake_case")]
+pub enum UpstreamAuth {
+    /// Sign in to the public document with the same id.
+    #[default]
+    Public,
+    /// Sign in to a local account, syncing with the account's document.
+    Local { username: String, password: String },
+}
+
+// written out so the config can be logged without the password
+impl std::fmt::Debug for UpstreamAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Public => f.write_str("Public"),
+            Self::Local { username, .. } => f
+                .debug_struct("Local")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+impl ReplicationConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if ws_url(&self.upstream).is_none() {
+            problems.push(format!(
+                "replication.upstream {:?} must start with http:// or https://",
+                self.upstream
+            ));
+        }
+        if self.documents.is_empty() {
+            problems.push("replication.documents must list at least one document".to_owned());
+        }
+        let mut ids = HashSet::new();
+        for document in &self.documents {
+            if !ids.insert(&document.id) {
+                problems.push(format!(
+                    "replication.documents has {:?} more than once",
+                    document.id
+                ));
+            }
+            if matches!(document.auth, UpstreamAuth::Public)
+                && document.id.parse::<uuid::Uuid>().is_err()
+            {
+                problems.push(format!(
+                    "replication.documents {:?} must be a uuid to sign in to it publicly",
+                    document.id
+                ));
+            }
+        }
+        if self.retry_secs == 0 || self.max_retry_secs < self.retry_secs {
+            problems.push(
+                "replication.retry_secs must be at least 1 and no more than max_retry_secs"
+                    .to_owned(),
+            );
+        }
+    }
+}
+
+/// The websocket address for the upstream's sync route.
+fn ws_url(upstream: &str) -> Option<String> {
+    let upstream = upstream.trim_end_matches('/');
+    if let Some(rest) = upstream.strip_prefix("https://") {
+        Some(format!("wss://{rest}/sync"))
+    } else {
+        upstream
+            .strip_prefix("http://")
+            .map(|rest| format!("ws://{rest}/sync"))
+    }
+}
+
+#[derive(Debug)]
+enum ReplicationError {
+    Http(reqwest::Error),
+    /// The upstream didn't start a session, such as for a wrong password.
+    SignIn(StatusCode),
+    WebSocket(tungstenite::Error),
+    /// The upstream sent something that isn't a valid sync message.
+    Protocol(String),
+    Load(LoadError),
+    Document(String),
+    /// The upstream's changes would take the document over this server's limits.
+    Limit(LimitExceeded),
+    /// The document is end-to-end encrypted on one of the servers so can't be merged.
+    Encrypted,
+}
+
+impl Display for ReplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(err) => err.fmt(f),
+            Self::SignIn(status) => write!(f, "failed to sign in to the upstream: {status}"),
+            Self::WebSocket(err) => err.fmt(f),
+            Self::Protocol(err) => write!(f, "invalid message from the upstream: {err}"),
+            Self::Load(err) => err.fmt(f),
+            Self::Document(err) => write!(f, "failed to apply changes: {err}"),
+            Self::Limit(err) => write!(f, "refusing changes from the upstream: {err}"),
+            Self::Encrypted => write!(f, "document is end-to-end encrypted"),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ReplicationError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl From<tungstenite::Error> for ReplicationError {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocket(err)
+    }
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Replicate each configured document until the server stops.
+pub async fn run(server: Arc<Mutex<Server>>, config: ReplicationConfig) {
+    for document in &config.documents {
+        tokio::spawn(replicate(server.clone(), config.clone(), document.clone()));
+    }
+}
+
+async fn replicate(
+    server: Arc<Mutex<Server>>,
+    config: ReplicationConfig,
+    document: ReplicatedDocument,
+) {
+    let shutdown = server.lock().await.shutdown.clone();
+    let mut retry = config.retry_secs;
+    while !shutdown.is_stopping() {
+        match replicate_once(&server, &config.upstream, &document).await {
+            Ok(()) => {
+                info!(id = document.id, "Replication connection closed");
+                retry = config.retry_secs;
+            }
+            Err(ReplicationError::Encrypted) => {
+                warn!(
+                    id = document.id,
+                    "Not replicating end-to-end encrypted document"
+                );
+                return;
+            }
+            Err(err) => {
+                warn!(id = document.id, %err, retry_secs = retry, "Failed to replicate document");
+                retry = (retry * 2).min(config.max_retry_secs);
+            }
+        }
+        tokio::select! {
+            () = tokio::time::sleep(Duration::from_secs(retry)) => {}
+            () = shutdown.stopping() => {}
+        }
+    }
+}
+
+/// Sign in to the upstream, returning the cookies of the new session.
+async fn sign_in(
+    upstream: &str,
+    document: &ReplicatedDocument,
+) -> Result<String, ReplicationError> {
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()?;
+    let upstream = upstream.trim_end_matches('/');
+    let request = match &document.auth {
+        UpstreamAuth::Public => client.get(format!(
+            "{upstream}/auth/public/sign_in?doc_id={}",
+            document.id
+        )),
+        UpstreamAuth::Local { username, password } => client
+            .post(format!("{upstream}/auth/local/sign_in"))
+            .form(&[("username", username), ("password", password)]),
+    };
+    let res = request.send().await?;
+    let cookies = res
+        .headers()
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok()?.split(';').next())
+        .collect::<Vec<_>>()
+        .join("; ");
+    if !res.status().is_redirection() || cookies.is_empty() {
+        return Err(ReplicationError::SignIn(res.status()));
+    }
+    Ok(cookies)
+}
+
+/// Sync with the upstream until the connection closes.
+async fn replicate_once(
+    server: &Arc<Mutex<Server>>,
+    upstream: &str,
+    document: &ReplicatedDocument,
+) -> Result<(), ReplicationError> {
+    let cookies = sign_in(upstream, document).await?;
+    // checked when validating the config
+    let mut request = ws_url(upstream).unwrap_or_default().into_client_request()?;
+    request.headers_mut().insert(
+        COOKIE,
+        HeaderValue::from_str(&cookies)
+            .map_err(|err| ReplicationError::Protocol(err.to_string()))?,
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(request).await?;
+    info!(id = document.id, upstream, "Replicating document");
+
+    // like a client connection, a new connection is a new peer that starts from nothing
+    let peer_id = uuid::Uuid::new_v4().as_bytes().to_vec();
+    let (changed, mut changes, shutdown) = {
+        let server = server.lock().await;
+        (
+            server.changed.clone(),
+            server.changed.subscribe(),
+            server.shutdown.clone(),
+        )
+    };
+    send_changes(server, &document.id, &peer_id, &mut socket).await?;
+    loop {
+        tokio::select! {
+            msg = socket.next() => match msg {
+                Some(Ok(Message::Binary(bytes))) => {
+                    let msg = SyncMessage::try_from(&bytes)
+                        .map_err(|err| ReplicationError::Protocol(err.to_string()))?;
+                    if let SyncMessage::Message(bytes) = msg {
+                        receive_changes(server, &document.id, &peer_id, &bytes).await?;
+                        // let this server's clients know
+                        let _ = changed.send(());
+                    }
+                }
+                Some(Ok(Message::Close(frame))) => {
+                    let code = frame.as_ref().map(|frame| u16::from(frame.code));
+                    if code == Some(CLOSE_DOCUMENT_ENCRYPTED) {
+                        return Err(ReplicationError::Encrypted);
+                    }
+                    debug!(?frame, "Upstream closed the connection");
+                    return Ok(());
+                }
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+                None => return Ok(()),
+            },
+            res = changes.recv() => {
+                if let Err(RecvError::Closed) = res {
+                    return Ok(());
+                }
+            }
+            () = shutdown.stopping() => {
+                let _ = socket.close(None).await;
+                return Ok(());
+            }
+        }
+        send_changes(server, &document.id, &peer_id, &mut socket).await?;
+    }
+}
+
+async fn receive_changes(
+    server: &Mutex<Server>,
+    id: &str,
+    peer_id: &[u8],
+    bytes: &[u8],
+) -> Result<(), ReplicationError> {
+    let msg = automerge::sync::Message::decode(bytes)
+        .map_err(|err| ReplicationError::Protocol(err.to_string()))?;
+    // the same checks and notifications as changes from a client
+    server
+        .lock()
+        .await
+        .apply_sync_message(id, peer_id.to_vec(), msg)
+        .map_err(|err| match err {
+            ApplyError::Load(err) => load_error(err),
+            ApplyError::Limit(err) => ReplicationError::Limit(err),
+            ApplyError::Invalid(err) => ReplicationError::Document(err.to_string()),
+            ApplyError::Flush(err) => ReplicationError::Document(err.to_string()),
+        })
+}
+
+async fn send_changes(
+    server: &Mutex<Server>,
+    id: &str,
+    peer_id: &[u8],
+    socket: &mut Socket,
+) -> Result<(), ReplicationError> {
+    let msg = {
+        let mut server = server.lock().await;
+        let document = server.load_document(id).map_err(load_error)?;
+        document
+            .generate_sync_message(peer_id.to_vec())
+            .map_err(|err| ReplicationError::Document(err.to_string()))?
+    };
+    if let Some(msg) = msg {
+        let bytes = Vec::try_from(SyncMessage::Message(msg.encode()))
+            .map_err(|err| ReplicationError::Protocol(err.to_string()))?;
+        socket.send(Message::Binary(bytes)).await?;
+    }
+    Ok(())
+}
+
+fn load_error(err: LoadError) -> ReplicationError {
+    match err {
+        LoadError::Encrypted => ReplicationError::Encrypted,
+        err => ReplicationError::Load(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use autosurgeon::hydrate;
+    use tasknet_shared::task::{Task, TaskId};
+
+    use super::*;
+    use crate::harness::{SyncClient, TestServer};
+
+    #[test]
+    fn test_ws_url() {
+        assert_eq!(
+            ws_url("https://tasks.example.com/").as_deref(),
+            Some("wss://tasks.example.com/sync")
+        );
+        assert_eq!(
+            ws_url("http://127.0.0.1:3000").as_deref(),
+            Some("ws://127.0.0.1:3000/sync")
+        );
+        assert_eq!(ws_url("tasks.example.com"), None);
+    }
+
+    #[tokio::test]
+    async fn test_servers_converge() {
+        let work = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let config = ReplicationConfig {
+            upstream: format!("http://{}", work.address),
+            documents: vec![ReplicatedDocument {
+                id: doc_id.clone(),
+                auth: UpstreamAuth::Public,
+            }],
+            retry_secs: 1,
+            max_retry_secs: 1,
+        };
+        let home = TestServer::start_with(|c| c.replication = Some(config)).await;
+
+        let mut at_work = SyncClient::connect(&work, &work.sign_in_public(&doc_id).await).await;
+        let mut at_home = SyncClient::connect(&home, &home.sign_in_public(&doc_id).await).await;
+        for (client, description) in [(&mut at_work, "at work"), (&mut at_home, "at home")] {
+            let mut tasks = HashMap::new();
+            let mut task = Task::new();
+            task.set_description(description.to_owned());
+            tasks.insert(task.id().clone(), task);
+            autosurgeon::reconcile(&mut client.doc, &tasks).unwrap();
+        }
+
+        // changes go through both servers so give them a few rounds
+        for _ in 0..10 {
+            futures::future::join(at_work.sync(), at_home.sync()).await;
+            if at_work.heads() == at_home.heads() {
+                break;
+            }
+        }
+        let tasks: HashMap<TaskId, Task> = hydrate(&at_home.doc).unwrap();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(at_work.heads(), at_home.heads());
+        let stored: HashMap<TaskId, Task> = hydrate(&home.stored_document(&doc_id)).unwrap();
+        assert_eq!(stored, tasks);
+    }
+
+    #[tokio::test]
+    async fn test_replicated_changes_respect_limits() {
+        let work = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let mut at_work = SyncClient::connect(&work, &work.sign_in_public(&doc_id).await).await;
+        let mut tasks = HashMap::new();
+        for description in ["one", "two"] {
+            let mut task = Task::new();
+            task.set_description(description.to_owned());
+            tasks.insert(task.id().clone(), task);
+        }
+        autosurgeon::reconcile(&mut at_work.doc, &tasks).unwrap();
+        at_work.sync().await;
+
+        let config = ReplicationConfig {
+            upstream: format!("http://{}", work.address),
+            documents: vec![ReplicatedDocument {
+                id: doc_id.clone(),
+                auth: UpstreamAuth::Public,
+            }],
+            retry_secs: 1,
+            max_retry_secs: 1,
+        };
+        let home = TestServer::start_with(|c| {
+            c.replication = Some(config);
+            c.limits.max_tasks = 1;
+        })
+        .await;
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        let mut server = home.server.lock().await;
+        let document = server.load_document(&doc_id).unwrap();
+        let stored: HashMap<TaskId, Task> = hydrate(document.document()).unwrap();
+        assert!(stored.is_empty());
+    }
+}
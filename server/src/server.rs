@@ -1,50 +1,115 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, fmt::Display, sync::Arc, time::Duration};
 
-use crate::{auth::google::Google, auth::UserSessionData, config::ServerConfig};
-use async_session::MemoryStore;
-use automerge_persistent_fs::FsPersisterError;
+use crate::{
+    auth::{google::Google, local::Local, session_cookie, sessions::Sessions, UserSessionData},
+    config::ServerConfig,
+    encrypted::EncryptedDocument,
+    limits::{self, LimitExceeded},
+    metrics::Metrics,
+    presence::{PresenceThrottle, Presences},
+    reminders::Reminders,
+    shutdown::Shutdown,
+    storage::{DocumentPersister, Storage, StorageError},
+    webhooks::{self, Webhooks},
+};
+use async_session::Session;
 use axum::{
     extract::{
-        ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        State, TypedHeader, WebSocketUpgrade,
     },
-    response::Response,
+    headers::Cookie,
+    http::StatusCode,
+    response::{IntoResponse, Response},
 };
 use futures::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use tasknet_shared::sync::SyncMessage;
-use tokio::sync::Mutex;
+use tasknet_shared::sync::{
+    SyncMessage, CLOSE_DOCUMENT_ENCRYPTED, CLOSE_LIMIT_EXCEEDED, CLOSE_SERVER_RESTARTING,
+    CLOSE_SESSION_ENDED,
+};
+use tokio::sync::{broadcast::error::RecvError, oneshot, watch, Mutex};
 use tracing::{debug, info, warn};
 
+/// How long to wait for a close frame to be sent before dropping the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 struct ConnectionMetadata {
     peer_id: uuid::Uuid,
 }
 
-type Document = automerge_persistent::PersistentAutomerge<automerge_persistent_fs::FsPersister>;
+pub(crate) type Document = automerge_persistent::PersistentAutomerge<DocumentPersister>;
+
+#[derive(Debug)]
+pub enum LoadError {
+    /// The document is end-to-end encrypted so there is nothing to merge with.
+    Encrypted,
+    Persister(automerge_persistent::Error<StorageError>),
+}
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Encrypted => write!(f, "document is end-to-end encrypted"),
+            Self::Persister(err) => err.fmt(f),
+        }
+    }
+}
+
+/// Why changes from a peer weren't applied.
+#[derive(Debug)]
+pub enum ApplyError {
+    Load(LoadError),
+    /// The changes would take the document over a limit.
+    Limit(LimitExceeded),
+    /// The sync message couldn't be applied to the document.
+    Invalid(automerge_persistent::Error<StorageError>),
+    Flush(StorageError),
+}
+
+impl Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Load(err) => err.fmt(f),
+            Self::Limit(err) => err.fmt(f),
+            Self::Invalid(err) => write!(f, "failed to apply sync message: {err}"),
+            Self::Flush(err) => write!(f, "failed to flush document: {err}"),
+        }
+    }
+}
 
 pub struct Server {
     pub(crate) documents: HashMap<String, Document>,
+    pub(crate) encrypted_documents: HashMap<String, EncryptedDocument>,
     pub(crate) changed: tokio::sync::broadcast::Sender<()>,
     pub(crate) config: ServerConfig,
+    pub(crate) storage: Storage,
     pub(crate) google: Option<Google>,
-    pub(crate) sessions: MemoryStore,
+    pub(crate) local: Option<Local>,
+    pub(crate) sessions: Sessions,
+    pub(crate) metrics: Metrics,
+    pub(crate) webhooks: Webhooks,
+    pub(crate) reminders: Reminders,
+    pub(crate) presence: Presences,
+    pub(crate) shutdown: Shutdown,
 }
 
 impl Server {
-    fn load_document(
-        &mut self,
-        id: &str,
-    ) -> Result<&mut Document, automerge_persistent::Error<FsPersisterError>> {
+    pub(crate) fn load_document(&mut self, id: &str) -> Result<&mut Document, LoadError> {
         if !self.documents.contains_key(id) {
+            if self.is_encrypted(id) {
+                return Err(LoadError::Encrypted);
+            }
             debug!(id, "Loading document");
-            let persister =
-                automerge_persistent_fs::FsPersister::new(&self.config.documents_dir, id)
-                    .map_err(automerge_persistent::Error::PersisterError)?;
+            let persister = self.storage.persister(id).map_err(|err| {
+                LoadError::Persister(automerge_persistent::Error::PersisterError(err))
+            })?;
 
-            let doc = automerge_persistent::PersistentAutomerge::load(persister)?;
+            let doc = automerge_persistent::PersistentAutomerge::load(persister)
+                .map_err(LoadError::Persister)?;
 
             self.documents.insert(id.to_owned(), doc);
             debug!(id, "Loaded document");
@@ -54,42 +119,242 @@ impl Server {
 
         Ok(self.documents.get_mut(id).unwrap())
     }
+
+    pub(crate) fn is_encrypted(&self, id: &str) -> bool {
+        // connecting to an encrypted sync doesn't encrypt the document until something is stored
+        self.encrypted_documents
+            .get(id)
+            .is_some_and(|document| !document.is_empty())
+            || EncryptedDocument::exists(&self.config.documents_dir, id)
+    }
+
+    pub(crate) fn load_encrypted_document(
+        &mut self,
+        id: &str,
+    ) -> std::io::Result<&mut EncryptedDocument> {
+        if !self.encrypted_documents.contains_key(id) {
+            debug!(id, "Loading encrypted document");
+            let doc = EncryptedDocument::load(&self.config.documents_dir, id)?;
+            self.encrypted_documents.insert(id.to_owned(), doc);
+        }
+        Ok(self.encrypted_documents.get_mut(id).unwrap())
+    }
+
+    /// Apply a peer's sync message to the document, refusing changes over the limits and telling
+    /// webhook subscribers what changed.
+    pub(crate) fn apply_sync_message(
+        &mut self,
+        id: &str,
+        peer_id: Vec<u8>,
+        msg: automerge::sync::Message,
+    ) -> Result<(), ApplyError> {
+        let limits = self.config.limits.clone();
+        let metrics = self.metrics.clone();
+        // only read the tasks to compare when someone is listening
+        let watched = !msg.changes.is_empty() && self.webhooks.has_subscriptions(id);
+        let document = self.load_document(id).map_err(ApplyError::Load)?;
+        limits::check_changes(
+            &limits,
+            document.document(),
+            limits::document_bytes(document),
+            &msg.changes,
+        )
+        .map_err(ApplyError::Limit)?;
+        let before = watched
+            .then(|| webhooks::tasks(document.document()))
+            .flatten();
+        document
+            .receive_sync_message(peer_id, msg)
+            .map_err(ApplyError::Invalid)?;
+        let num_changes = document.document().get_changes(&[]).unwrap().len();
+        debug!("applied sync message, now have {}", num_changes);
+        let timer = metrics.flush_seconds.start_timer();
+        document.flush().map_err(ApplyError::Flush)?;
+        timer.observe_duration();
+        debug!("flushed");
+        let events = before
+            .zip(webhooks::tasks(document.document()))
+            .map(|(before, after)| webhooks::diff(&before, &after))
+            .unwrap_or_default();
+        self.webhooks.notify(id, &events);
+        Ok(())
+    }
+
+    /// Unload and delete the plaintext copy of a document that has been encrypted.
+    pub(crate) fn remove_plaintext_document(&mut self, id: &str) {
+        self.documents.remove(id);
+        if self.storage.exists(id).unwrap_or(true) {
+            info!(id, "Removing plaintext copy of encrypted document");
+            if let Err(err) = self.storage.delete(id) {
+                warn!(id, %err, "Failed to remove plaintext document");
+            }
+        }
+    }
+}
+
+/// The id of the session in the cookie, for tracking the websockets using it.
+pub(crate) fn session_id(cookie: &Option<TypedHeader<Cookie>>) -> String {
+    // the session must exist for the user to have been extracted
+    session_cookie(cookie)
+        .and_then(|session_cookie| Session::id_from_cookie_value(session_cookie).ok())
+        .unwrap_or_default()
 }
 
 pub async fn sync_handler(
     ws: WebSocketUpgrade,
     user: UserSessionData,
+    cookie: Option<TypedHeader<Cookie>>,
     State(server): State<Arc<Mutex<Server>>>,
 ) -> Response {
-    ws.on_upgrade(|socket| handle_sync_socket(socket, server, user))
+    let session_id = session_id(&cookie);
+    if server.lock().await.shutdown.is_stopping() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Server restarting").into_response();
+    }
+    // sync_read refuses messages over the limit with a clear reason, this only stops a client
+    // from making the server buffer arbitrarily large frames
+    let max_message_bytes = server.lock().await.config.limits.max_message_bytes;
+    ws.max_message_size(max_message_bytes.saturating_mul(2))
+        .on_upgrade(|socket| handle_sync_socket(socket, server, user, session_id))
 }
 
-async fn handle_sync_socket(socket: WebSocket, server: Arc<Mutex<Server>>, user: UserSessionData) {
+async fn handle_sync_socket(
+    mut socket: WebSocket,
+    server: Arc<Mutex<Server>>,
+    user: UserSessionData,
+    session_id: String,
+) {
+    if server.lock().await.is_encrypted(user.doc_id()) {
+        debug!("Refusing plaintext sync of an encrypted document");
+        let _ = socket
+            .send(Message::Close(Some(CloseFrame {
+                code: CLOSE_DOCUMENT_ENCRYPTED,
+                reason: "Document is end-to-end encrypted".into(),
+            })))
+            .await;
+        return;
+    }
+
+    let (sessions, metrics, shutdown) = {
+        let server = server.lock().await;
+        (
+            server.sessions.clone(),
+            server.metrics.clone(),
+            server.shutdown.clone(),
+        )
+    };
+    let _connection = shutdown.connection();
+    let Some(session_ended) = sessions.connect_websocket(&session_id).await else {
+        debug!("Session ended before the sync connection started");
+        return;
+    };
+    metrics.sync_connections.inc();
+
     let (sender, receiver) = socket.split();
     let connection_metadata = ConnectionMetadata {
         peer_id: uuid::Uuid::new_v4(),
     };
     info!(?connection_metadata, "New sync connection");
 
-    tokio::spawn(sync_read(
+    let doc_id = user.doc_id().to_owned();
+    let (close, close_requested) = oneshot::channel();
+    let mut read = tokio::spawn(sync_read(
         server.clone(),
         connection_metadata.clone(),
         user.clone(),
         receiver,
     ));
-    tokio::spawn(sync_write(server, connection_metadata, user, sender));
+    let mut write = tokio::spawn(sync_write(
+        server.clone(),
+        connection_metadata.clone(),
+        user,
+        sender,
+        session_ended,
+        close_requested,
+    ));
+
+    // once either half finishes the connection is done with
+    tokio::select! {
+        frame = &mut read => match frame {
+            Ok(Some(frame)) => {
+                // let the client know why before dropping it
+                let _ = close.send(frame);
+                if tokio::time::timeout(CLOSE_TIMEOUT, &mut write).await.is_err() {
+                    write.abort();
+                }
+            }
+            _ => write.abort(),
+        },
+        _ = &mut write => {
+            // when stopping, give the client's reply to the close frame a chance to arrive so
+            // anything it sent first is applied
+            if !shutdown.is_stopping()
+                || tokio::time::timeout(CLOSE_TIMEOUT, &mut read).await.is_err()
+            {
+                read.abort();
+            }
+        }
+    }
+
+    server
+        .lock()
+        .await
+        .presence
+        .remove(&doc_id, connection_metadata.peer_id);
+    sessions.disconnect_websocket(&session_id).await;
+    metrics.sync_connections.dec();
+    info!(?connection_metadata, "Closed sync connection");
+}
+
+/// Close the connection as the client went over a limit.
+fn limit_exceeded(err: &LimitExceeded) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CLOSE_LIMIT_EXCEEDED,
+        reason: err.to_string().into(),
+    }
 }
 
+/// Close the connection as the client sent something that can't be applied.
+fn invalid_message(reason: &'static str) -> CloseFrame<'static> {
+    CloseFrame {
+        code: close_code::INVALID,
+        reason: reason.into(),
+    }
+}
+
+/// Apply the client's sync messages, returning the close frame to send if something it sent ended
+/// the connection.
 #[tracing::instrument(skip(server, receiver))]
 async fn sync_read(
     server: Arc<Mutex<Server>>,
     connection_metadata: ConnectionMetadata,
     user: UserSessionData,
     mut receiver: SplitStream<WebSocket>,
-) {
-    let changed = server.lock().await.changed.clone();
+) -> Option<CloseFrame<'static>> {
+    let (changed, metrics, limits) = {
+        let server = server.lock().await;
+        (
+            server.changed.clone(),
+            server.metrics.clone(),
+            server.config.limits.clone(),
+        )
+    };
+    let mut presence_throttle = PresenceThrottle::default();
     debug!("waiting for messages from client");
-    while let Some(msg) = receiver.next().await {
+    loop {
+        let msg = tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+            presence = presence_throttle.ready() => {
+                server.lock().await.presence.set(
+                    user.doc_id(),
+                    connection_metadata.peer_id,
+                    presence,
+                );
+                continue;
+            }
+        };
         debug!("received msg");
         match msg {
             Ok(msg) => {
@@ -98,39 +363,70 @@ async fn sync_read(
                     Message::Binary(b) => {
                         // parse the sync message
                         debug!("received binary ws message");
-                        let msg = SyncMessage::try_from(&b).unwrap();
+                        metrics.sync_received(b.len());
+                        if b.len() > limits.max_message_bytes {
+                            return Some(limit_exceeded(&LimitExceeded::Message {
+                                bytes: b.len(),
+                                max: limits.max_message_bytes,
+                            }));
+                        }
+                        let msg = match SyncMessage::try_from(&b) {
+                            Ok(msg) => msg,
+                            Err(err) => {
+                                info!(%err, "Closing connection that sent an invalid message");
+                                return Some(invalid_message("Invalid sync message"));
+                            }
+                        };
                         match msg {
                             SyncMessage::Message(bytes) => {
                                 {
                                     debug!("parsed message into sync message");
-                                    let msg = automerge::sync::Message::decode(&bytes).unwrap();
+                                    let msg = match automerge::sync::Message::decode(&bytes) {
+                                        Ok(msg) => msg,
+                                        Err(err) => {
+                                            info!(%err, "Closing connection that sent an invalid sync message");
+                                            return Some(invalid_message("Invalid sync message"));
+                                        }
+                                    };
                                     // apply the message to the document
-                                    let mut server = server.lock().await;
-                                    match server.load_document(user.doc_id()) {
-                                        Ok(document) => {
-                                            document
-                                                .receive_sync_message(
-                                                    connection_metadata.peer_id.as_bytes().to_vec(),
-                                                    msg,
-                                                )
-                                                .unwrap();
-                                            let num_changes =
-                                                document.document().get_changes(&[]).unwrap().len();
-                                            debug!(
-                                                "applied sync message, now have {}",
-                                                num_changes
-                                            );
-                                            document.flush().unwrap();
-                                            debug!("flushed");
+                                    let res = server.lock().await.apply_sync_message(
+                                        user.doc_id(),
+                                        connection_metadata.peer_id.as_bytes().to_vec(),
+                                        msg,
+                                    );
+                                    match res {
+                                        Ok(()) => {}
+                                        Err(ApplyError::Limit(err)) => {
+                                            info!(%err, "Refusing changes over the limits");
+                                            return Some(limit_exceeded(&err));
                                         }
-                                        Err(err) => {
-                                            warn!(id=user.doc_id(), %err, "Failed to load document, closing connection");
+                                        Err(ApplyError::Invalid(err)) => {
+                                            info!(%err, "Closing connection that sent changes that can't be applied");
+                                            return Some(invalid_message(
+                                                "Sync message could not be applied",
+                                            ));
+                                        }
+                                        Err(err @ (ApplyError::Load(_) | ApplyError::Flush(_))) => {
+                                            warn!(id=user.doc_id(), %err, "Failed to apply changes, closing connection");
                                             break;
                                         }
                                     }
                                 }
                                 let _ = changed.send(());
                             }
+                            SyncMessage::Presence(presence) => {
+                                debug!("received presence");
+                                if let Some(presence) = presence_throttle.accept(presence) {
+                                    server.lock().await.presence.set(
+                                        user.doc_id(),
+                                        connection_metadata.peer_id,
+                                        presence,
+                                    );
+                                }
+                            }
+                            SyncMessage::Peers(_) => {
+                                debug!("ignoring peers sent by client");
+                            }
                         }
                     }
                     Message::Ping(_) => {}
@@ -143,15 +439,19 @@ async fn sync_read(
             }
         }
     }
+    None
 }
 
-#[tracing::instrument(skip(server, sender))]
+#[tracing::instrument(skip(server, sender, session_ended, close_requested))]
 async fn sync_write(
     server: Arc<Mutex<Server>>,
     connection_metadata: ConnectionMetadata,
     user: UserSessionData,
     mut sender: SplitSink<WebSocket, Message>,
+    mut session_ended: watch::Receiver<()>,
+    mut close_requested: oneshot::Receiver<CloseFrame<'static>>,
 ) {
+    let metrics = server.lock().await.metrics.clone();
     debug!("trying to generate initial sync message");
     {
         let mut server = server.lock().await;
@@ -165,6 +465,7 @@ async fn sync_write(
 
                     match Vec::try_from(msg) {
                         Ok(bytes) => {
+                            metrics.sync_sent(bytes.len());
                             sender.send(Message::Binary(bytes)).await.unwrap();
                             debug!("sent initial sync message");
                         }
@@ -181,12 +482,67 @@ async fn sync_write(
         }
     }
 
-    let mut changed = {
-        let server = server.lock().await;
-        server.changed.subscribe()
+    let (mut changed, mut presence_changed, shutdown) = {
+        let mut server = server.lock().await;
+        (
+            server.changed.subscribe(),
+            server.presence.subscribe(user.doc_id()),
+            server.shutdown.clone(),
+        )
     };
+    if let Err(err) = send_peers(&server, &connection_metadata, &user, &mut sender).await {
+        warn!("failed to send peers {}", err);
+        return;
+    }
     debug!("waiting for changes");
-    while let Ok(()) = changed.recv().await {
+    loop {
+        tokio::select! {
+            res = changed.recv() => match res {
+                // lagging only means we missed some notifications, the sync message covers them
+                Ok(()) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            },
+            res = presence_changed.recv() => {
+                match res {
+                    Ok(()) | Err(RecvError::Lagged(_)) => {
+                        if let Err(err) =
+                            send_peers(&server, &connection_metadata, &user, &mut sender).await
+                        {
+                            warn!("failed to send peers {}", err);
+                            break;
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+                continue;
+            }
+            _ = session_ended.changed() => {
+                debug!("session ended, closing connection");
+                let _ = sender
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CLOSE_SESSION_ENDED,
+                        reason: "Session ended".into(),
+                    })))
+                    .await;
+                break;
+            }
+            () = shutdown.stopping() => {
+                debug!("server stopping, closing connection");
+                let _ = sender
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CLOSE_SERVER_RESTARTING,
+                        reason: "Server restarting".into(),
+                    })))
+                    .await;
+                break;
+            }
+            frame = &mut close_requested => {
+                if let Ok(frame) = frame {
+                    let _ = sender.send(Message::Close(Some(frame))).await;
+                }
+                break;
+            }
+        }
         debug!("notified of change");
         let mut server = server.lock().await;
         match server.load_document(user.doc_id()) {
@@ -198,21 +554,37 @@ async fn sync_write(
                     let msg = SyncMessage::Message(msg.encode());
 
                     match Vec::try_from(msg) {
-                        Ok(bytes) => match sender.send(Message::Binary(bytes)).await {
-                            Ok(()) => debug!("sent sync message"),
-                            Err(err) => {
-                                warn!("failed to send sync message {}", err);
-                                break;
+                        Ok(bytes) => {
+                            metrics.sync_sent(bytes.len());
+                            match sender.send(Message::Binary(bytes)).await {
+                                Ok(()) => debug!("sent sync message"),
+                                Err(err) => {
+                                    warn!("failed to send sync message {}", err);
+                                    break;
+                                }
                             }
-                        },
+                        }
                         Err(err) => {
                             warn!("failed to convert sync message to bytes {}", err);
                         }
                     }
+                    let timer = metrics.flush_seconds.start_timer();
                     document.flush().unwrap();
+                    timer.observe_duration();
                     debug!("flushed");
                 }
             }
+            Err(LoadError::Encrypted) => {
+                // another device encrypted the document, so there is nothing left to sync here
+                debug!("document encrypted, closing connection");
+                let _ = sender
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CLOSE_DOCUMENT_ENCRYPTED,
+                        reason: "Document is end-to-end encrypted".into(),
+                    })))
+                    .await;
+                return;
+            }
             Err(err) => {
                 warn!(id=user.doc_id(), %err, "Failed to load document");
                 return;
@@ -220,3 +592,226 @@ async fn sync_write(
         }
     }
 }
+
+/// Tell the client which other devices are connected to its document.
+async fn send_peers(
+    server: &Arc<Mutex<Server>>,
+    connection_metadata: &ConnectionMetadata,
+    user: &UserSessionData,
+    sender: &mut SplitSink<WebSocket, Message>,
+) -> Result<(), axum::Error> {
+    let (peers, metrics) = {
+        let server = server.lock().await;
+        (
+            server
+                .presence
+                .peers(user.doc_id(), connection_metadata.peer_id),
+            server.metrics.clone(),
+        )
+    };
+    match Vec::try_from(SyncMessage::Peers(peers)) {
+        Ok(bytes) => {
+            metrics.sync_sent(bytes.len());
+            sender.send(Message::Binary(bytes)).await
+        }
+        Err(err) => {
+            warn!("failed to convert peers to bytes {}", err);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use autosurgeon::{hydrate, reconcile};
+    use futures::future::join_all;
+    use tasknet_shared::{
+        presence::Presence,
+        task::{Task, TaskId},
+    };
+
+    use super::{close_code, SyncMessage, CLOSE_SERVER_RESTARTING, CLOSE_TIMEOUT};
+    use crate::harness::{SyncClient, TestServer};
+
+    fn tasks(client: &SyncClient) -> HashMap<TaskId, Task> {
+        hydrate(&client.doc).unwrap()
+    }
+
+    fn add_task(client: &mut SyncClient, description: &str) -> TaskId {
+        let mut tasks = tasks(client);
+        let mut task = Task::new();
+        task.set_description(description.to_owned());
+        let id = task.id().clone();
+        tasks.insert(id.clone(), task);
+        reconcile(&mut client.doc, &tasks).unwrap();
+        id
+    }
+
+    fn edit_task(client: &mut SyncClient, id: &TaskId, description: &str) {
+        let mut tasks = tasks(client);
+        tasks
+            .get_mut(id)
+            .unwrap()
+            .set_description(description.to_owned());
+        reconcile(&mut client.doc, &tasks).unwrap();
+    }
+
+    async fn sync_all(clients: &mut [SyncClient]) {
+        // twice so changes received in the first round reach everyone
+        for _ in 0..2 {
+            join_all(clients.iter_mut().map(SyncClient::sync)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_edits_converge() {
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(SyncClient::connect(&server, &cookies).await);
+        }
+        let shared = add_task(&mut clients[0], "shared");
+        sync_all(&mut clients).await;
+
+        // everyone edits at once, including the same task
+        for (i, client) in clients.iter_mut().enumerate() {
+            add_task(client, &format!("from client {i}"));
+            edit_task(client, &shared, &format!("edited by client {i}"));
+        }
+        sync_all(&mut clients).await;
+
+        // one client drops off, keeps editing and comes back while another edits
+        let mut offline = clients.remove(0).disconnect();
+        let reconnecting = SyncClient::connect_with(&server, &cookies, offline.clone()).await;
+        drop(reconnecting.disconnect());
+        let mut tasks_offline: HashMap<TaskId, Task> = hydrate(&offline).unwrap();
+        let mut task = Task::new();
+        task.set_description("made offline".to_owned());
+        tasks_offline.insert(task.id().clone(), task);
+        reconcile(&mut offline, &tasks_offline).unwrap();
+        add_task(&mut clients[0], "made while the other was offline");
+        sync_all(&mut clients).await;
+        clients.push(SyncClient::connect_with(&server, &cookies, offline).await);
+        sync_all(&mut clients).await;
+
+        let expected = tasks(&clients[0]);
+        assert_eq!(expected.len(), 6);
+        assert!(expected[&shared]
+            .description()
+            .starts_with("edited by client"));
+        let heads = clients[0].heads();
+        for client in &mut clients {
+            assert_eq!(tasks(client), expected);
+            assert_eq!(client.heads(), heads);
+        }
+
+        let mut loaded_heads = server.server.lock().await.documents[&doc_id]
+            .document()
+            .get_heads();
+        loaded_heads.sort();
+        assert_eq!(loaded_heads, heads);
+
+        let stored = server.stored_document(&doc_id);
+        let mut stored_heads = stored.get_heads();
+        stored_heads.sort();
+        assert_eq!(stored_heads, heads);
+        assert_eq!(
+            hydrate::<_, HashMap<TaskId, Task>>(&stored).unwrap(),
+            expected
+        );
+    }
+
+    #[tokio::test]
+    async fn test_presence_reaches_other_peers() {
+        let server = TestServer::start().await;
+        let cookies = server
+            .sign_in_public(&uuid::Uuid::new_v4().to_string())
+            .await;
+        let other_cookies = server
+            .sign_in_public(&uuid::Uuid::new_v4().to_string())
+            .await;
+        let mut phone = SyncClient::connect(&server, &cookies).await;
+        let mut laptop = SyncClient::connect(&server, &cookies).await;
+        let mut elsewhere = SyncClient::connect(&server, &other_cookies).await;
+
+        let id = add_task(&mut phone, "shared");
+        let presence = Presence {
+            device: "Phone".to_owned(),
+            viewing: Some(id.clone()),
+            editing: true,
+            last_active: None,
+        };
+        phone.send_presence(presence).await;
+        join_all([&mut phone, &mut laptop, &mut elsewhere].map(SyncClient::sync)).await;
+
+        assert!(phone.peers.is_empty());
+        assert!(elsewhere.peers.is_empty());
+        assert_eq!(laptop.peers.len(), 1);
+        let seen = &laptop.peers[0].presence;
+        assert_eq!(seen.device, "Phone");
+        assert_eq!(seen.viewing, Some(id));
+        assert!(seen.editing);
+        assert!(seen.last_active.is_some());
+
+        drop(phone.disconnect());
+        laptop.sync().await;
+        assert!(laptop.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_closes_connections() {
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        add_task(&mut client, "made before shutting down");
+        client.sync().await;
+
+        let finished = tokio::spawn(crate::shutdown::finish(server.server.clone()));
+        let frame = client.closed().await.unwrap();
+        assert_eq!(u16::from(frame.code), CLOSE_SERVER_RESTARTING);
+        let doc = client.disconnect();
+        tokio::time::timeout(CLOSE_TIMEOUT, finished)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(SyncClient::try_connect_with(&server, &cookies, doc.clone())
+            .await
+            .is_err());
+        let stored: HashMap<TaskId, Task> = hydrate(&server.stored_document(&doc_id)).unwrap();
+        assert_eq!(stored, hydrate::<_, HashMap<TaskId, Task>>(&doc).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_messages_close_the_connection() {
+        let server = TestServer::start().await;
+        let doc_id = uuid::Uuid::new_v4().to_string();
+        let cookies = server.sign_in_public(&doc_id).await;
+
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        client.send_raw(b"not a sync message".to_vec()).await;
+        let frame = client.closed().await.unwrap();
+        assert_eq!(u16::from(frame.code), close_code::INVALID);
+
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        let bytes = Vec::try_from(SyncMessage::Message(vec![0x42, 1, 2, 3])).unwrap();
+        client.send_raw(bytes).await;
+        let frame = client.closed().await.unwrap();
+        assert_eq!(u16::from(frame.code), close_code::INVALID);
+
+        // the server carries on for everyone else
+        let mut client = SyncClient::connect(&server, &cookies).await;
+        add_task(&mut client, "still syncing");
+        client.sync().await;
+        assert_eq!(
+            hydrate::<_, HashMap<TaskId, Task>>(&server.stored_document(&doc_id)).unwrap(),
+            tasks(&client)
+        );
+    }
+}
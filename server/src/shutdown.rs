@@ -0,0 +1,113 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{watch, Mutex};
+use tracing::{info, warn};
+
+use crate::server::Server;
+
+/// Lets sync connections know the server is stopping and waits for them to close.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    stopping: Arc<watch::Sender<bool>>,
+    connections: Arc<watch::Sender<usize>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            stopping: Arc::new(watch::channel(false).0),
+            connections: Arc::new(watch::channel(0).0),
+        }
+    }
+}
+
+impl Shutdown {
+    /// Stop accepting syncs and ask the open ones to close.
+    pub fn begin(&self) {
+        self.stopping.send_replace(true);
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.borrow()
+    }
+
+    /// Completes once the server starts stopping, even if it already has.
+    pub async fn stopping(&self) {
+        let mut stopping = self.stopping.subscribe();
+        // the sender lives as long as self so this can't fail
+        let _ = stopping.wait_for(|stopping| *stopping).await;
+    }
+
+    /// Count a sync connection as open until the guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard {
+        self.connections.send_modify(|count| *count += 1);
+        ConnectionGuard {
+            connections: self.connections.clone(),
+        }
+    }
+
+    /// Wait for every sync connection to close, returning false if some were still open after
+    /// the timeout.
+    pub async fn wait(&self, timeout: Duration) -> bool {
+        let mut connections = self.connections.subscribe();
+        let closed = tokio::time::timeout(timeout, connections.wait_for(|count| *count == 0)).await;
+        closed.is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    connections: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Close the sync connections and flush the documents once the server has stopped serving.
+pub async fn finish(server: Arc<Mutex<Server>>) {
+    let (shutdown, timeout) = {
+        let server = server.lock().await;
+        (
+            server.shutdown.clone(),
+            Duration::from_secs(server.config.shutdown_timeout_secs),
+        )
+    };
+    // also covers the listener stopping for another reason
+    shutdown.begin();
+    if !shutdown.wait(timeout).await {
+        warn!("Sync connections still open after the shutdown timeout");
+    }
+
+    let mut server = server.lock().await;
+    for (id, document) in &mut server.documents {
+        if let Err(err) = document.flush() {
+            warn!(id, %err, "Failed to flush document");
+        }
+    }
+    info!("Shut down");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_wait_for_connections() {
+        let shutdown = Shutdown::default();
+        assert!(shutdown.wait(Duration::ZERO).await);
+
+        let guard = shutdown.connection();
+        shutdown.begin();
+        shutdown.stopping().await;
+        assert!(!shutdown.wait(Duration::from_millis(10)).await);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+        assert!(shutdown.wait(Duration::from_secs(5)).await);
+    }
+}
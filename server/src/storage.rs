@@ -0,0 +1,248 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use automerge::ActorId;
+use automerge_persistent::{Persister, StoredSizes};
+use automerge_persistent_fs::{FsPersister, FsPersisterError};
+use serde::{Deserialize, Serialize};
+
+use crate::metrics::disk_size;
+
+pub mod sqlite;
+
+/// The SQLite database used when no file is configured, inside `documents_dir`.
+const DEFAULT_SQLITE_FILE: &str = "documents.sqlite";
+
+/// Where plaintext documents are stored.
+///
+/// Encrypted documents are always kept as logs in `documents_dir`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum StorageConfig {
+    /// A directory of files for each document in `documents_dir`.
+    #[default]
+    Fs,
+    /// A single SQLite database, flushing each change to a document in one transaction.
+    Sqlite {
+        /// Defaults to `documents.sqlite` in `documents_dir`.
+        #[serde(default)]
+        file: Option<PathBuf>,
+    },
+}
+
+impl StorageConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Self::Sqlite { file: Some(file) } = self {
+            let parent = file
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if parent.is_some_and(|parent| !parent.is_dir()) {
+                problems.push(format!(
+                    "storage.file {} is not in an existing directory",
+                    file.display()
+                ));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    Fs(FsPersisterError),
+    Sqlite(rusqlite::Error),
+    Io(std::io::Error),
+}
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Fs(err) => err.fmt(f),
+            Self::Sqlite(err) => err.fmt(f),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<rusqlite::Error> for StorageError {
+    fn from(err: rusqlite::Error) -> Self {
+        Self::Sqlite(err)
+    }
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// The storage backend holding every plaintext document.
+#[derive(Debug, Clone)]
+pub enum Storage {
+    Fs { dir: PathBuf },
+    Sqlite(sqlite::SqliteStorage),
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig, documents_dir: &Path) -> Result<Self, StorageError> {
+        match config {
+            StorageConfig::Fs => Ok(Self::Fs {
+                dir: documents_dir.to_owned(),
+            }),
+            StorageConfig::Sqlite { file } => {
+                let file = file
+                    .clone()
+                    .unwrap_or_else(|| documents_dir.join(DEFAULT_SQLITE_FILE));
+                Ok(Self::Sqlite(sqlite::SqliteStorage::open(&file)?))
+            }
+        }
+    }
+
+    /// The persister for a document, which is created when first flushed if it doesn't exist.
+    pub fn persister(&self, id: &str) -> Result<DocumentPersister, StorageError> {
+        match self {
+            Self::Fs { dir } => FsPersister::new(dir, id)
+                .map(DocumentPersister::Fs)
+                .map_err(StorageError::Fs),
+            Self::Sqlite(storage) => Ok(DocumentPersister::Sqlite(storage.persister(id)?)),
+        }
+    }
+
+    /// The ids of every stored document, sorted.
+    pub fn document_ids(&self) -> Result<Vec<String>, StorageError> {
+        match self {
+            Self::Fs { dir } => {
+                let mut ids = std::fs::read_dir(dir)?
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().is_dir())
+                    .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                ids.sort();
+                Ok(ids)
+            }
+            Self::Sqlite(storage) => Ok(storage.document_ids()?),
+        }
+    }
+
+    pub fn exists(&self, id: &str) -> Result<bool, StorageError> {
+        match self {
+            Self::Fs { dir } => Ok(dir.join(id).is_dir()),
+            Self::Sqlite(storage) => Ok(storage.exists(id)?),
+        }
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), StorageError> {
+        match self {
+            Self::Fs { dir } => {
+                let path = dir.join(id);
+                if path.is_dir() {
+                    std::fs::remove_dir_all(path)?;
+                }
+                Ok(())
+            }
+            Self::Sqlite(storage) => Ok(storage.delete(id)?),
+        }
+    }
+
+    /// Bytes stored for the document.
+    pub fn size(&self, id: &str) -> Result<u64, StorageError> {
+        match self {
+            Self::Fs { dir } => Ok(disk_size(&dir.join(id))),
+            Self::Sqlite(storage) => Ok(storage.size(id)?),
+        }
+    }
+
+    /// When the document was last changed.
+    pub fn last_modified(&self, id: &str) -> Result<Option<SystemTime>, StorageError> {
+        match self {
+            Self::Fs { dir } => Ok(last_modified(&dir.join(id))),
+            Self::Sqlite(storage) => Ok(storage.last_modified(id)?),
+        }
+    }
+}
+
+/// When anything under the path was last modified.
+pub(crate) fn last_modified(path: &Path) -> Option<SystemTime> {
+    let metadata = std::fs::symlink_metadata(path).ok()?;
+    if metadata.is_dir() {
+        std::fs::read_dir(path)
+            .ok()?
+            .filter_map(Result::ok)
+            .filter_map(|entry| last_modified(&entry.path()))
+            .chain(metadata.modified().ok())
+            .max()
+    } else {
+        metadata.modified().ok()
+    }
+}
+
+/// Persists a single document to whichever backend is configured.
+#[derive(Debug)]
+pub enum DocumentPersister {
+    Fs(FsPersister),
+    Sqlite(sqlite::SqlitePersister),
+}
+
+macro_rules! delegate {
+    ($self:ident, $persister:ident => $call:expr) => {
+        match $self {
+            Self::Fs($persister) => $call.map_err(StorageError::Fs),
+            Self::Sqlite($persister) => $call.map_err(StorageError::Sqlite),
+        }
+    };
+}
+
+impl Persister for DocumentPersister {
+    type Error = StorageError;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        delegate!(self, p => p.get_changes())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        delegate!(self, p => p.insert_changes(changes))
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        delegate!(self, p => p.remove_changes(changes))
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        delegate!(self, p => p.get_document())
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        delegate!(self, p => p.set_document(data))
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        delegate!(self, p => p.get_sync_state(peer_id))
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        delegate!(self, p => p.set_sync_state(peer_id, sync_state))
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        delegate!(self, p => p.remove_sync_states(peer_ids))
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        delegate!(self, p => p.get_peer_ids())
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        match self {
+            Self::Fs(p) => p.sizes(),
+            Self::Sqlite(p) => p.sizes(),
+        }
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        delegate!(self, p => p.flush())
+    }
+}
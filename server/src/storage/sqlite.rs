@@ -0,0 +1,366 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use automerge::ActorId;
+use automerge_persistent::{Persister, StoredSizes};
+use rusqlite::{params, Connection, OptionalExtension};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS documents (
+        id TEXT PRIMARY KEY,
+        data BLOB,
+        updated INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS changes (
+        document TEXT NOT NULL,
+        actor BLOB NOT NULL,
+        seq INTEGER NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (document, actor, seq)
+    );
+    CREATE TABLE IF NOT EXISTS sync_states (
+        document TEXT NOT NULL,
+        peer BLOB NOT NULL,
+        data BLOB NOT NULL,
+        PRIMARY KEY (document, peer)
+    );
+";
+
+/// Every document in a single SQLite database.
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(file: &Path) -> rusqlite::Result<Self> {
+        let connection = Connection::open(file)?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    pub fn persister(&self, id: &str) -> rusqlite::Result<SqlitePersister> {
+        let sizes = {
+            let connection = self.connection.lock().unwrap();
+            let sum = |table: &str, column: &str| -> rusqlite::Result<u64> {
+                connection.query_row(
+                    &format!(
+                        "SELECT COALESCE(SUM(LENGTH(data)), 0) FROM {table} WHERE {column} = ?1"
+                    ),
+                    [id],
+                    |row| row.get(0),
+                )
+            };
+            StoredSizes {
+                changes: sum("changes", "document")?,
+                document: sum("documents", "id")?,
+                sync_states: sum("sync_states", "document")?,
+            }
+        };
+        Ok(SqlitePersister {
+            connection: self.connection.clone(),
+            id: id.to_owned(),
+            changes: HashMap::new(),
+            removed_changes: HashSet::new(),
+            document: None,
+            sync_states: HashMap::new(),
+            sizes,
+        })
+    }
+
+    pub fn document_ids(&self) -> rusqlite::Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare("SELECT id FROM documents ORDER BY id")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        Ok(ids)
+    }
+
+    pub fn exists(&self, id: &str) -> rusqlite::Result<bool> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row("SELECT 1 FROM documents WHERE id = ?1", [id], |_| Ok(()))
+            .optional()
+            .map(|row| row.is_some())
+    }
+
+    pub fn delete(&self, id: &str) -> rusqlite::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        tx.execute("DELETE FROM documents WHERE id = ?1", [id])?;
+        tx.execute("DELETE FROM changes WHERE document = ?1", [id])?;
+        tx.execute("DELETE FROM sync_states WHERE document = ?1", [id])?;
+        tx.commit()
+    }
+
+    pub fn size(&self, id: &str) -> rusqlite::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            "SELECT
+                (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM documents WHERE id = ?1)
+                + (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM changes WHERE document = ?1)
+                + (SELECT COALESCE(SUM(LENGTH(data)), 0) FROM sync_states WHERE document = ?1)",
+            [id],
+            |row| row.get(0),
+        )
+    }
+
+    pub fn last_modified(&self, id: &str) -> rusqlite::Result<Option<SystemTime>> {
+        let connection = self.connection.lock().unwrap();
+        let updated: Option<u64> = connection
+            .query_row("SELECT updated FROM documents WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        Ok(updated.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)))
+    }
+}
+
+/// Persists a document to the database, buffering everything until it is flushed in a single
+/// transaction.
+#[derive(Debug)]
+pub struct SqlitePersister {
+    connection: Arc<Mutex<Connection>>,
+    id: String,
+    changes: HashMap<(Vec<u8>, u64), Vec<u8>>,
+    removed_changes: HashSet<(Vec<u8>, u64)>,
+    document: Option<Vec<u8>>,
+    /// Sync states to store, or remove when `None`.
+    sync_states: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    sizes: StoredSizes,
+}
+
+impl Persister for SqlitePersister {
+    type Error = rusqlite::Error;
+
+    fn get_changes(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT actor, seq, data FROM changes WHERE document = ?1")?;
+        let stored = statement
+            .query_map([&self.id], |row| {
+                Ok((
+                    (row.get::<_, Vec<u8>>(0)?, row.get::<_, u64>(1)?),
+                    row.get(2)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(stored
+            .into_iter()
+            .filter(|(key, _)| {
+                !self.removed_changes.contains(key) && !self.changes.contains_key(key)
+            })
+            .map(|(_, data)| data)
+            .chain(self.changes.values().cloned())
+            .collect())
+    }
+
+    fn insert_changes(&mut self, changes: Vec<(ActorId, u64, Vec<u8>)>) -> Result<(), Self::Error> {
+        for (actor, seq, data) in changes {
+            let key = (actor.to_bytes().to_vec(), seq);
+            self.removed_changes.remove(&key);
+            self.sizes.changes += data.len() as u64;
+            self.changes.insert(key, data);
+        }
+        Ok(())
+    }
+
+    fn remove_changes(&mut self, changes: Vec<(&ActorId, u64)>) -> Result<(), Self::Error> {
+        for (actor, seq) in changes {
+            let key = (actor.to_bytes().to_vec(), seq);
+            if let Some(data) = self.changes.remove(&key) {
+                self.sizes.changes = self.sizes.changes.saturating_sub(data.len() as u64);
+            }
+            self.removed_changes.insert(key);
+        }
+        Ok(())
+    }
+
+    fn get_document(&self) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(document) = &self.document {
+            return Ok(Some(document.clone()));
+        }
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT data FROM documents WHERE id = ?1",
+                [&self.id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map(Option::flatten)
+    }
+
+    fn set_document(&mut self, data: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.document = data.len() as u64;
+        self.document = Some(data);
+        Ok(())
+    }
+
+    fn get_sync_state(&self, peer_id: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(sync_state) = self.sync_states.get(peer_id) {
+            return Ok(sync_state.clone());
+        }
+        let connection = self.connection.lock().unwrap();
+        connection
+            .query_row(
+                "SELECT data FROM sync_states WHERE document = ?1 AND peer = ?2",
+                params![self.id, peer_id],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    fn set_sync_state(&mut self, peer_id: Vec<u8>, sync_state: Vec<u8>) -> Result<(), Self::Error> {
+        self.sizes.sync_states += sync_state.len() as u64;
+        self.sync_states.insert(peer_id, Some(sync_state));
+        Ok(())
+    }
+
+    fn remove_sync_states(&mut self, peer_ids: &[&[u8]]) -> Result<(), Self::Error> {
+        for peer_id in peer_ids {
+            self.sync_states.insert(peer_id.to_vec(), None);
+        }
+        Ok(())
+    }
+
+    fn get_peer_ids(&self) -> Result<Vec<Vec<u8>>, Self::Error> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT peer FROM sync_states WHERE document = ?1")?;
+        let stored = statement
+            .query_map([&self.id], |row| row.get::<_, Vec<u8>>(0))?
+            .collect::<rusqlite::Result<HashSet<_>>>()?;
+        let mut peer_ids = stored
+            .into_iter()
+            .filter(|peer_id| !self.sync_states.contains_key(peer_id))
+            .collect::<Vec<_>>();
+        peer_ids.extend(
+            self.sync_states
+                .iter()
+                .filter(|(_, sync_state)| sync_state.is_some())
+                .map(|(peer_id, _)| peer_id.clone()),
+        );
+        Ok(peer_ids)
+    }
+
+    fn sizes(&self) -> StoredSizes {
+        self.sizes.clone()
+    }
+
+    fn flush(&mut self) -> Result<usize, Self::Error> {
+        if self.changes.is_empty()
+            && self.removed_changes.is_empty()
+            && self.document.is_none()
+            && self.sync_states.is_empty()
+        {
+            return Ok(0);
+        }
+
+        let updated = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut flushed = 0;
+        let mut connection = self.connection.lock().unwrap();
+        let tx = connection.transaction()?;
+        // the row for the document marks it as existing, even before it has been compacted
+        tx.execute(
+            "INSERT INTO documents (id, data, updated) VALUES (?1, NULL, ?2)
+            ON CONFLICT (id) DO UPDATE SET updated = excluded.updated",
+            params![self.id, updated],
+        )?;
+        if let Some(document) = &self.document {
+            tx.execute(
+                "UPDATE documents SET data = ?2 WHERE id = ?1",
+                params![self.id, document],
+            )?;
+            flushed += document.len();
+        }
+        for (actor, seq) in &self.removed_changes {
+            tx.execute(
+                "DELETE FROM changes WHERE document = ?1 AND actor = ?2 AND seq = ?3",
+                params![self.id, actor, seq],
+            )?;
+        }
+        for ((actor, seq), data) in &self.changes {
+            tx.execute(
+                "INSERT OR REPLACE INTO changes (document, actor, seq, data) VALUES (?1, ?2, ?3, ?4)",
+                params![self.id, actor, seq, data],
+            )?;
+            flushed += data.len();
+        }
+        for (peer_id, sync_state) in &self.sync_states {
+            if let Some(sync_state) = sync_state {
+                tx.execute(
+                    "INSERT OR REPLACE INTO sync_states (document, peer, data) VALUES (?1, ?2, ?3)",
+                    params![self.id, peer_id, sync_state],
+                )?;
+                flushed += sync_state.len();
+            } else {
+                tx.execute(
+                    "DELETE FROM sync_states WHERE document = ?1 AND peer = ?2",
+                    params![self.id, peer_id],
+                )?;
+            }
+        }
+        tx.commit()?;
+
+        self.changes.clear();
+        self.removed_changes.clear();
+        self.document = None;
+        self.sync_states.clear();
+        Ok(flushed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use automerge::{transaction::Transactable, ReadDoc, ROOT};
+    use automerge_persistent::PersistentAutomerge;
+
+    use super::*;
+
+    #[test]
+    fn test_flush_compact_and_reload() {
+        let file = std::env::temp_dir().join(format!("tasknet-{}.sqlite", uuid::Uuid::new_v4()));
+        let storage = SqliteStorage::open(&file).unwrap();
+
+        let mut document = PersistentAutomerge::load(storage.persister("doc").unwrap()).unwrap();
+        document.transact(|tx| tx.put(ROOT, "a", 1)).unwrap();
+        document
+            .persister_mut()
+            .set_sync_state(b"peer".to_vec(), b"state".to_vec())
+            .unwrap();
+        assert!(!storage.exists("doc").unwrap());
+        document.flush().unwrap();
+        assert_eq!(storage.document_ids().unwrap(), vec!["doc".to_owned()]);
+
+        document.transact(|tx| tx.put(ROOT, "b", 2)).unwrap();
+        document.compact(&[b"peer"]).unwrap();
+        document.flush().unwrap();
+        drop(document);
+
+        let storage = SqliteStorage::open(&file).unwrap();
+        let document = PersistentAutomerge::load(storage.persister("doc").unwrap()).unwrap();
+        assert!(document.document().get(ROOT, "a").unwrap().is_some());
+        assert!(document.document().get(ROOT, "b").unwrap().is_some());
+        // compacting moves the changes into the document and drops the old sync states
+        let persister = storage.persister("doc").unwrap();
+        assert!(persister.get_peer_ids().unwrap().is_empty());
+        assert_eq!(persister.sizes().changes, 0);
+
+        storage.delete("doc").unwrap();
+        assert!(storage.document_ids().unwrap().is_empty());
+        drop(storage);
+        std::fs::remove_file(file).unwrap();
+    }
+}
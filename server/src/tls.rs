@@ -0,0 +1,119 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::{
+    extract::Host,
+    http::Uri,
+    response::{IntoResponse, Redirect},
+    Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+/// How often to check whether the certificate files have changed.
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub cert_file: PathBuf,
+    /// PEM encoded private key.
+    pub key_file: PathBuf,
+    /// Also listen for plain http on this port, redirecting everything to https.
+    #[serde(default)]
+    pub redirect_http_port: Option<u16>,
+}
+
+impl TlsConfig {
+    pub fn validate(&self, port: u16, problems: &mut Vec<String>) {
+        for (name, file) in [("cert_file", &self.cert_file), ("key_file", &self.key_file)] {
+            if !file.is_file() {
+                problems.push(format!("tls.{name} {} does not exist", file.display()));
+            }
+        }
+        if self.redirect_http_port == Some(port) {
+            problems.push("tls.redirect_http_port must differ from port".to_owned());
+        }
+    }
+
+    pub async fn load(&self) -> std::io::Result<RustlsConfig> {
+        RustlsConfig::from_pem_file(&self.cert_file, &self.key_file).await
+    }
+
+    async fn reload(&self, rustls_config: &RustlsConfig) {
+        match rustls_config
+            .reload_from_pem_file(&self.cert_file, &self.key_file)
+            .await
+        {
+            Ok(()) => info!("Reloaded TLS certificate"),
+            // keep serving the old certificate rather than nothing
+            Err(err) => warn!(%err, "Failed to reload TLS certificate"),
+        }
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |file: &Path| std::fs::metadata(file).and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert_file)?, modified(&self.key_file)?))
+    }
+
+    /// Reload the certificate when its files change or on SIGHUP, such as after renewal.
+    pub async fn watch(self, rustls_config: RustlsConfig) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to install hangup signal handler");
+
+        loop {
+            #[cfg(unix)]
+            let hangup = hangup.recv();
+            #[cfg(not(unix))]
+            let hangup = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = interval.tick() => {
+                    let modified = self.modified();
+                    if modified == last_modified {
+                        continue;
+                    }
+                    debug!("TLS certificate files changed");
+                    last_modified = modified;
+                }
+                _ = hangup => {
+                    debug!("Hangup signal received");
+                    last_modified = self.modified();
+                }
+            }
+            self.reload(&rustls_config).await;
+        }
+    }
+}
+
+/// Serve plain http on the port, redirecting every request to the same location on https.
+pub async fn serve_redirect(address: SocketAddr, https_port: u16) {
+    let redirect = move |Host(host): Host, uri: Uri| async move {
+        // drop any port from the host, browsers connect to 443 for https by default
+        let host = host
+            .rsplit_once(':')
+            .filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit()))
+            .map_or(host.as_str(), |(host, _)| host);
+        let path = uri.path_and_query().map_or("/", |p| p.as_str());
+        let location = if https_port == 443 {
+            format!("https://{host}{path}")
+        } else {
+            format!("https://{host}:{https_port}{path}")
+        };
+        Redirect::permanent(&location).into_response()
+    };
+
+    info!("Redirecting http://{} to https", address);
+    axum::Server::bind(&address)
+        .serve(Router::new().fallback(redirect).into_make_service())
+        .await
+        .unwrap();
+}
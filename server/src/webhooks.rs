@@ -0,0 +1,892 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use automerge::Automerge;
+use autosurgeon::hydrate;
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use rand::RngCore;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    Url,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tasknet_shared::task::{Status, Task, TaskId};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{
+    auth::UserSessionData,
+    hex,
+    json_file::{self, JsonFileError},
+    server::Server,
+};
+
+/// The file subscriptions are kept in, inside `documents_dir`.
+const WEBHOOKS_FILE: &str = "webhooks.json";
+
+/// Header with the hex HMAC-SHA256 of the body, keyed with the subscription's secret.
+const SIGNATURE_HEADER: &str = "X-Tasknet-Signature";
+const EVENT_HEADER: &str = "X-Tasknet-Event";
+const DELIVERY_HEADER: &str = "X-Tasknet-Delivery";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhooksConfig {
+    /// Attempts at delivering each event before giving up.
+    pub max_attempts: u32,
+    /// The wait before the first retry, doubling after each failed attempt.
+    pub initial_backoff_ms: u64,
+    pub timeout_secs: u64,
+    /// Deliveries remembered for each subscription.
+    pub log_size: usize,
+    /// Hosts, as written in URLs, that may be sent to even though they are on a private network.
+    ///
+    /// Anything else has to resolve to public addresses so subscriptions can't reach services
+    /// only the server can.
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 1000,
+            timeout_secs: 10,
+            log_size: 50,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhooksConfig {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.max_attempts == 0 {
+            problems.push("webhooks.max_attempts must be more than 0".to_owned());
+        }
+        if self.timeout_secs == 0 {
+            problems.push("webhooks.timeout_secs must be more than 0".to_owned());
+        }
+    }
+}
+
+/// Whether the address is reachable on the public internet.
+///
+/// `IpAddr::is_global` isn't stable yet, so this covers the special purpose ranges in its place.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                // shared address space for carrier-grade NAT
+                || (a == 100 && (64..128).contains(&b))
+                // protocol assignments
+                || (a == 192 && b == 0 && c == 0)
+                // benchmarking
+                || (a == 198 && (b == 18 || b == 19)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // unique local
+                || (first & 0xfe00) == 0xfc00
+                // link local
+                || (first & 0xffc0) == 0xfe80
+                // documentation
+                || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+        }
+    }
+}
+
+/// Resolves the hosts of webhook URLs, leaving out private addresses unless the host is allowed.
+///
+/// Checking when the request is made, rather than only when subscribing, means a host can't
+/// change what it resolves to afterwards.
+struct PublicResolver {
+    allowed_hosts: Vec<String>,
+}
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        let allowed = self
+            .allowed_hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&host));
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| allowed || is_public(addr.ip()))
+                .collect::<Vec<_>>();
+            if addrs.is_empty() {
+                return Err(format!("{host} has no public addresses").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The address in the URL, if it has one instead of a host name.
+fn ip_address(url: &Url) -> Option<IpAddr> {
+    url.host_str()?
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Check a URL can be sent to, giving why not otherwise.
+async fn check_url(config: &WebhooksConfig, url: &str) -> Result<(), String> {
+    let url = Url::parse(url)
+        .ok()
+        .filter(|url| matches!(url.scheme(), "http" | "https"))
+        .ok_or_else(|| "url must be an http or https URL".to_owned())?;
+    let host = url.host_str().unwrap_or_default();
+    if config
+        .allowed_hosts
+        .iter()
+        .any(|allowed| allowed.eq_ignore_ascii_case(host))
+    {
+        return Ok(());
+    }
+    let addrs = match ip_address(&url) {
+        Some(ip) => vec![ip],
+        None => {
+            let port = url.port_or_known_default().unwrap_or_default();
+            tokio::net::lookup_host((host, port))
+                .await
+                .map_err(|err| format!("Failed to resolve {host}: {err}"))?
+                .map(|addr| addr.ip())
+                .collect()
+        }
+    };
+    if addrs.is_empty() || !addrs.into_iter().all(is_public) {
+        return Err(format!("{host} is not a public address"));
+    }
+    Ok(())
+}
+
+/// The signature header of a payload, the hex HMAC-SHA256 of the body keyed with the secret.
+fn signature(secret: &[u8], body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(&mac.finalize().into_bytes()))
+}
+
+/// What happened to a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Created,
+    Completed,
+    Deleted,
+    Modified,
+}
+
+impl EventKind {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Completed => "completed",
+            Self::Deleted => "deleted",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+/// A change to a task from a merged sync message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    pub kind: EventKind,
+    pub task_id: TaskId,
+    /// Missing for created tasks.
+    pub before: Option<Task>,
+    /// Missing for tasks removed from the document.
+    pub after: Option<Task>,
+}
+
+/// The tasks in the document, to compare before and after merging.
+pub fn tasks(doc: &Automerge) -> Option<HashMap<TaskId, Task>> {
+    hydrate(doc)
+        .map_err(|err| warn!(%err, "Failed to read tasks for webhooks"))
+        .ok()
+}
+
+/// The events for the differences between two sets of tasks, in task id order.
+pub fn diff(before: &HashMap<TaskId, Task>, after: &HashMap<TaskId, Task>) -> Vec<Event> {
+    let mut events = Vec::new();
+    for (id, task) in after {
+        let kind = match before.get(id) {
+            None => EventKind::Created,
+            Some(old) if old == task => continue,
+            Some(old) => match task.status() {
+                Status::Completed if old.status() != &Status::Completed => EventKind::Completed,
+                Status::Deleted if old.status() != &Status::Deleted => EventKind::Deleted,
+                _ => EventKind::Modified,
+            },
+        };
+        events.push(Event {
+            kind,
+            task_id: id.clone(),
+            before: before.get(id).cloned(),
+            after: Some(task.clone()),
+        });
+    }
+    for (id, task) in before {
+        if !after.contains_key(id) {
+            events.push(Event {
+                kind: EventKind::Deleted,
+                task_id: id.clone(),
+                before: Some(task.clone()),
+                after: None,
+            });
+        }
+    }
+    events.sort_by(|a, b| a.task_id.as_ref().cmp(b.task_id.as_ref()));
+    events
+}
+
+/// Where to send the events of a document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub document: String,
+    pub url: String,
+    /// Signs the payloads, only shown when the subscription is created.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub secret: String,
+    /// The kinds of event to send, or all of them when empty.
+    pub events: Vec<EventKind>,
+    pub created: u64,
+}
+
+impl Subscription {
+    fn wants(&self, kind: EventKind) -> bool {
+        self.events.is_empty() || self.events.contains(&kind)
+    }
+
+    fn without_secret(&self) -> Self {
+        Self {
+            secret: String::new(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The outcome of sending an event to a subscription.
+#[derive(Debug, Clone, Serialize)]
+pub struct Delivery {
+    pub id: String,
+    pub event: EventKind,
+    pub task_id: TaskId,
+    pub timestamp: u64,
+    pub attempts: u32,
+    /// The status of the last response, if there was one.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    delivery: &'a str,
+    event: EventKind,
+    document: &'a str,
+    timestamp: u64,
+    task_id: &'a TaskId,
+    before: &'a Option<Task>,
+    after: &'a Option<Task>,
+}
+
+type DeliveryLog = Arc<std::sync::Mutex<HashMap<String, VecDeque<Delivery>>>>;
+
+/// The webhook subscriptions of every document, and the deliveries made to them.
+///
+/// The delivery log is only kept in memory.
+#[derive(Debug)]
+pub struct Webhooks {
+    config: WebhooksConfig,
+    file: PathBuf,
+    subscriptions: Vec<Subscription>,
+    log: DeliveryLog,
+    client: reqwest::Client,
+}
+
+impl Webhooks {
+    pub fn load(config: &WebhooksConfig, documents_dir: &Path) -> Result<Self, JsonFileError> {
+        let file = documents_dir.join(WEBHOOKS_FILE);
+        let subscriptions = json_file::load(&file)?.unwrap_or_else(|| {
+            debug!(?file, "No webhooks file, starting empty");
+            Vec::new()
+        });
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .dns_resolver(Arc::new(PublicResolver {
+                allowed_hosts: config.allowed_hosts.clone(),
+            }))
+            // a redirect could lead anywhere, including addresses the checks refuse
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to create webhooks client");
+        Ok(Self {
+            config: config.clone(),
+            file,
+            subscriptions,
+            log: DeliveryLog::default(),
+            client,
+        })
+    }
+
+    fn save(&self) -> std::io::Result<()> {
+        json_file::save(&self.file, &self.subscriptions)
+    }
+
+    pub fn has_subscriptions(&self, document: &str) -> bool {
+        self.subscriptions
+            .iter()
+            .any(|subscription| subscription.document == document)
+    }
+
+    pub fn subscriptions(&self, document: &str) -> Vec<Subscription> {
+        self.subscriptions
+            .iter()
+            .filter(|subscription| subscription.document == document)
+            .map(Subscription::without_secret)
+            .collect()
+    }
+
+    /// Subscribe to the events of a document, returning the subscription with its secret.
+    pub fn subscribe(
+        &mut self,
+        document: &str,
+        url: &str,
+        events: Vec<EventKind>,
+    ) -> std::io::Result<Subscription> {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        let subscription = Subscription {
+            id: uuid::Uuid::new_v4().to_string(),
+            document: document.to_owned(),
+            url: url.to_owned(),
+            secret: hex::encode(&secret),
+            events,
+            created: unix_secs(SystemTime::now()),
+        };
+        self.subscriptions.push(subscription.clone());
+        if let Err(err) = self.save() {
+            self.subscriptions.pop();
+            return Err(err);
+        }
+        Ok(subscription)
+    }
+
+    /// Remove a subscription, returning whether the document had it.
+    pub fn unsubscribe(&mut self, document: &str, id: &str) -> std::io::Result<bool> {
+        let Some(index) = self
+            .subscriptions
+            .iter()
+            .position(|subscription| subscription.document == document && subscription.id == id)
+        else {
+            return Ok(false);
+        };
+        let removed = self.subscriptions.remove(index);
+        if let Err(err) = self.save() {
+            self.subscriptions.insert(index, removed);
+            return Err(err);
+        }
+        self.log.lock().unwrap().remove(id);
+        Ok(true)
+    }
+
+    /// The latest deliveries to a subscription of the document, newest first.
+    pub fn deliveries(&self, document: &str, id: &str) -> Option<Vec<Delivery>> {
+        self.subscriptions
+            .iter()
+            .find(|subscription| subscription.document == document && subscription.id == id)?;
+        let log = self.log.lock().unwrap();
+        Some(
+            log.get(id)
+                .map(|deliveries| deliveries.iter().rev().cloned().collect())
+                .unwrap_or_default(),
+        )
+    }
+
+    /// Send the events to the document's subscriptions in the background.
+    pub fn notify(&self, document: &str, events: &[Event]) {
+        if events.is_empty() {
+            return;
+        }
+        for subscription in &self.subscriptions {
+            if subscription.document != document {
+                continue;
+            }
+            let events = events
+                .iter()
+                .filter(|event| subscription.wants(event.kind))
+                .cloned()
+                .collect::<Vec<_>>();
+            if events.is_empty() {
+                continue;
+            }
+            let sender = Sender {
+                config: self.config.clone(),
+                client: self.client.clone(),
+                log: self.log.clone(),
+                subscription: subscription.clone(),
+            };
+            // the events of one merge are sent one after another, but those of separate merges
+            // can overlap so receivers should order them by timestamp
+            tokio::spawn(async move {
+                for event in events {
+                    sender.deliver(&event).await;
+                }
+            });
+        }
+    }
+}
+
+/// Delivers events to a single subscription.
+struct Sender {
+    config: WebhooksConfig,
+    client: reqwest::Client,
+    log: DeliveryLog,
+    subscription: Subscription,
+}
+
+impl Sender {
+    async fn deliver(&self, event: &Event) {
+        let id = uuid::Uuid::new_v4().to_string();
+        let timestamp = unix_secs(SystemTime::now());
+        let body = serde_json::to_vec(&Payload {
+            delivery: &id,
+            event: event.kind,
+            document: &self.subscription.document,
+            timestamp,
+            task_id: &event.task_id,
+            before: &event.before,
+            after: &event.after,
+        })
+        .expect("Failed to serialize webhook payload");
+        let signature = signature(self.subscription.secret.as_bytes(), &body);
+
+        let mut delivery = Delivery {
+            id,
+            event: event.kind,
+            task_id: event.task_id.clone(),
+            timestamp,
+            attempts: 0,
+            status: None,
+            error: None,
+            delivered: false,
+        };
+        // the resolver checks hosts by name, but addresses in the URL are connected to directly
+        if Url::parse(&self.subscription.url)
+            .ok()
+            .and_then(|url| ip_address(&url))
+            .is_some()
+        {
+            if let Err(err) = check_url(&self.config, &self.subscription.url).await {
+                warn!(
+                    subscription = self.subscription.id,
+                    url = self.subscription.url,
+                    err,
+                    "Refusing webhook delivery"
+                );
+                delivery.error = Some(err);
+                self.record(delivery);
+                return;
+            }
+        }
+        let mut backoff = Duration::from_millis(self.config.initial_backoff_ms);
+        while delivery.attempts < self.config.max_attempts {
+            if delivery.attempts > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            delivery.attempts += 1;
+            let result = self
+                .client
+                .post(&self.subscription.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(EVENT_HEADER, event.kind.name())
+                .header(DELIVERY_HEADER, &delivery.id)
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .send()
+                .await;
+            match result {
+                Ok(res) => {
+                    delivery.status = Some(res.status().as_u16());
+                    delivery.error = None;
+                    if res.status().is_success() {
+                        delivery.delivered = true;
+                        break;
+                    }
+                }
+                Err(err) => {
+                    delivery.status = None;
+                    delivery.error = Some(err.to_string());
+                }
+            }
+            debug!(
+                subscription = self.subscription.id,
+                attempt = delivery.attempts,
+                status = delivery.status,
+                error = delivery.error,
+                "Webhook delivery failed"
+            );
+        }
+        if !delivery.delivered {
+            warn!(
+                subscription = self.subscription.id,
+                url = self.subscription.url,
+                attempts = delivery.attempts,
+                "Giving up on webhook delivery"
+            );
+        }
+        self.record(delivery);
+    }
+
+    fn record(&self, delivery: Delivery) {
+        let mut log = self.log.lock().unwrap();
+        let deliveries = log.entry(self.subscription.id.clone()).or_default();
+        deliveries.push_back(delivery);
+        while deliveries.len() > self.config.log_size {
+            deliveries.pop_front();
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SubscribeRequest {
+    url: String,
+    #[serde(default)]
+    events: Vec<EventKind>,
+}
+
+pub async fn list_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Json<Vec<Subscription>> {
+    let server = server.lock().await;
+    Json(server.webhooks.subscriptions(user.doc_id()))
+}
+
+pub async fn subscribe_handler(
+    user: UserSessionData,
+    State(server): State<Arc<Mutex<Server>>>,
+    Json(request): Json<SubscribeRequest>,
+) -> Response {
+    let config = server.lock().await.webhooks.config.clone();
+    if let Err(err) = check_url(&config, &request.url).await {
+        return (StatusCode::BAD_REQUEST, err).into_response();
+    }
+    let mut server = server.lock().await;
+    match server
+        .webhooks
+        .subscribe(user.doc_id(), &request.url, request.events)
+    {
+        Ok(subscription) => {
+            info!(id = subscription.id, "Added webhook subscription");
+            (StatusCode::CREATED, Json(subscription)).into_response()
+        }
+        Err(err) => {
+            warn!(%err, "Failed to save webhooks");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+pub async fn unsubscribe_handler(
+    user: UserSessionData,
+    UrlPath(id): UrlPath<String>,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> StatusCode {
+    let mut server = server.lock().await;
+    match server.webhooks.unsubscribe(user.doc_id(), &id) {
+        Ok(true) => {
+            info!(id, "Removed webhook subscription");
+            StatusCode::NO_CONTENT
+        }
+        Ok(false) => StatusCode::NOT_FOUND,
+        Err(err) => {
+            warn!(%err, "Failed to save webhooks");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+pub async fn deliveries_handler(
+    user: UserSessionData,
+    UrlPath(id): UrlPath<String>,
+    State(server): State<Arc<Mutex<Server>>>,
+) -> Response {
+    let server = server.lock().await;
+    match server.webhooks.deliveries(user.doc_id(), &id) {
+        Some(deliveries) => Json(deliveries).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderMap, routing::post, Router};
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Vec<u8>)>>>;
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            signature(b"Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_check_url() {
+        let config = WebhooksConfig::default();
+        assert!(check_url(&config, "https://93.184.216.34/hook")
+            .await
+            .is_ok());
+        for url in [
+            "ftp://93.184.216.34/hook",
+            "http://127.0.0.1:3000/hook",
+            "http://localhost/hook",
+            "http://10.0.0.1/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://[::ffff:192.168.0.1]/hook",
+        ] {
+            assert!(check_url(&config, url).await.is_err(), "{url}");
+        }
+
+        let config = WebhooksConfig {
+            allowed_hosts: vec!["127.0.0.1".to_owned()],
+            ..WebhooksConfig::default()
+        };
+        assert!(check_url(&config, "http://127.0.0.1:3000/hook")
+            .await
+            .is_ok());
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut kept = Task::new();
+        kept.set_description("kept".to_owned());
+        let mut edited = Task::new();
+        let mut completed = Task::new();
+        let removed = Task::new();
+        let before = [&kept, &edited, &completed, &removed]
+            .into_iter()
+            .map(|task| (task.id().clone(), task.clone()))
+            .collect::<HashMap<_, _>>();
+
+        edited.set_description("edited".to_owned());
+        completed.complete();
+        let created = Task::new();
+        let after = [&kept, &edited, &completed, &created]
+            .into_iter()
+            .map(|task| (task.id().clone(), task.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let events = diff(&before, &after)
+            .into_iter()
+            .map(|event| {
+                (
+                    event.task_id,
+                    (event.kind, event.before.is_some(), event.after.is_some()),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[edited.id()], (EventKind::Modified, true, true));
+        assert_eq!(events[completed.id()], (EventKind::Completed, true, true));
+        assert_eq!(events[created.id()], (EventKind::Created, false, true));
+        assert_eq!(events[removed.id()], (EventKind::Deleted, true, false));
+    }
+
+    #[tokio::test]
+    async fn test_retry_until_receiver_is_up() {
+        // find a free port, then leave nothing listening on it for the first attempt
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let dir = std::env::temp_dir().join(format!("tasknet-webhooks-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = WebhooksConfig {
+            initial_backoff_ms: 1000,
+            allowed_hosts: vec!["127.0.0.1".to_owned()],
+            ..WebhooksConfig::default()
+        };
+        let mut webhooks = Webhooks::load(&config, &dir).unwrap();
+        let subscription = webhooks
+            .subscribe("doc", &format!("http://{address}/hook"), Vec::new())
+            .unwrap();
+        let task = Task::new();
+        webhooks.notify(
+            "doc",
+            &diff(
+                &HashMap::new(),
+                &HashMap::from([(task.id().clone(), task.clone())]),
+            ),
+        );
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        let app = Router::new().route("/hook", post(|| async { StatusCode::OK }));
+        tokio::spawn(axum::Server::bind(&address).serve(app.into_make_service()));
+
+        let deliveries = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let deliveries = webhooks.deliveries("doc", &subscription.id).unwrap();
+                if !deliveries.is_empty() {
+                    return deliveries;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(deliveries[0].delivered);
+        assert_eq!(deliveries[0].attempts, 2);
+        assert_eq!(deliveries[0].status, Some(200));
+        assert!(deliveries[0].error.is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deliver_with_retry() {
+        // a receiver that fails the first request, as a restarting service might
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    |State(received): State<Received>,
+                     headers: HeaderMap,
+                     body: axum::body::Bytes| async move {
+                        let mut received = received.lock().await;
+                        received.push((headers, body.to_vec()));
+                        if received.len() == 1 {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            StatusCode::OK
+                        }
+                    },
+                ),
+            )
+            .with_state(received.clone());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let dir = std::env::temp_dir().join(format!("tasknet-webhooks-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = WebhooksConfig {
+            initial_backoff_ms: 10,
+            allowed_hosts: vec!["127.0.0.1".to_owned()],
+            ..WebhooksConfig::default()
+        };
+        let mut webhooks = Webhooks::load(&config, &dir).unwrap();
+        let url = format!("http://{address}/hook");
+        let subscription = webhooks
+            .subscribe("doc", &url, vec![EventKind::Created])
+            .unwrap();
+        // only created events were asked for
+        webhooks.subscribe("other", &url, Vec::new()).unwrap();
+        assert_eq!(
+            Webhooks::load(&config, &dir)
+                .unwrap()
+                .subscriptions("doc")
+                .len(),
+            1
+        );
+
+        let task = Task::new();
+        let events = diff(
+            &HashMap::new(),
+            &HashMap::from([(task.id().clone(), task.clone())]),
+        );
+        webhooks.notify("doc", &events);
+        webhooks.notify(
+            "doc",
+            &[Event {
+                kind: EventKind::Modified,
+                ..events[0].clone()
+            }],
+        );
+
+        let deliveries = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let deliveries = webhooks.deliveries("doc", &subscription.id).unwrap();
+                if !deliveries.is_empty() {
+                    return deliveries;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(deliveries.len(), 1);
+        assert!(deliveries[0].delivered);
+        assert_eq!(deliveries[0].attempts, 2);
+        assert_eq!(deliveries[0].status, Some(200));
+
+        let received = received.lock().await;
+        assert_eq!(received.len(), 2);
+        let (headers, body) = &received[1];
+        let expected = signature(subscription.secret.as_bytes(), body);
+        assert_eq!(headers[SIGNATURE_HEADER], expected.as_str());
+        assert_eq!(headers[EVENT_HEADER], "created");
+        let payload = serde_json::from_slice::<serde_json::Value>(body).unwrap();
+        assert_eq!(payload["document"], "doc");
+        assert_eq!(payload["task_id"], task.id().to_string());
+        assert!(payload["before"].is_null());
+        assert_eq!(payload["after"]["description"], "");
+
+        assert!(webhooks.unsubscribe("doc", &subscription.id).unwrap());
+        assert!(webhooks.deliveries("doc", &subscription.id).is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+//! Times edits and incoming changes on a large document, comparing working a task at a time with
+//! reconciling and hydrating every task.
+//!
+//! Run with `cargo bench -p tasknet-shared`.
+
+use std::{collections::HashMap, time::Instant};
+
+use automerge::AutoCommit;
+use autosurgeon::{hydrate, reconcile};
+use tasknet_shared::{
+    document::TaskDocument,
+    task::{Task, TaskId},
+};
+
+const TASKS: usize = 10_000;
+const ROUNDS: u32 = 20;
+
+fn time(name: &str, mut f: impl FnMut(u32)) {
+    let start = Instant::now();
+    for round in 0..ROUNDS {
+        f(round);
+    }
+    let each = start.elapsed() / ROUNDS;
+    println!("{name:<32} {:>10.3}ms", each.as_secs_f64() * 1000.0);
+}
+
+fn load(saved: &[u8]) -> TaskDocument {
+    let mut document = TaskDocument::default();
+    document.load(saved).unwrap();
+    document.hydrate_all().unwrap();
+    document
+}
+
+fn main() {
+    let mut tasks = (0..TASKS)
+        .map(|_| {
+            let task = Task::new();
+            (task.id().clone(), task)
+        })
+        .collect::<HashMap<TaskId, Task>>();
+    let id = tasks.keys().next().unwrap().clone();
+
+    let mut whole = AutoCommit::new();
+    reconcile(&mut whole, &tasks).unwrap();
+    let saved = whole.save();
+    println!("{TASKS} tasks, {} bytes saved", saved.len());
+
+    let mut document = load(&saved);
+    time("edit: reconcile every task", |round| {
+        let task = tasks.get_mut(&id).unwrap();
+        task.set_description(format!("whole {round}"));
+        reconcile(&mut whole, &tasks).unwrap();
+    });
+    time("edit: reconcile one task", |round| {
+        document
+            .change_task(&id, |task| task.set_description(format!("one {round}")))
+            .unwrap();
+    });
+
+    // another device makes a change at a time for the documents here to receive
+    let mut remote = load(&saved);
+    let mut receiving = AutoCommit::load(&saved).unwrap();
+    let mut document = load(&saved);
+    let mut changes = Vec::new();
+    for round in 0..ROUNDS {
+        let heads = remote.heads();
+        remote
+            .change_task(&id, |task| task.set_description(format!("remote {round}")))
+            .unwrap();
+        changes.push(remote.changes_since(&heads));
+    }
+    time("receive: hydrate every task", |round| {
+        receiving
+            .load_incremental(&changes[round as usize])
+            .unwrap();
+        let _: HashMap<TaskId, Task> = hydrate(&receiving).unwrap();
+    });
+    time("receive: hydrate changed tasks", |round| {
+        document.load_incremental(&changes[round as usize]).unwrap();
+    });
+    assert_eq!(document.tasks(), remote.tasks());
+}
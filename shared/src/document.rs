@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+
+use automerge::{
+    op_observer::HasPatches, sync, sync::SyncDoc, Automerge, AutomergeError, ChangeHash, Patch,
+    PatchAction, Prop, ReadDoc, VecOpObserver, ROOT,
+};
+use autosurgeon::{hydrate, hydrate_prop, reconcile_prop, HydrateError, ReconcileError};
+
+use crate::task::{Task, TaskId};
+
+/// An automerge document of tasks alongside the tasks it holds.
+///
+/// Edits are reconciled a task at a time and incoming changes only hydrate the tasks they touch,
+/// so neither costs more with more tasks in the document.
+#[derive(Debug, Default)]
+pub struct TaskDocument {
+    doc: Automerge,
+    tasks: HashMap<TaskId, Task>,
+}
+
+impl TaskDocument {
+    /// Merge in a saved document or changes without hydrating any tasks, which is cheaper when
+    /// loading many followed by [`Self::hydrate_all`].
+    pub fn load(&mut self, bytes: &[u8]) -> Result<usize, AutomergeError> {
+        self.doc.load_incremental(bytes)
+    }
+
+    /// Hydrate every task from the document.
+    pub fn hydrate_all(&mut self) -> Result<(), HydrateError> {
+        self.tasks = hydrate(&self.doc)?;
+        Ok(())
+    }
+
+    pub const fn tasks(&self) -> &HashMap<TaskId, Task> {
+        &self.tasks
+    }
+
+    pub fn get_task(&self, id: &TaskId) -> Option<&Task> {
+        self.tasks.get(id)
+    }
+
+    /// Write the task into the document, leaving the other tasks alone.
+    pub fn update_task(&mut self, task: Task) -> Result<(), ReconcileError> {
+        let mut tx = self.doc.transaction();
+        reconcile_prop(&mut tx, ROOT, task.id().as_ref(), &task)?;
+        tx.commit();
+        self.tasks.insert(task.id().clone(), task);
+        Ok(())
+    }
+
+    /// Change a task in place, doing nothing if it doesn't exist.
+    pub fn change_task<F: FnOnce(&mut Task)>(
+        &mut self,
+        id: &TaskId,
+        f: F,
+    ) -> Result<(), ReconcileError> {
+        let Some(task) = self.tasks.get_mut(id) else {
+            return Ok(());
+        };
+        f(task);
+        let mut tx = self.doc.transaction();
+        reconcile_prop(&mut tx, ROOT, id.as_ref(), &*task)?;
+        tx.commit();
+        Ok(())
+    }
+
+    pub fn remove_task(&mut self, id: &TaskId) -> Result<(), AutomergeError> {
+        if self.tasks.remove(id).is_some() {
+            let mut tx = self.doc.transaction();
+            automerge::transaction::Transactable::delete(&mut tx, ROOT, id.as_ref())?;
+            tx.commit();
+        }
+        Ok(())
+    }
+
+    pub fn generate_sync_message(&self, state: &mut sync::State) -> Option<sync::Message> {
+        self.doc.generate_sync_message(state)
+    }
+
+    /// Apply a sync message, returning the tasks it changed.
+    pub fn receive_sync_message(
+        &mut self,
+        state: &mut sync::State,
+        message: sync::Message,
+    ) -> Result<HashSet<TaskId>, AutomergeError> {
+        let mut observer = VecOpObserver::default();
+        self.doc
+            .receive_sync_message_with(state, message, &mut observer)?;
+        Ok(self.rehydrate(&observer.take_patches()))
+    }
+
+    /// Apply changes or a saved document from elsewhere, returning the tasks they changed.
+    pub fn load_incremental(&mut self, bytes: &[u8]) -> Result<HashSet<TaskId>, AutomergeError> {
+        let mut observer = VecOpObserver::default();
+        self.doc.load_incremental_with(bytes, Some(&mut observer))?;
+        Ok(self.rehydrate(&observer.take_patches()))
+    }
+
+    pub fn heads(&self) -> Vec<ChangeHash> {
+        self.doc.get_heads()
+    }
+
+    /// Encode the changes made since `heads`, or the whole document if the heads are unknown.
+    pub fn changes_since(&mut self, heads: &[ChangeHash]) -> Vec<u8> {
+        match self.doc.get_changes(heads) {
+            Ok(changes) => changes
+                .iter()
+                .flat_map(|change| change.raw_bytes().to_vec())
+                .collect(),
+            Err(_) => self.doc.save(),
+        }
+    }
+
+    /// How many changes there are since `heads`, or in total if the heads are unknown.
+    pub fn count_changes_since(&self, heads: &[ChangeHash]) -> usize {
+        self.doc
+            .get_changes(heads)
+            .or_else(|_| self.doc.get_changes(&[]))
+            .map_or(0, |changes| changes.len())
+    }
+
+    /// Encode the whole document.
+    pub fn save(&mut self) -> Vec<u8> {
+        self.doc.save()
+    }
+
+    /// Hydrate again just the tasks the patches touched.
+    fn rehydrate(&mut self, patches: &[Patch<char>]) -> HashSet<TaskId> {
+        let touched = patches
+            .iter()
+            .filter_map(patched_task)
+            .collect::<HashSet<_>>();
+        for id in &touched {
+            match self.hydrate_task(id) {
+                Ok(Some(task)) => {
+                    self.tasks.insert(id.clone(), task);
+                }
+                Ok(None) => {
+                    self.tasks.remove(id);
+                }
+                // keep the last good version rather than losing the task
+                Err(_) => {}
+            }
+        }
+        touched
+    }
+
+    fn hydrate_task(&self, id: &TaskId) -> Result<Option<Task>, HydrateError> {
+        if self.doc.get(ROOT, id.as_ref())?.is_none() {
+            return Ok(None);
+        }
+        hydrate_prop(&self.doc, ROOT, id.as_ref()).map(Some)
+    }
+}
+
+/// The task a patch is within, as tasks are keyed by their id at the root of the document.
+fn patched_task(patch: &Patch<char>) -> Option<TaskId> {
+    match (patch.path.first(), &patch.action) {
+        (Some((_, Prop::Map(key))), _)
+        | (None, PatchAction::PutMap { key, .. } | PatchAction::DeleteMap { key }) => {
+            Some(TaskId::from(key.as_str()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The tasks as stored, which keeps times to the millisecond.
+    fn stored(document: &TaskDocument) -> HashMap<TaskId, Task> {
+        hydrate(&document.doc).unwrap()
+    }
+
+    fn synced(a: &mut TaskDocument, b: &mut TaskDocument) -> HashSet<TaskId> {
+        let mut changed = HashSet::new();
+        let (mut a_state, mut b_state) = (sync::State::new(), sync::State::new());
+        loop {
+            let to_b = a.generate_sync_message(&mut a_state);
+            let to_a = b.generate_sync_message(&mut b_state);
+            if to_a.is_none() && to_b.is_none() {
+                return changed;
+            }
+            if let Some(message) = to_b {
+                changed.extend(b.receive_sync_message(&mut b_state, message).unwrap());
+            }
+            if let Some(message) = to_a {
+                a.receive_sync_message(&mut a_state, message).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_hydrates_touched_tasks() {
+        let mut a = TaskDocument::default();
+        let mut b = TaskDocument::default();
+        let (first, second) = (Task::new(), Task::new());
+        a.update_task(first.clone()).unwrap();
+        a.update_task(second.clone()).unwrap();
+        assert_eq!(
+            synced(&mut a, &mut b),
+            HashSet::from([first.id().clone(), second.id().clone()])
+        );
+        assert_eq!(b.tasks(), &stored(&a));
+
+        a.change_task(first.id(), |task| {
+            task.set_description("changed".to_owned())
+        })
+        .unwrap();
+        assert_eq!(synced(&mut a, &mut b), HashSet::from([first.id().clone()]));
+        assert_eq!(b.get_task(first.id()).unwrap().description(), "changed");
+
+        a.remove_task(second.id()).unwrap();
+        assert_eq!(synced(&mut a, &mut b), HashSet::from([second.id().clone()]));
+        assert_eq!(b.tasks(), &stored(&a));
+        assert_eq!(b.tasks().len(), 1);
+    }
+
+    #[test]
+    fn test_load_incremental_hydrates_touched_tasks() {
+        let mut a = TaskDocument::default();
+        let task = Task::new();
+        a.update_task(task.clone()).unwrap();
+        let mut b = TaskDocument::default();
+        b.load(&a.save()).unwrap();
+        b.hydrate_all().unwrap();
+        assert_eq!(b.tasks(), &stored(&a));
+
+        let heads = a.heads();
+        a.change_task(task.id(), |task| task.set_description("changed".to_owned()))
+            .unwrap();
+        assert_eq!(a.count_changes_since(&heads), 1);
+        let changed = b.load_incremental(&a.changes_since(&heads)).unwrap();
+        assert_eq!(changed, HashSet::from([task.id().clone()]));
+        assert_eq!(b.tasks(), &stored(&a));
+    }
+}
@@ -1,3 +1,9 @@
 pub mod cookies;
+pub mod document;
+pub mod limits;
+pub mod presence;
 pub mod providers;
+pub mod reminders;
+pub mod sessions;
 pub mod sync;
+pub mod task;
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// The limits the server puts on each document and its sync messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// The largest sync message a client can send.
+    pub max_message_bytes: usize,
+    /// The most a document can take up in storage, beyond which only changes removing tasks are
+    /// accepted.
+    pub max_document_bytes: u64,
+    pub max_tasks: usize,
+    /// The longest a task description can be, in characters.
+    pub max_description_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_bytes: 4 * 1024 * 1024,
+            max_document_bytes: 32 * 1024 * 1024,
+            max_tasks: 10_000,
+            max_description_length: 1000,
+        }
+    }
+}
+
+/// How much of the limits a document is using.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub limits: Limits,
+    pub document_bytes: u64,
+    /// The number of tasks, unknown to the server when the document is end-to-end encrypted.
+    pub tasks: Option<usize>,
+}
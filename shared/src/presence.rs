@@ -0,0 +1,29 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::task::TaskId;
+
+/// Longest device name the server passes on to other devices.
+pub const MAX_DEVICE_NAME_LENGTH: usize = 64;
+
+/// What a device is doing with a document, shared with the other devices connected to it.
+///
+/// Presence is never stored, it only lasts as long as the sync connection.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Presence {
+    pub device: String,
+    /// The task the device has open.
+    pub viewing: Option<TaskId>,
+    /// Whether the open task is being changed rather than only looked at.
+    pub editing: bool,
+    /// When the device last did something, set by the server when it receives the presence.
+    pub last_active: Option<DateTime<Utc>>,
+}
+
+/// Another device connected to the same document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Peer {
+    /// Identifies the connection, so a device connected twice shows up twice.
+    pub id: String,
+    pub presence: Presence,
+}
@@ -5,8 +5,35 @@ pub struct ProviderDefault {
     pub enabled: bool,
 }
 
+/// Whether new local accounts can be created.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Registration {
+    /// Anyone can register an account.
+    Open,
+    /// Registration requires a valid invite code.
+    InviteOnly,
+    /// No new accounts can be registered.
+    #[default]
+    Disabled,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ProviderLocal {
+    pub enabled: bool,
+    pub registration: Registration,
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Providers {
     pub public: ProviderDefault,
     pub google: ProviderDefault,
+    pub local: ProviderLocal,
+}
+
+/// Details of the signed in local account.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct LocalAccount {
+    pub username: String,
+    pub admin: bool,
 }
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+/// How a user wants to be emailed about tasks that are due or scheduled.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReminderSettings {
+    /// Nothing is sent until the user opts in.
+    pub enabled: bool,
+    pub email: String,
+    pub mode: ReminderMode,
+    pub quiet_hours: Option<QuietHours>,
+    /// How far the user's local time is ahead of UTC, for the digest and quiet hours.
+    pub utc_offset_minutes: i32,
+}
+
+/// A document's reminder settings as the server has them.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReminderStatus {
+    pub settings: ReminderSettings,
+    /// Nothing is sent to the email until the code emailed to it has been entered.
+    pub email_confirmed: bool,
+}
+
+/// The code from a confirmation email.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConfirmEmail {
+    pub code: String,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReminderMode {
+    /// Send reminders as tasks become due.
+    #[default]
+    Immediate,
+    /// Send a single email a day at the local hour, listing the tasks due in the next day.
+    Digest { hour: u32 },
+}
+
+/// Local hours when no reminders are sent, from `start` up to `end`, which may wrap past
+/// midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QuietHours {
+    pub start: u32,
+    pub end: u32,
+}
+
+impl QuietHours {
+    pub const fn contains(self, hour: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= hour && hour < self.end
+        } else {
+            self.start <= hour || hour < self.end
+        }
+    }
+}
+
+impl ReminderSettings {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled
+            && (!self.email.contains('@') || self.email.chars().any(char::is_whitespace))
+        {
+            return Err("Enter an email address to send reminders to.".to_owned());
+        }
+        if let ReminderMode::Digest { hour } = self.mode {
+            if hour > 23 {
+                return Err("The digest hour must be from 0 to 23.".to_owned());
+            }
+        }
+        if let Some(quiet_hours) = self.quiet_hours {
+            if quiet_hours.start > 23 || quiet_hours.end > 23 {
+                return Err("Quiet hours must be from 0 to 23.".to_owned());
+            }
+        }
+        if self.utc_offset_minutes.abs() > 14 * 60 {
+            return Err("The UTC offset must be within 14 hours.".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quiet_hours_wrap_midnight() {
+        let night = QuietHours { start: 22, end: 7 };
+        assert!(night.contains(23));
+        assert!(night.contains(0));
+        assert!(!night.contains(7));
+        assert!(!night.contains(12));
+
+        let lunch = QuietHours { start: 12, end: 14 };
+        assert!(lunch.contains(13));
+        assert!(!lunch.contains(14));
+    }
+}
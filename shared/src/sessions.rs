@@ -0,0 +1,16 @@
+use serde::{Deserialize, Serialize};
+
+/// A device signed in to the same account.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: String,
+    /// Whether this is the session making the request.
+    pub current: bool,
+    /// When the session was started, in seconds since the unix epoch.
+    pub created: u64,
+    /// When the session was last used, in seconds since the unix epoch.
+    pub last_seen: u64,
+    pub user_agent: Option<String>,
+    /// The number of sync connections currently using the session.
+    pub websockets: usize,
+}
@@ -3,9 +3,15 @@ use std::convert::TryFrom;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::presence::{Peer, Presence};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum SyncMessage {
     Message(Vec<u8>),
+    /// Sent by a client when what it is doing changes.
+    Presence(Presence),
+    /// Sent by the server with the other devices connected to the document, whenever they change.
+    Peers(Vec<Peer>),
 }
 
 impl TryFrom<&Vec<u8>> for SyncMessage {
@@ -28,3 +34,93 @@ impl TryFrom<SyncMessage> for Vec<u8> {
         serde_json::to_vec(&m)
     }
 }
+
+/// Websocket close code sent when the session the connection was using has ended, such as from
+/// signing out or being revoked from another device.
+pub const CLOSE_SESSION_ENDED: u16 = 4001;
+
+/// Websocket close code sent when a plaintext sync connection is opened for a document that is
+/// end-to-end encrypted.
+pub const CLOSE_DOCUMENT_ENCRYPTED: u16 = 4002;
+
+/// Websocket close code sent when a client's sync message would break one of the server's limits,
+/// with the reason saying which.
+pub const CLOSE_LIMIT_EXCEEDED: u16 = 4003;
+
+/// Websocket close code sent when the server is shutting down, the standard code for a service
+/// restart, so clients should reconnect after a short wait.
+pub const CLOSE_SERVER_RESTARTING: u16 = 1012;
+
+/// A change set encrypted by a client, opaque to the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedBlob {
+    /// Identifies the key the blob was encrypted with, without revealing it.
+    pub key_id: String,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+/// An encrypted blob along with its position in the server's log for the document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredBlob {
+    pub seq: u64,
+    pub blob: EncryptedBlob,
+}
+
+/// Why the server refused an encrypted sync message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EncryptedSyncError {
+    /// The blobs were encrypted with a different key to the document's current one, usually as
+    /// the passphrase was changed on another device.
+    KeyMismatch,
+    /// A compaction did not cover all of the blobs the server has, so the client should pull and
+    /// try again.
+    OutOfDate,
+    /// The blobs would take the document over the server's size limit.
+    TooLarge,
+    /// Blobs were pushed before a snapshot encrypted the document, which has to come first.
+    NotEncrypted,
+}
+
+/// Messages for syncing end-to-end encrypted documents.
+///
+/// The server cannot merge encrypted changes so it keeps an append-only log of blobs for each
+/// document, which clients pull from and push to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EncryptedSyncMessage {
+    /// Sent by the server when the connection opens, with the id of the key the document is
+    /// encrypted with, if it has any blobs yet.
+    Welcome {
+        key_id: Option<String>,
+        latest_seq: u64,
+    },
+    /// Request the blobs after the given sequence number.
+    Pull { after: u64 },
+    /// Blobs from the server's log, in order.
+    Blobs { blobs: Vec<StoredBlob> },
+    /// Append blobs to the log.
+    Push { blobs: Vec<EncryptedBlob> },
+    /// The server stored the pushed blobs, the last with sequence number `seq`.
+    Pushed { seq: u64 },
+    /// Replace the blobs with a snapshot of the whole document, `up_to` being the last blob the
+    /// client has.
+    ///
+    /// Compacting with a different key changes the document's key.
+    Compact { up_to: u64, snapshot: EncryptedBlob },
+    /// The server refused the previous message.
+    Rejected { error: EncryptedSyncError },
+}
+
+impl TryFrom<&[u8]> for EncryptedSyncMessage {
+    type Error = serde_json::Error;
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        serde_json::from_slice(value)
+    }
+}
+
+impl TryFrom<EncryptedSyncMessage> for Vec<u8> {
+    type Error = serde_json::Error;
+    fn try_from(m: EncryptedSyncMessage) -> Result<Self, Self::Error> {
+        serde_json::to_vec(&m)
+    }
+}
@@ -61,9 +61,9 @@ impl From<&str> for TaskId {
     }
 }
 
-impl ToString for TaskId {
-    fn to_string(&self) -> String {
-        self.0.clone()
+impl std::fmt::Display for TaskId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
     }
 }
 
@@ -147,6 +147,12 @@ pub enum Status {
     Waiting,
 }
 
+impl Default for Task {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Task {
     pub fn new() -> Self {
         Self {
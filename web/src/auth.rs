@@ -1,7 +1,7 @@
 use cookie::CookieJar;
+#[allow(clippy::wildcard_imports)]
 use seed::{prelude::*, *};
-use tasknet_shared::cookies::AUTH_PROVIDER_COOKIE;
-use tasknet_shared::cookies::SESSION_COOKIE;
+use tasknet_shared::cookies::{AUTH_PROVIDER_COOKIE, DOCUMENT_ID_COOKIE};
 
 #[derive(Debug)]
 pub enum Provider {
@@ -9,35 +9,34 @@ pub enum Provider {
     Public,
     /// Sign in with a google account to access a private document.
     Google,
+    /// Sign in with a username and password registered on the server.
+    Local,
 }
 
 impl Provider {
+    /// The provider of the current session.
+    ///
+    /// The session cookie itself is hidden from scripts, but the provider cookie is set and
+    /// expires alongside it.
     pub fn load_from_session() -> Option<Self> {
-        let have_session = cookies()
-            .map(|cookie_jar| cookie_jar.get(SESSION_COOKIE).is_some())
-            .unwrap_or_default();
-
-        if have_session {
-            cookies()
-                .and_then(|cookie_jar| {
-                    cookie_jar
-                        .get(AUTH_PROVIDER_COOKIE)
-                        .map(|cookie| cookie.value())
-                        .map(ToOwned::to_owned)
-                })
-                .and_then(|provider| match provider.as_str() {
-                    "public" => Some(Self::Public),
-                    "google" => Some(Self::Google),
-                    _ => None,
-                })
-        } else {
-            None
-        }
+        cookies()
+            .and_then(|cookie_jar| {
+                cookie_jar
+                    .get(AUTH_PROVIDER_COOKIE)
+                    .map(cookie::Cookie::value)
+                    .map(ToOwned::to_owned)
+            })
+            .and_then(|provider| match provider.as_str() {
+                "public" => Some(Self::Public),
+                "google" => Some(Self::Google),
+                "local" => Some(Self::Local),
+                _ => None,
+            })
     }
 
     pub fn logo(&self) -> Node<crate::Msg> {
         match self {
-            Self::Public => seed::empty!(),
+            Self::Public | Self::Local => seed::empty!(),
             Self::Google => seed::img![
                 C!["inline", "pr-2"],
                 attrs! {At::Src => "/assets/btn_google_light_normal_ios.svg"}
@@ -46,6 +45,15 @@ impl Provider {
     }
 }
 
+/// The id of the document the current session syncs.
+pub fn document_id() -> Option<String> {
+    cookies().and_then(|cookie_jar| {
+        cookie_jar
+            .get(DOCUMENT_ID_COOKIE)
+            .map(|cookie| cookie.value().to_owned())
+    })
+}
+
 pub fn cookies() -> Option<CookieJar> {
     let cookies_str = html_document().cookie().ok()?;
     let mut jar = cookie::CookieJar::new();
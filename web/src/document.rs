@@ -1,80 +1,83 @@
-use automerge::sync::SyncDoc;
-use automerge::AutoCommit;
-use autosurgeon::{hydrate, reconcile};
-use base64::Engine;
+use automerge::ChangeHash;
 use gloo_console::log;
-use gloo_storage::{LocalStorage, Storage};
 
-use crate::task::{Task, TaskId};
 use std::collections::HashMap;
+use tasknet_shared::document::TaskDocument;
+use tasknet_shared::task::{Task, TaskId};
 
-const AUTODOC_STORAGE_KEY: &str = "tasknet-autodoc";
-
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Document {
-    tasks: HashMap<TaskId, Task>,
-    autodoc: automerge::AutoCommit,
+    autodoc: TaskDocument,
     server_sync_state: automerge::sync::State,
+    /// The heads when the document was last saved on this device.
+    ///
+    /// Tracked here rather than using `save_incremental` as snapshots for syncing also move
+    /// automerge's marker.
+    saved_heads: Vec<ChangeHash>,
 }
 
 impl Document {
     pub fn get_task(&self, id: &TaskId) -> Option<&Task> {
-        self.tasks.get(id)
+        self.autodoc.get_task(id)
     }
 
     pub const fn tasks(&self) -> &HashMap<TaskId, Task> {
-        &self.tasks
+        self.autodoc.tasks()
     }
 
     pub fn new_task(&mut self) -> TaskId {
         let task = Task::new();
         let id = task.id().clone();
-        self.tasks.insert(id.clone(), task);
-        reconcile(&mut self.autodoc, &self.tasks).unwrap();
+        self.autodoc.update_task(task).unwrap();
         id
     }
 
     pub fn change_task<F: FnOnce(&mut Task)>(&mut self, id: &TaskId, f: F) {
-        if let Some(task) = self.tasks.get_mut(id) {
-            f(task);
-            reconcile(&mut self.autodoc, &self.tasks).unwrap();
-        }
+        self.autodoc.change_task(id, f).unwrap();
     }
 
     pub fn update_task(&mut self, task: Task) {
-        self.tasks.insert(task.id().clone(), task);
-        reconcile(&mut self.autodoc, &self.tasks).unwrap();
+        self.autodoc.update_task(task).unwrap();
     }
 
     pub fn remove_task(&mut self, id: &TaskId) {
-        self.tasks.remove(id);
-        reconcile(&mut self.autodoc, &self.tasks).unwrap();
-    }
-
-    pub fn load() -> Self {
-        let saved_document: String =
-            LocalStorage::get(AUTODOC_STORAGE_KEY).map_or_else(|_| String::new(), |bytes| bytes);
-        let b64_engine = base64::engine::general_purpose::STANDARD;
-        let saved_document = b64_engine.decode(saved_document).unwrap_or_default();
-        let autodoc = AutoCommit::load(&saved_document).unwrap_or_else(|_| AutoCommit::new());
-        let tasks = hydrate(&autodoc).unwrap();
-        Self {
-            tasks,
-            autodoc,
-            server_sync_state: automerge::sync::State::default(),
+        self.autodoc.remove_task(id).unwrap();
+    }
+
+    /// Merge in the chunks saved on this device, returning whether there were already changes
+    /// that still need saving.
+    pub fn load(&mut self, chunks: &[Vec<u8>]) -> bool {
+        let unsaved = self.unsaved();
+        for chunk in chunks {
+            if let Err(err) = self.autodoc.load(chunk) {
+                log!(format!("Failed to load saved document: {:?}", err));
+            }
         }
+        self.saved_heads = self.heads();
+        self.autodoc.hydrate_all().unwrap();
+        unsaved
+    }
+
+    /// Whether there are changes since the document was last saved.
+    pub fn unsaved(&self) -> bool {
+        self.heads() != self.saved_heads
     }
 
-    pub fn save(&mut self) {
-        let bytes = self.autodoc.save();
-        let b64_engine = base64::engine::general_purpose::STANDARD;
-        let bytes = b64_engine.encode(bytes);
-        LocalStorage::set(AUTODOC_STORAGE_KEY, &bytes).expect("save autodoc to LocalStorage");
+    /// Encode the changes since the document was last saved.
+    pub fn save_changes(&mut self) -> Vec<u8> {
+        let changes = self.changes_since(&self.saved_heads.clone());
+        self.saved_heads = self.heads();
+        changes
+    }
+
+    /// Encode the whole document to replace what was saved.
+    pub fn save_all(&mut self) -> Vec<u8> {
+        self.saved_heads = self.heads();
+        self.autodoc.save()
     }
 
     pub fn generate_sync_message(&mut self) -> Option<Vec<u8>> {
         self.autodoc
-            .sync()
             .generate_sync_message(&mut self.server_sync_state)
             .map(automerge::sync::Message::encode)
     }
@@ -84,18 +87,12 @@ impl Document {
             Ok(message) => {
                 let res = self
                     .autodoc
-                    .sync()
                     .receive_sync_message(&mut self.server_sync_state, message);
-                match res {
-                    Ok(()) => {
-                        self.tasks = hydrate(&self.autodoc).unwrap();
-                    }
-                    Err(err) => {
-                        log!(format!(
-                            "Failed to receive sync message from server: {:?}",
-                            err
-                        ));
-                    }
+                if let Err(err) = res {
+                    log!(format!(
+                        "Failed to receive sync message from server: {:?}",
+                        err
+                    ));
                 }
             }
             Err(err) => {
@@ -103,4 +100,50 @@ impl Document {
             }
         }
     }
+
+    /// How many changes the server isn't known to have, going by the heads both last had.
+    pub fn unsynced_changes(&self) -> usize {
+        self.autodoc
+            .count_changes_since(&self.server_sync_state.shared_heads)
+    }
+
+    pub fn count_changes_since(&self, heads: &[ChangeHash]) -> usize {
+        self.autodoc.count_changes_since(heads)
+    }
+
+    /// Forget what the server is known to have, so the next sync compares everything.
+    pub fn reset_sync(&mut self) {
+        self.server_sync_state = automerge::sync::State::new();
+    }
+
+    pub fn heads(&self) -> Vec<ChangeHash> {
+        self.autodoc.heads()
+    }
+
+    /// Encode the changes made since `heads`, or the whole document if the heads are unknown.
+    pub fn changes_since(&mut self, heads: &[ChangeHash]) -> Vec<u8> {
+        self.autodoc.changes_since(heads)
+    }
+
+    /// Encode the whole document.
+    pub fn snapshot(&mut self) -> Vec<u8> {
+        self.autodoc.save()
+    }
+
+    /// Apply changes or a snapshot produced on another device.
+    pub fn apply_changes(&mut self, bytes: &[u8]) {
+        if let Err(err) = self.autodoc.load_incremental(bytes) {
+            log!(format!("Failed to apply changes: {:?}", err));
+        }
+    }
+
+    /// Apply changes another tab already saved on this device, so they aren't saved again unless
+    /// there are changes here to save with them.
+    pub fn apply_saved(&mut self, bytes: &[u8]) {
+        let saved = !self.unsaved();
+        self.apply_changes(bytes);
+        if saved {
+            self.saved_heads = self.heads();
+        }
+    }
 }
@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+use argon2::Argon2;
+use automerge::ChangeHash;
+use base64::Engine;
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+use gloo_console::log;
+use gloo_storage::{LocalStorage, Storage};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tasknet_shared::sync::EncryptedBlob;
+
+const ENCRYPTION_STORAGE_KEY: &str = "tasknet-encryption";
+
+/// Why syncing has stopped until the user enters a passphrase.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locked {
+    /// The document is encrypted but this device has no passphrase for it.
+    PassphraseRequired,
+    /// The passphrase does not match the key the document is encrypted with.
+    WrongPassphrase,
+    /// The passphrase was changed on another device.
+    PassphraseChanged,
+}
+
+impl Locked {
+    pub const fn message(self) -> &'static str {
+        match self {
+            Self::PassphraseRequired => {
+                "This document is end-to-end encrypted, enter its passphrase in the settings to sync."
+            }
+            Self::WrongPassphrase => "The passphrase is incorrect for this document.",
+            Self::PassphraseChanged => {
+                "The passphrase was changed on another device, enter the new one in the settings to sync."
+            }
+        }
+    }
+}
+
+/// The key for an end-to-end encrypted document, along with how far this device has synced it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Encryption {
+    doc_id: String,
+    /// The base64 encoded key derived from the passphrase.
+    key: String,
+    key_id: String,
+    /// The sequence number of the last blob received from the server.
+    pub last_seq: u64,
+    /// Heads of the document known to be stored on the server.
+    pub synced_heads: Vec<ChangeHash>,
+    /// Heads of the changes pushed that the server hasn't acknowledged yet.
+    #[serde(skip)]
+    pub pushing: Option<Vec<ChangeHash>>,
+    /// Whether the server has welcomed the current connection, so changes can be pushed.
+    #[serde(skip)]
+    pub ready: bool,
+    /// The key being changed to, until the server has stored the document with it.
+    #[serde(skip)]
+    pub rotation: Option<Box<Self>>,
+}
+
+impl Encryption {
+    /// Derive the key for the document from the passphrase.
+    ///
+    /// The salt comes from the document id so every device derives the same key.
+    pub fn new(doc_id: String, passphrase: &str) -> Self {
+        let salt = format!("tasknet-{doc_id}");
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut key)
+            .expect("derive key from passphrase");
+        let key_id = Sha256::digest(key)
+            .iter()
+            .take(8)
+            .fold(String::new(), |mut key_id, b| {
+                let _ = write!(key_id, "{b:02x}");
+                key_id
+            });
+        Self {
+            doc_id,
+            key: base64::engine::general_purpose::STANDARD.encode(key),
+            key_id,
+            last_seq: 0,
+            synced_heads: Vec::new(),
+            pushing: None,
+            ready: false,
+            rotation: None,
+        }
+    }
+
+    /// Derive a new key for the same document.
+    pub fn with_passphrase(&self, passphrase: &str) -> Self {
+        Self::new(self.doc_id.clone(), passphrase)
+    }
+
+    /// Switch to the new key, keeping track of what has been synced.
+    pub fn complete_rotation(&mut self) {
+        if let Some(mut rotation) = self.rotation.take() {
+            rotation.last_seq = self.last_seq;
+            rotation.synced_heads = std::mem::take(&mut self.synced_heads);
+            rotation.pushing = self.pushing.take();
+            rotation.ready = self.ready;
+            *self = *rotation;
+        }
+    }
+
+    /// Load the key saved on this device for the document.
+    pub fn load(doc_id: &str) -> Option<Self> {
+        LocalStorage::get::<Self>(ENCRYPTION_STORAGE_KEY)
+            .ok()
+            .filter(|encryption| encryption.doc_id == doc_id)
+    }
+
+    pub fn save(&self) {
+        LocalStorage::set(ENCRYPTION_STORAGE_KEY, self).expect("save encryption to LocalStorage");
+    }
+
+    pub fn forget() {
+        LocalStorage::delete(ENCRYPTION_STORAGE_KEY);
+    }
+
+    pub fn key_id(&self) -> &str {
+        &self.key_id
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(&self.key)
+            .unwrap_or_default();
+        XChaCha20Poly1305::new_from_slice(&key).expect("valid key length")
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> EncryptedBlob {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()
+            .encrypt(&nonce, plaintext)
+            .expect("encrypt changes");
+        EncryptedBlob {
+            key_id: self.key_id.clone(),
+            nonce: nonce.to_vec(),
+            ciphertext,
+        }
+    }
+
+    pub fn decrypt(&self, blob: &EncryptedBlob) -> Option<Vec<u8>> {
+        if blob.key_id != self.key_id || blob.nonce.len() != 24 {
+            log!("Skipping blob encrypted with a different key");
+            return None;
+        }
+        self.cipher()
+            .decrypt(XNonce::from_slice(&blob.nonce), blob.ciphertext.as_slice())
+            .map_err(|err| log!(format!("Failed to decrypt blob: {:?}", err)))
+            .ok()
+    }
+}
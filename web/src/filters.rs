@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::task::{Priority, Status, Task};
+use tasknet_shared::task::{Priority, Status, Task};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[allow(clippy::struct_excessive_bools)]
@@ -1,9 +1,10 @@
 #![warn(clippy::pedantic)]
 #![warn(clippy::nursery)]
 
+use automerge::ChangeHash;
 use std::convert::TryFrom;
 use wasm_sockets::{self, ConnectionStatus, EventClient};
-use web_sys::CloseEvent;
+use web_sys::{CloseEvent, ServiceWorkerRegistration};
 
 use auth::Provider;
 #[allow(clippy::wildcard_imports)]
@@ -15,43 +16,62 @@ use gloo_console::log;
 mod auth;
 mod components;
 mod document;
+mod encryption;
 mod filters;
 mod pages;
-mod task;
+mod presence;
+mod reconnect;
+mod service_worker;
+mod storage;
+mod sync_status;
+mod tabs;
 mod urgency;
 
 use components::{view_button, view_button_str, ButtonOptions};
 use document::Document;
+use encryption::{Encryption, Locked};
 use filters::Filters;
-use task::TaskId;
-use tasknet_shared::sync::SyncMessage;
+use presence::Presences;
+use reconnect::Reconnect;
+use storage::Storage;
+use sync_status::SyncStatus;
+use tabs::{TabMessage, Tabs};
+use tasknet_shared::task::TaskId;
+use tasknet_shared::sync::{
+    EncryptedSyncError, EncryptedSyncMessage, SyncMessage, CLOSE_DOCUMENT_ENCRYPTED,
+    CLOSE_LIMIT_EXCEEDED, CLOSE_SERVER_RESTARTING, CLOSE_SESSION_ENDED,
+};
 
 const VIEW_TASK: &str = "view";
 const AUTH: &str = "auth";
 const SETTINGS: &str = "settings";
 
-fn ws_url() -> String {
+/// How long changes to the document have to pause for before they are saved and synced.
+const FLUSH_DELAY_MS: u32 = 500;
+
+fn ws_url(encrypted: bool) -> String {
     let location = window().location();
     let protocol = match location.protocol().unwrap_or_default().as_str() {
         "https:" => "wss",
         _ => "ws",
     };
     format!(
-        "{}://{}{}sync",
+        "{}://{}{}{}",
         protocol,
         location.host().unwrap(),
         location.pathname().unwrap(),
+        if encrypted { "sync/encrypted" } else { "sync" },
     )
 }
 
-fn create_websocket(orders: &impl Orders<Msg>) -> EventClient {
+fn create_websocket(orders: &impl Orders<Msg>, encrypted: bool) -> EventClient {
     let msg_sender = orders.msg_sender();
 
-    let mut client = EventClient::new(&ws_url()).expect("Failed to create websocket client");
+    let mut client = EventClient::new(&ws_url(encrypted)).expect("Failed to create websocket client");
 
     let send = msg_sender.clone();
     client.set_on_error(Some(Box::new(move |error| {
-        error!("WS: {:#?}", error);
+        error!("WS:", format!("{error:#?}"));
         send(Some(Msg::WebSocketFailed));
     })));
 
@@ -83,7 +103,7 @@ fn create_websocket(orders: &impl Orders<Msg>) -> EventClient {
     client.set_on_message(Some(Box::new(
         move |_: &EventClient, msg: wasm_sockets::Message| match msg {
             wasm_sockets::Message::Text(s) => {
-                error!("received text message from websocket: {:?}", s);
+                error!("received text message from websocket:", s);
             }
             wasm_sockets::Message::Binary(b) => {
                 send(Some(Msg::ReceiveWebSocketMessage(b)));
@@ -108,18 +128,44 @@ fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
 
     orders
         .stream(streams::interval(1000, || Msg::OnRenderTick))
+        .stream(streams::interval(
+            service_worker::UPDATE_CHECK_MS,
+            || Msg::CheckForUpdate,
+        ))
+        .stream(streams::window_event(Ev::PageHide, |_| Msg::Flush))
+        .stream(streams::document_event(Ev::VisibilityChange, |_| {
+            Msg::VisibilityChanged
+        }))
+        .stream(streams::window_event(Ev::Online, |_| Msg::NetworkChanged))
+        .stream(streams::window_event(Ev::Offline, |_| Msg::NetworkChanged))
         .subscribe(Msg::UrlChanged);
-    let document = Document::load();
+    service_worker::register(orders);
+    let tabs = Tabs::open(orders);
+    // the websocket connects once the saved document is loaded
+    Storage::open(orders);
+    let document = Document::default();
     let page = Page::init(url.clone(), &document, orders);
 
-    let web_socket = create_websocket(orders);
+    let encryption = auth::document_id().and_then(|id| Encryption::load(&id));
 
     Model {
         global: GlobalModel {
             document,
+            storage: Storage::default(),
+            tabs,
             base_url: url.to_hash_base_url(),
-            web_socket,
-            web_socket_reconnector: None,
+            web_socket: None,
+            sync_status: SyncStatus::default(),
+            seen_heads: Vec::new(),
+            flush: None,
+            reconnect: Reconnect::default(),
+            session_expired: false,
+            encryption,
+            locked: None,
+            sync_error: None,
+            presence: Presences::load(),
+            service_worker: None,
+            update_available: false,
         },
         page,
     }
@@ -131,9 +177,29 @@ fn init(url: Url, orders: &mut impl Orders<Msg>) -> Model {
 
 pub struct GlobalModel {
     document: Document,
+    storage: Storage,
+    tabs: Tabs,
     base_url: Url,
-    web_socket: EventClient,
-    web_socket_reconnector: Option<StreamHandle>,
+    /// The connection to the server, once the saved document is loaded.
+    web_socket: Option<EventClient>,
+    sync_status: SyncStatus,
+    /// The document heads as of the last update, to notice when it changes.
+    seen_heads: Vec<ChangeHash>,
+    /// Saves and syncs the document once changes pause.
+    flush: Option<CmdHandle>,
+    reconnect: Reconnect,
+    /// Whether the server signed this device out, to explain why on the auth page.
+    session_expired: bool,
+    /// The key to encrypt the document with, when it is end-to-end encrypted.
+    encryption: Option<Encryption>,
+    /// Why syncing is stopped until the user enters a passphrase.
+    locked: Option<Locked>,
+    /// Why the server stopped syncing, such as the document going over its limits.
+    sync_error: Option<String>,
+    presence: Presences,
+    service_worker: Option<ServiceWorkerRegistration>,
+    /// Whether a new version of the app is installed and waiting for the page to reload.
+    update_available: bool,
 }
 
 pub struct Model {
@@ -146,7 +212,7 @@ pub struct Model {
 // ------ ------
 
 struct_urls!();
-impl<'a> Urls<'a> {
+impl Urls<'_> {
     #[must_use]
     pub fn home(self) -> Url {
         self.base_url()
@@ -186,20 +252,18 @@ impl Page {
     fn init(mut url: Url, document: &Document, orders: &mut impl Orders<Msg>) -> Self {
         match url.next_hash_path_part() {
             Some(VIEW_TASK) => match url.next_hash_path_part() {
-                Some(id) => match TaskId::try_from(id) {
-                    Ok(id) => {
-                        if document.get_task(&id).is_some() {
-                            Self::ViewTask(pages::view_task::init(id, orders))
-                        } else {
-                            Self::Home(pages::home::init(orders))
-                        }
+                Some(id) => {
+                    let id = TaskId::from(id);
+                    if document.get_task(&id).is_some() {
+                        Self::ViewTask(pages::view_task::init(id, orders))
+                    } else {
+                        Self::Home(pages::home::init(orders))
                     }
-                    Err(_) => Self::Home(pages::home::init(orders)),
-                },
+                }
                 None => Self::Home(pages::home::init(orders)),
             },
             Some(AUTH) => Self::Auth(pages::auth::init(orders)),
-            Some(SETTINGS) => Self::Settings(pages::settings::init()),
+            Some(SETTINGS) => Self::Settings(pages::settings::init(orders)),
             None | Some(_) => Self::Home(pages::home::init(orders)),
         }
     }
@@ -223,10 +287,31 @@ pub enum Msg {
     WebSocketOpened,
     WebSocketClosed(CloseEvent),
     WebSocketFailed,
-    ReconnectWebSocket(usize),
+    ReconnectWebSocket(u32),
+    /// Whether the session is still signed in, after the connection failed.
+    SessionChecked(Option<u16>),
+    VisibilityChanged,
+    NetworkChanged,
+    ToggleSyncStatus,
+    ForceResync,
     SendWebSocketMessage(Vec<u8>),
     ReceiveWebSocketMessage(Vec<u8>),
 
+    /// Save and sync any changes to the document now.
+    Flush,
+
+    StorageOpened(storage::Opened),
+    StorageSaved(storage::Saved),
+    StorageFailed(String),
+    TabMessage(TabMessage),
+    /// This tab is now the one syncing with the server.
+    BecameLeader,
+
+    ServiceWorkerRegistered(ServiceWorkerRegistration),
+    CheckForUpdate,
+    UpdateAvailable,
+    ApplyUpdate,
+
     GoAuth,
     GoSettings,
 }
@@ -234,6 +319,17 @@ pub enum Msg {
 #[allow(clippy::too_many_lines)]
 #[allow(clippy::cognitive_complexity)]
 fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
+    // the server waits for replies, while local edits can wait until they pause
+    let flush_now = matches!(
+        msg,
+        Msg::Flush
+            | Msg::StorageOpened(_)
+            | Msg::WebSocketOpened
+            | Msg::ReceiveWebSocketMessage(_)
+            | Msg::TabMessage(_)
+    )
+        // the page may be closed without another event once hidden
+        || (matches!(msg, Msg::VisibilityChanged) && document().hidden());
     match msg {
         Msg::SelectTask(None) => {
             orders.request_url(Urls::new(&model.global.base_url).home());
@@ -252,8 +348,81 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             orders.request_url(Urls::new(&model.global.base_url).settings());
         }
         Msg::OnRenderTick => { /* just re-render to update the ages */ }
+        Msg::Flush => model.global.flush = None,
+        Msg::ServiceWorkerRegistered(registration) => {
+            model.global.service_worker = Some(registration);
+        }
+        Msg::CheckForUpdate => {
+            if let Some(registration) = &model.global.service_worker {
+                service_worker::check_for_update(registration);
+            }
+        }
+        Msg::UpdateAvailable => model.global.update_available = true,
+        Msg::ApplyUpdate => {
+            if let Some(registration) = &model.global.service_worker {
+                service_worker::apply_update(registration);
+            }
+        }
+        Msg::StorageOpened(opened) => {
+            let chunks = model.global.storage.opened(opened);
+            if model.global.document.load(&chunks) {
+                model.global.storage.compact();
+            }
+            // the task in the url may have only just been loaded
+            orders.send_msg(Msg::UrlChanged(subs::UrlChanged(Url::current())));
+            if model.global.tabs.leader {
+                model.global.web_socket =
+                    Some(create_websocket(orders, model.global.encryption.is_some()));
+            }
+        }
+        Msg::StorageSaved(saved) => {
+            model.global.storage.saved(&saved);
+            model.global.tabs.send(&TabMessage::saved(&saved));
+        }
+        Msg::StorageFailed(err) => model.global.storage.failed(err),
+        Msg::TabMessage(TabMessage::Saved {
+            key,
+            replaced,
+            chunk,
+        }) => {
+            let Some(chunk) = tabs::decode_chunk(&chunk) else {
+                return;
+            };
+            if key.is_some() {
+                model.global.document.apply_saved(&chunk);
+            } else {
+                model.global.document.apply_changes(&chunk);
+            }
+            model.global.storage.saved(&storage::Saved {
+                key,
+                replaced,
+                compacted: false,
+                chunk: Vec::new(),
+            });
+        }
+        Msg::TabMessage(_) if !model.global.tabs.leader => {}
+        Msg::TabMessage(TabMessage::Reconnect) => {
+            // the other tab may have changed the passphrase
+            model.global.encryption = auth::document_id().and_then(|id| Encryption::load(&id));
+            model.global.locked = None;
+            orders.send_msg(Msg::ReconnectWebSocket(0));
+        }
+        Msg::TabMessage(TabMessage::Resync) => {
+            orders.send_msg(Msg::ForceResync);
+        }
+        Msg::BecameLeader => {
+            log!("Syncing with the server from this tab");
+            model.global.tabs.leader = true;
+            if model.global.storage.loaded {
+                orders.send_msg(Msg::ReconnectWebSocket(0));
+            }
+        }
         Msg::UrlChanged(subs::UrlChanged(url)) => {
             model.page = Page::init(url, &model.global.document, orders);
+            model.global.presence.view(match &model.page {
+                Page::ViewTask(lm) => Some(lm.selected_task.clone()),
+                Page::Home(_) | Page::Auth(_) | Page::Settings(_) => None,
+            });
         }
         Msg::ViewTask(msg) => {
             if let Page::ViewTask(lm) = &mut model.page {
@@ -276,7 +445,14 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             }
         }
         Msg::WebSocketOpened => {
-            model.global.web_socket_reconnector = None;
+            model.global.reconnect.stop();
+            model.global.session_expired = false;
+            model.global.sync_error = None;
+            model.global.sync_status.error = None;
+            if let Some(encryption) = &mut model.global.encryption {
+                // wait for the server to say which key the document uses
+                encryption.ready = false;
+            }
             log!("WebSocket connection is open now");
         }
         Msg::WebSocketClosed(close_event) => {
@@ -287,36 +463,122 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             log!("Reason:", close_event.reason());
             log!("==================");
 
+            model.global.presence.disconnected();
             if !close_event.was_clean() {
-                // don't retry this
-                // TODO: filter it up in the UI later
-                model.global.web_socket_reconnector = None;
+                model.global.sync_status.error = Some(format!(
+                    "The connection closed unexpectedly (code {})",
+                    close_event.code()
+                ));
+            }
+
+            if let Some(encryption) = &mut model.global.encryption {
+                encryption.ready = false;
+            }
+            if close_event.code() == CLOSE_DOCUMENT_ENCRYPTED {
+                model.global.locked = Some(Locked::PassphraseRequired);
+            }
+            if close_event.code() == CLOSE_LIMIT_EXCEEDED {
+                // reconnecting would only send the same changes again
+                model.global.sync_error = Some(close_event.reason());
+            }
+
+            match close_event.code() {
+                // the server will be back shortly
+                CLOSE_SERVER_RESTARTING => model.global.reconnect.schedule(orders),
+                // checking also clears the ended session's cookies
+                CLOSE_SESSION_ENDED => {
+                    orders.perform_cmd(check_session());
+                }
+                // a rejected upgrade looks the same as an unreachable server, so ask which
+                _ if !close_event.was_clean() && Provider::load_from_session().is_some() => {
+                    orders.perform_cmd(check_session());
+                }
+                _ => model.global.reconnect.stop(),
             }
         }
         Msg::WebSocketFailed => {
+            // the connection closes straight after, which decides whether to retry
             log!("WebSocket failed");
-            if model.global.web_socket_reconnector.is_none() {
-                model.global.web_socket_reconnector = Some(
-                    orders.stream_with_handle(streams::backoff(None, Msg::ReconnectWebSocket)),
-                );
+            model.global.sync_status.error = Some("Couldn't reach the server".to_owned());
+        }
+        Msg::SessionChecked(Some(401)) => session_expired(&mut model.global, orders),
+        Msg::SessionChecked(status) => {
+            log!("Session check:", status);
+            model.global.reconnect.schedule(orders);
+        }
+        Msg::VisibilityChanged | Msg::NetworkChanged => {
+            if reconnect::can_connect() {
+                model.global.reconnect.resume(orders);
+            } else {
+                model.global.reconnect.pause();
             }
         }
-        Msg::ReconnectWebSocket(retries) => {
-            log!("Reconnect attempt:", retries);
-            model.global.web_socket = create_websocket(orders);
+        Msg::ReconnectWebSocket(attempt) => {
+            log!("Reconnect attempt:", attempt);
+            model.global.reconnect.started();
+            if !model.global.tabs.leader {
+                model.global.tabs.send(&TabMessage::Reconnect);
+                return;
+            }
+            if !model.global.storage.loaded {
+                return;
+            }
+            model.global.presence.disconnected();
+            if let Some(mut web_socket) = model.global.web_socket.take() {
+                // events from the old connection would be mistaken for the new one's
+                web_socket.set_on_error(None);
+                web_socket.set_on_connection(None);
+                web_socket.set_on_close(None);
+                web_socket.set_on_message(None);
+                let _ = web_socket.close();
+            }
+            model.global.web_socket =
+                Some(create_websocket(orders, model.global.encryption.is_some()));
+        }
+        Msg::ToggleSyncStatus => model.global.sync_status.open = !model.global.sync_status.open,
+        Msg::ForceResync if !model.global.tabs.leader => {
+            model.global.tabs.send(&TabMessage::Resync);
+        }
+        Msg::ForceResync => {
+            log!("Forcing a resync");
+            model.global.document.reset_sync();
+            if let Some(encryption) = &mut model.global.encryption {
+                encryption.last_seq = 0;
+                encryption.save();
+            }
+            model.global.sync_status.pending = None;
+            orders.send_msg(Msg::ReconnectWebSocket(0));
         }
         Msg::SendWebSocketMessage(message) => {
-            if let Err(err) = model.global.web_socket.send_binary(message) {
+            let Some(web_socket) = &model.global.web_socket else {
+                return;
+            };
+            if let Err(err) = web_socket.send_binary(message) {
                 log!("Failed to send websocket message:", err);
             }
         }
+        Msg::ReceiveWebSocketMessage(message) if model.global.encryption.is_some() => {
+            match EncryptedSyncMessage::try_from(message.as_slice()) {
+                Ok(message) => receive_encrypted_message(message, &mut model.global, orders),
+                Err(err) => {
+                    log!(format!(
+                        "Failed to decode websocket encrypted sync message: {:?}",
+                        err
+                    ));
+                }
+            }
+        }
         Msg::ReceiveWebSocketMessage(message) => {
             match SyncMessage::try_from(&message) {
                 Ok(message) => match message {
                     SyncMessage::Message(m) => {
                         log!("Applying sync message");
                         model.global.document.receive_sync_message(&m);
+                        let pending = pending_changes(&model.global);
+                        model.global.sync_status.heard(pending);
                     }
+                    SyncMessage::Peers(peers) => model.global.presence.peers = peers,
+                    SyncMessage::Presence(_) => log!("Ignoring presence from server"),
                 },
                 Err(err) => {
                     log!(format!(
@@ -328,17 +590,231 @@ fn update(msg: Msg, model: &mut Model, orders: &mut impl Orders<Msg>) {
             log!("Received ws message");
         }
     }
-    model.global.document.save();
-    if let Some(msg) = model.global.document.generate_sync_message() {
-        let sync_message = SyncMessage::Message(msg);
-        match Vec::try_from(sync_message) {
-            Ok(bytes) => {
-                log!("sending sync message");
-                orders.send_msg(Msg::SendWebSocketMessage(bytes));
+
+    let heads = model.global.document.heads();
+    if heads != model.global.seen_heads {
+        model.global.seen_heads = heads;
+        // replacing the handle restarts the wait
+        model.global.flush =
+            Some(orders.perform_cmd_with_handle(cmds::timeout(FLUSH_DELAY_MS, || Msg::Flush)));
+    }
+    if flush_now {
+        flush(&mut model.global, orders);
+    }
+    if model.global.encryption.is_none() {
+        let connected = model.global.web_socket.as_ref().is_some_and(|web_socket| {
+            matches!(*web_socket.status.borrow(), ConnectionStatus::Connected)
+        });
+        if connected {
+            if let Some(presence) = model.global.presence.message() {
+                send_message(presence, orders);
             }
-            Err(err) => {
-                log!(format!("Failed to serialize sync message {:?}", err));
+        }
+    }
+}
+
+/// Save the changes to the document and send them to the server.
+fn flush(global: &mut GlobalModel, orders: &mut impl Orders<Msg>) {
+    global.storage.save(&mut global.document, orders);
+    if !global.tabs.leader {
+        // the syncing tab sends the changes on once it has them
+        return;
+    }
+    if let Some(encryption) = &mut global.encryption {
+        // hold back changes while a new key is being agreed with the server, and until the last
+        // push is acknowledged so the next only has what it didn't
+        if encryption.ready && encryption.rotation.is_none() && encryption.pushing.is_none() {
+            let changes = global.document.changes_since(&encryption.synced_heads);
+            if !changes.is_empty() {
+                log!("pushing encrypted changes");
+                let blob = encryption.encrypt(&changes);
+                encryption.pushing = Some(global.document.heads());
+                send_encrypted_message(EncryptedSyncMessage::Push { blobs: vec![blob] }, orders);
+            }
+        }
+    } else if let Some(msg) = global.document.generate_sync_message() {
+        send_message(SyncMessage::Message(msg), orders);
+    }
+    let pending = pending_changes(global);
+    global.sync_status.changed(pending);
+}
+
+/// How many local changes the server doesn't have yet.
+fn pending_changes(global: &GlobalModel) -> usize {
+    global.encryption.as_ref().map_or_else(
+        || global.document.unsynced_changes(),
+        |encryption| {
+            global
+                .document
+                .count_changes_since(&encryption.synced_heads)
+        },
+    )
+}
+
+/// Ask the server whether the session is still signed in, giving the response status if it
+/// answered.
+#[allow(clippy::future_not_send)]
+async fn check_session() -> Msg {
+    let status = gloo_net::http::Request::get("/auth/session")
+        .send()
+        .await
+        .ok()
+        .map(|res| res.status());
+    Msg::SessionChecked(status)
+}
+
+/// Stop syncing and send the user to sign in again, as retrying can't succeed.
+fn session_expired(global: &mut GlobalModel, orders: &mut impl Orders<Msg>) {
+    log!("Session expired");
+    global.reconnect.stop();
+    global.session_expired = true;
+    global.sync_status.error = Some("Signed out, sign in again to sync".to_owned());
+    orders.request_url(Urls::new(&global.base_url).auth());
+}
+
+fn send_message(message: SyncMessage, orders: &mut impl Orders<Msg>) {
+    match Vec::try_from(message) {
+        Ok(bytes) => {
+            log!("sending sync message");
+            orders.send_msg(Msg::SendWebSocketMessage(bytes));
+        }
+        Err(err) => {
+            log!(format!("Failed to serialize sync message {:?}", err));
+        }
+    }
+}
+
+pub fn send_encrypted_message(message: EncryptedSyncMessage, orders: &mut impl Orders<Msg>) {
+    match Vec::try_from(message) {
+        Ok(bytes) => {
+            orders.send_msg(Msg::SendWebSocketMessage(bytes));
+        }
+        Err(err) => {
+            log!(format!("Failed to serialize encrypted sync message {:?}", err));
+        }
+    }
+}
+
+#[allow(clippy::too_many_lines)]
+fn receive_encrypted_message(
+    message: EncryptedSyncMessage,
+    global: &mut GlobalModel,
+    orders: &mut impl Orders<Msg>,
+) {
+    let Some(encryption) = &mut global.encryption else {
+        return;
+    };
+    match message {
+        EncryptedSyncMessage::Welcome { key_id, .. } => {
+            if key_id.as_deref().is_some_and(|key_id| key_id != encryption.key_id()) {
+                global.locked = Some(Locked::WrongPassphrase);
+                if let Some(web_socket) = &global.web_socket {
+                    let _ = web_socket.close();
+                }
+                return;
+            }
+            if key_id.is_none() {
+                // nothing is stored yet so upload the whole document, which also has the server
+                // drop its plaintext copy
+                log!("Encrypting document on the server");
+                let snapshot = encryption.encrypt(&global.document.snapshot());
+                encryption.synced_heads = global.document.heads();
+                send_encrypted_message(
+                    EncryptedSyncMessage::Compact { up_to: 0, snapshot },
+                    orders,
+                );
+            }
+            global.locked = None;
+            encryption.ready = true;
+            // whatever was pushed on an earlier connection is pushed again if it wasn't stored
+            encryption.pushing = None;
+            send_encrypted_message(
+                EncryptedSyncMessage::Pull {
+                    after: encryption.last_seq,
+                },
+                orders,
+            );
+            let pending = pending_changes(global);
+            global.sync_status.heard(pending);
+        }
+        EncryptedSyncMessage::Pushed { seq } => {
+            log!(format!("Server stored encrypted changes up to {seq}"));
+            if let Some(heads) = encryption.pushing.take() {
+                encryption.synced_heads = heads;
+                encryption.save();
+            }
+            let pending = pending_changes(global);
+            global.sync_status.heard(pending);
+        }
+        EncryptedSyncMessage::Blobs { blobs } => {
+            for stored in blobs {
+                if encryption
+                    .rotation
+                    .as_ref()
+                    .is_some_and(|rotation| rotation.key_id() == stored.blob.key_id)
+                {
+                    log!("Passphrase changed");
+                    encryption.complete_rotation();
+                }
+                if stored.blob.key_id != encryption.key_id() {
+                    // the passphrase was changed on another device, so stop before these blobs
+                    // and pull them again once the new passphrase is entered
+                    global.locked = Some(Locked::PassphraseChanged);
+                    encryption.ready = false;
+                    if let Some(web_socket) = &global.web_socket {
+                        let _ = web_socket.close();
+                    }
+                    break;
+                }
+                let synced = global.document.heads() == encryption.synced_heads;
+                if let Some(changes) = encryption.decrypt(&stored.blob) {
+                    global.document.apply_changes(&changes);
+                }
+                if synced {
+                    encryption.synced_heads = global.document.heads();
+                }
+                encryption.last_seq = encryption.last_seq.max(stored.seq);
             }
+            encryption.save();
+            let pending = pending_changes(global);
+            global.sync_status.heard(pending);
+        }
+        EncryptedSyncMessage::Rejected { error } => match error {
+            EncryptedSyncError::KeyMismatch => {
+                global.locked = Some(Locked::PassphraseChanged);
+                encryption.ready = false;
+                if let Some(web_socket) = &global.web_socket {
+                    let _ = web_socket.close();
+                }
+            }
+            EncryptedSyncError::OutOfDate => {
+                encryption.rotation = None;
+                window()
+                    .alert_with_message(
+                        "Another device made changes while changing the passphrase, try again.",
+                    )
+                    .unwrap_or_else(|e| log!(e));
+            }
+            EncryptedSyncError::TooLarge => {
+                // nothing was stored, so removing tasks can be pushed next
+                encryption.pushing = None;
+                window()
+                    .alert_with_message(
+                        "The document is too large for the server, remove some tasks or change \
+                        the passphrase to compact it.",
+                    )
+                    .unwrap_or_else(|e| log!(e));
+            }
+            EncryptedSyncError::NotEncrypted => {
+                // reconnecting sends the snapshot that encrypts the document first
+                encryption.ready = false;
+                orders.send_msg(Msg::ReconnectWebSocket(0));
+            }
+        },
+        EncryptedSyncMessage::Pull { .. }
+        | EncryptedSyncMessage::Push { .. }
+        | EncryptedSyncMessage::Compact { .. } => {
+            log!("Ignoring client message from server");
         }
     }
 }
@@ -364,22 +840,42 @@ fn view_titlebar(model: &Model) -> Node<Msg> {
     let signed_in = Provider::load_from_session().is_some();
     let account_string = if signed_in { "Account" } else { "Sign in" };
 
-    let connection_string = if signed_in {
-        match *model.global.web_socket.status.borrow() {
-            wasm_sockets::ConnectionStatus::Connecting => "Connecting",
-            wasm_sockets::ConnectionStatus::Connected => "Connected",
-            wasm_sockets::ConnectionStatus::Error
-            | wasm_sockets::ConnectionStatus::Disconnected => "Disconnected",
-        }
+    let connection_string = if model.global.locked.is_some() {
+        "Locked"
+    } else if model.global.sync_error.is_some() {
+        "Sync stopped"
+    } else if signed_in && !model.global.tabs.leader {
+        "Syncing in another tab"
+    } else if signed_in {
+        model
+            .global
+            .web_socket
+            .as_ref()
+            .map_or("Loading", |web_socket| match *web_socket.status.borrow() {
+                wasm_sockets::ConnectionStatus::Connecting => "Connecting",
+                wasm_sockets::ConnectionStatus::Connected => "Connected",
+                wasm_sockets::ConnectionStatus::Error
+                | wasm_sockets::ConnectionStatus::Disconnected => "Disconnected",
+            })
     } else {
         "Sign in before syncing"
     };
     let connection = span![
         attrs! {
-            At::Title => "Click to reconnect",
+            At::Title => match (model.global.locked, &model.global.sync_error) {
+                (Some(locked), _) => locked.message(),
+                (None, Some(error)) => error,
+                (None, None) => "Click for sync status",
+            },
         },
-        connection_string
+        connection_string,
+        model
+            .global
+            .sync_status
+            .summary()
+            .map(|summary| format!(" ({summary})")),
     ];
+    let peers = &model.global.presence.peers;
     div![
         C!["flex", "flex-row", "justify-between"],
         div![
@@ -388,13 +884,38 @@ fn view_titlebar(model: &Model) -> Node<Msg> {
                 C!["bg-gray-200", "py-2", "px-4", "m-2", "hover:bg-gray-300",],
                 attrs! {At::Href => "#"},
                 "TaskNet"
-            ]
+            ],
+            IF!(!peers.is_empty() => span![
+                C!["py-2", "px-4", "m-2"],
+                attrs! {
+                    At::Title => peers
+                        .iter()
+                        .map(|peer| peer.presence.device.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                },
+                if peers.len() == 1 {
+                    format!("{} is online", peers[0].presence.device)
+                } else {
+                    format!("{} other devices online", peers.len())
+                }
+            ]),
+            model.global.storage.error.as_ref().map(|error| span![
+                C!["py-2", "px-4", "m-2", "text-red-700"],
+                attrs! {
+                    At::Title => format!(
+                        "{error}, so changes are only kept while this page is open or once synced"
+                    ),
+                },
+                "Not saved on this device"
+            ]),
         ],
         nav![
-            C!["flex", "flex-row", "justify-end"],
+            C!["flex", "flex-row", "justify-end", "relative"],
+            IF!(model.global.update_available => view_button_str("Update available", Msg::ApplyUpdate)),
             view_button(
                 connection,
-                Msg::ReconnectWebSocket(0),
+                Msg::ToggleSyncStatus,
                 &ButtonOptions {
                     disabled: !signed_in
                 }
@@ -402,6 +923,7 @@ fn view_titlebar(model: &Model) -> Node<Msg> {
             view_button_str(account_string, Msg::GoAuth),
             view_button_str("Settings", Msg::GoSettings),
             view_button_str("Create", Msg::CreateTask),
+            sync_status::view(&model.global),
         ]
     ]
 }
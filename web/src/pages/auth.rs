@@ -1,15 +1,42 @@
+use std::convert::TryFrom;
+
 use crate::auth::cookies;
 #[allow(clippy::wildcard_imports)]
 use seed::{prelude::*, *};
 
-use crate::{auth::Provider, GlobalModel, Msg as GMsg};
+use crate::{
+    auth::Provider,
+    components::{duration_string, view_button_str},
+    GlobalModel, Msg as GMsg,
+};
 use gloo_console::log;
 use gloo_net::http::Request;
-use tasknet_shared::{cookies::DOCUMENT_ID_COOKIE, providers::Providers};
+use tasknet_shared::{
+    cookies::DOCUMENT_ID_COOKIE,
+    providers::{LocalAccount, ProviderLocal, Providers, Registration},
+    sessions::SessionInfo,
+};
 
 pub fn init(orders: &mut impl Orders<GMsg>) -> Model {
     let auth_provider = Provider::load_from_session();
 
+    if auth_provider.is_some() {
+        orders.perform_cmd(fetch_sessions());
+    }
+
+    if matches!(auth_provider, Some(Provider::Local)) {
+        orders.perform_cmd(async move {
+            let account_request = Request::get("/auth/local/account");
+            let res = account_request.send().await;
+            if let Ok(res) = res {
+                if let Ok(account) = res.json::<LocalAccount>().await {
+                    return Some(GMsg::Auth(Msg::FetchedLocalAccount(account)));
+                }
+            }
+            None
+        });
+    }
+
     orders.perform_cmd(async move {
         let providers_request = Request::get("/auth/providers");
         let res = providers_request.send().await;
@@ -24,7 +51,21 @@ pub fn init(orders: &mut impl Orders<GMsg>) -> Model {
         auth_provider,
         providers: None,
         public_doc_id: String::new(),
+        local_account: None,
+        sessions: Vec::new(),
+    }
+}
+
+#[allow(clippy::future_not_send)]
+async fn fetch_sessions() -> Option<GMsg> {
+    let sessions_request = Request::get("/auth/sessions");
+    let res = sessions_request.send().await;
+    if let Ok(res) = res {
+        if let Ok(sessions) = res.json::<Vec<SessionInfo>>().await {
+            return Some(GMsg::Auth(Msg::FetchedSessions(sessions)));
+        }
     }
+    None
 }
 
 #[derive(Debug)]
@@ -32,11 +73,17 @@ pub struct Model {
     auth_provider: Option<Provider>,
     providers: Option<Providers>,
     public_doc_id: String,
+    local_account: Option<LocalAccount>,
+    sessions: Vec<SessionInfo>,
 }
 
 #[derive(Clone)]
 pub enum Msg {
     FetchedProviders(Providers),
+    FetchedLocalAccount(LocalAccount),
+    FetchedSessions(Vec<SessionInfo>),
+    RevokeSession(SessionInfo),
+    RevokedSession(SessionInfo),
     PublicDocIdChanged(String),
 }
 
@@ -44,19 +91,55 @@ pub fn update(
     msg: Msg,
     _global_model: &mut GlobalModel,
     model: &mut Model,
-    _orders: &mut impl Orders<GMsg>,
+    orders: &mut impl Orders<GMsg>,
 ) {
     match msg {
         Msg::FetchedProviders(providers) => {
             model.providers = Some(providers);
         }
+        Msg::FetchedLocalAccount(account) => {
+            model.local_account = Some(account);
+        }
+        Msg::FetchedSessions(sessions) => {
+            model.sessions = sessions;
+        }
+        Msg::RevokeSession(session) => {
+            orders.perform_cmd(async move {
+                let url = format!(
+                    "/auth/sessions/{}",
+                    String::from(js_sys::encode_uri_component(&session.id))
+                );
+                match Request::delete(&url).send().await {
+                    Ok(res) if res.ok() => Some(GMsg::Auth(Msg::RevokedSession(session))),
+                    Ok(res) => {
+                        log!("Failed to revoke session:", res.status());
+                        None
+                    }
+                    Err(err) => {
+                        log!(format!("Failed to revoke session: {:?}", err));
+                        None
+                    }
+                }
+            });
+        }
+        Msg::RevokedSession(session) => {
+            if session.current {
+                // our own session is gone so start from scratch
+                window()
+                    .location()
+                    .set_href("/")
+                    .unwrap_or_else(|e| log!(e));
+            } else {
+                orders.perform_cmd(fetch_sessions());
+            }
+        }
         Msg::PublicDocIdChanged(new_id) => {
             model.public_doc_id = new_id;
         }
     }
 }
 
-pub fn view(_global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
+pub fn view(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
     let public_provider = form![
         C!["py-1", "px-2", "m-1"],
         Provider::Public.logo(),
@@ -81,13 +164,6 @@ pub fn view(_global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
             "Create a new public document",
         ],
     ];
-    let doc_id = cookies()
-        .and_then(|cookie_jar| {
-            cookie_jar
-                .get(DOCUMENT_ID_COOKIE)
-                .map(|c| c.value().to_owned())
-        })
-        .unwrap_or_else(|| "NOT FOUND".to_owned());
     div![
         C![
             "flex",
@@ -98,8 +174,13 @@ pub fn view(_global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
             "border-4",
             "border-gray-200"
         ],
-        if let Some(providers) = &model.providers {
-            if model.auth_provider.is_none() {
+        IF!(global_model.session_expired => div![
+            C!["py-1", "px-2", "m-1", "text-red-700"],
+            "Your session has ended, sign in again to keep syncing"
+        ]),
+        match (&model.providers, &model.auth_provider) {
+            (None, _) => div![C!["py-1", "px-2", "m-1"], "No auth providers available"],
+            (Some(providers), None) => {
                 log!(format!("providers: {:?}", providers));
                 div![
                     IF!(providers.google.enabled => a![
@@ -108,38 +189,194 @@ pub fn view(_global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
                         Provider::Google.logo(),
                         "Sign in with Google",
                     ]),
+                    IF!(providers.local.enabled => view_local_sign_in(&providers.local)),
                     IF!(providers.public.enabled => public_provider)
                 ]
-            } else {
-                let provider = model.auth_provider.as_ref().unwrap();
-                let provider_block = match provider {
-                    Provider::Public => div![a![
-                        C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
-                        attrs! {At::Href => "/auth/public/sign_out"},
-                        provider.logo(),
-                        "Sign out from Public",
-                    ]],
-                    Provider::Google => {
-                        div![a![
-                            C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
-                            attrs! {At::Href => "/auth/google/sign_out"},
-                            provider.logo(),
-                            "Sign out with Google",
-                        ],]
-                    }
-                };
-                div![
-                    div![
-                        C!["py-1", "px-2", "m-1"],
-                        "Current document ID",
-                        br!(),
-                        doc_id
-                    ],
-                    provider_block,
-                ]
             }
-        } else {
-            div![C!["py-1", "px-2", "m-1"], "No auth providers available"]
+            (Some(_), Some(provider)) => view_signed_in(provider, model),
         }
     ]
 }
+
+fn current_document_id() -> String {
+    cookies()
+        .and_then(|cookie_jar| {
+            cookie_jar
+                .get(DOCUMENT_ID_COOKIE)
+                .map(|c| c.value().to_owned())
+        })
+        .unwrap_or_else(|| "NOT FOUND".to_owned())
+}
+
+fn view_signed_in(provider: &Provider, model: &Model) -> Node<GMsg> {
+    let provider_block = match provider {
+        Provider::Public => div![a![
+            C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
+            attrs! {At::Href => "/auth/public/sign_out"},
+            provider.logo(),
+            "Sign out from Public",
+        ]],
+        Provider::Google => {
+            div![a![
+                C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
+                attrs! {At::Href => "/auth/google/sign_out"},
+                provider.logo(),
+                "Sign out with Google",
+            ],]
+        }
+        Provider::Local => div![
+            model.local_account.as_ref().map(view_local_account),
+            a![
+                C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
+                attrs! {At::Href => "/auth/local/sign_out"},
+                provider.logo(),
+                "Sign out",
+            ]
+        ],
+    };
+    div![
+        div![
+            C!["py-1", "px-2", "m-1"],
+            "Current document ID",
+            br!(),
+            current_document_id()
+        ],
+        provider_block,
+        view_sessions(&model.sessions),
+    ]
+}
+
+fn view_password_input(label: &str, name: &str) -> Vec<Node<GMsg>> {
+    vec![
+        label![label],
+        input![attrs! {
+            At::Type => "password",
+            At::Name => name,
+            At::Required => AtValue::None,
+        }],
+        br!(),
+    ]
+}
+
+fn view_submit(text: &str) -> Node<GMsg> {
+    button![
+        C!["bg-gray-200", "py-1", "px-2", "m-1", "hover:bg-gray-300"],
+        attrs! {At::Type => "submit"},
+        text,
+    ]
+}
+
+fn view_username_input() -> Vec<Node<GMsg>> {
+    vec![
+        label!["Username"],
+        input![attrs! {
+            At::Name => "username",
+            At::AutoComplete => "username",
+            At::Required => AtValue::None,
+        }],
+        br!(),
+    ]
+}
+
+fn view_local_sign_in(local: &ProviderLocal) -> Node<GMsg> {
+    div![
+        form![
+            C!["py-1", "px-2", "m-1"],
+            attrs! {At::Action => "/auth/local/sign_in", At::Method => "post"},
+            "Sign in with an account on this server",
+            br!(),
+            view_username_input(),
+            view_password_input("Password", "password"),
+            view_submit("Sign in"),
+        ],
+        match local.registration {
+            Registration::Disabled => empty!(),
+            Registration::Open | Registration::InviteOnly => form![
+                C!["py-1", "px-2", "m-1"],
+                attrs! {At::Action => "/auth/local/register", At::Method => "post"},
+                "Create a new account",
+                br!(),
+                view_username_input(),
+                view_password_input("Password", "password"),
+                IF!(local.registration == Registration::InviteOnly => vec![
+                    label!["Invite code"],
+                    input![attrs! {At::Name => "invite_code", At::Required => AtValue::None}],
+                    br!(),
+                ]),
+                view_submit("Register"),
+            ],
+        }
+    ]
+}
+
+fn view_local_account(account: &LocalAccount) -> Node<GMsg> {
+    div![
+        div![
+            C!["py-1", "px-2", "m-1"],
+            "Signed in as",
+            br!(),
+            &account.username
+        ],
+        form![
+            C!["py-1", "px-2", "m-1"],
+            attrs! {At::Action => "/auth/local/change_password", At::Method => "post"},
+            "Change password",
+            br!(),
+            view_password_input("Current password", "current_password"),
+            view_password_input("New password", "new_password"),
+            view_submit("Change password"),
+        ],
+        IF!(account.admin => form![
+            C!["py-1", "px-2", "m-1"],
+            attrs! {At::Action => "/auth/local/reset_password", At::Method => "post"},
+            "Reset the password of another account",
+            br!(),
+            view_username_input(),
+            view_password_input("New password", "new_password"),
+            view_submit("Reset password"),
+        ]),
+    ]
+}
+
+fn view_sessions(sessions: &[SessionInfo]) -> Node<GMsg> {
+    let now = chrono::offset::Utc::now();
+    let ago = |secs: u64| {
+        let time =
+            chrono::NaiveDateTime::from_timestamp_opt(i64::try_from(secs).unwrap_or_default(), 0)
+                .unwrap_or_default();
+        duration_string(now.naive_utc().signed_duration_since(time))
+    };
+    div![
+        C!["py-1", "px-2", "m-1"],
+        "Signed in devices",
+        table![
+            C!["table-auto", "w-full"],
+            tr![
+                th!["Device"],
+                th!["Signed in"],
+                th!["Last seen"],
+                th!["Syncing"],
+                th![],
+            ],
+            sessions.iter().map(|session| {
+                let revoke = session.clone();
+                tr![
+                    td![
+                        session.user_agent.as_deref().unwrap_or("Unknown"),
+                        IF!(session.current => " (this device)"),
+                    ],
+                    td![C!["text-center"], ago(session.created)],
+                    td![C!["text-center"], ago(session.last_seen)],
+                    td![
+                        C!["text-center"],
+                        if session.websockets > 0 { "Yes" } else { "No" }
+                    ],
+                    td![view_button_str(
+                        "Sign out",
+                        GMsg::Auth(Msg::RevokeSession(revoke))
+                    )],
+                ]
+            }),
+        ]
+    ]
+}
@@ -9,10 +9,11 @@ use gloo_storage::{errors::StorageError, LocalStorage, Storage};
 #[allow(clippy::wildcard_imports)]
 use seed::{prelude::*, *};
 
+use tasknet_shared::task::{DateTime, Priority, Status, Task, TaskId};
+
 use crate::{
     components::{duration_string, view_button_str, view_checkbox, view_text_input},
     document::Document,
-    task::{DateTime, Priority, Status, Task, TaskId},
     urgency, Filters, GlobalModel, Msg as GMsg,
 };
 
@@ -24,7 +25,7 @@ pub fn init(orders: &mut impl Orders<GMsg>) -> Model {
         let active_element = seed::document()
             .active_element()
             .map(|e| e.tag_name())
-            .map_or(false, |e| e != "BODY");
+            .is_some_and(|e| e != "BODY");
         if active_element {
             // ignore key presses when we have a focused element
             return None;
@@ -234,13 +235,14 @@ pub fn update(msg: Msg, model: &mut Model, _orders: &mut impl Orders<GMsg>) {
 pub fn view(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
     div![
         view_filters(model, &global_model.document),
-        view_tasks(&global_model.document, model),
+        view_tasks(global_model, model),
     ]
 }
 
 #[allow(clippy::too_many_lines)]
-fn view_tasks(document: &Document, model: &Model) -> Node<GMsg> {
-    let mut tasks: Vec<_> = document
+fn view_tasks(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
+    let mut tasks: Vec<_> = global_model
+        .document
         .tasks()
         .values()
         .filter(|t| model.filters.filter_task(t))
@@ -270,6 +272,11 @@ fn view_tasks(document: &Document, model: &Model) -> Node<GMsg> {
             active: t.start().is_some(),
             due: t.due().clone(),
             scheduled: t.scheduled().clone(),
+            viewers: global_model
+                .presence
+                .viewing(t.id())
+                .map(|presence| presence.device.clone())
+                .collect(),
         })
         .collect::<Vec<_>>();
 
@@ -355,7 +362,14 @@ fn view_tasks(document: &Document, model: &Model) -> Node<GMsg> {
                             })
                     })
                     ]),
-                    td![C!["border-l-2", "text-left", "px-2"], &t.description],
+                    td![
+                        C!["border-l-2", "text-left", "px-2"],
+                        &t.description,
+                        IF!(!t.viewers.is_empty() => span![
+                            C!["text-orange-700", "ml-2"],
+                            format!("({})", t.viewers.join(", "))
+                        ])
+                    ],
                     td![
                         C!["border-l-2", "text-center", "px-2"],
                         t.urgency.map_or_else(||empty![], |urgency| {
@@ -438,6 +452,8 @@ struct ViewableTask {
     urgency: Option<f64>,
     due: Option<DateTime>,
     scheduled: Option<DateTime>,
+    /// The other devices with the task open.
+    viewers: Vec<String>,
 }
 
 #[allow(clippy::too_many_lines)]
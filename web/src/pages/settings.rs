@@ -1,26 +1,119 @@
 use gloo_console::log;
+use gloo_net::http::Request;
 use std::collections::HashMap;
 
 #[allow(clippy::wildcard_imports)]
 use seed::{prelude::*, *};
 
-use crate::{
-    components::view_button_str,
+use tasknet_shared::{
+    limits::Quota,
+    presence::MAX_DEVICE_NAME_LENGTH,
+    reminders::{ConfirmEmail, QuietHours, ReminderMode, ReminderSettings, ReminderStatus},
+    sync::EncryptedSyncMessage,
     task::{Task, TaskId},
+};
+
+use crate::{
+    auth::{self, Provider},
+    components::{view_button_str, view_checkbox},
+    encryption::Encryption,
+    send_encrypted_message,
     GlobalModel, Msg as GMsg,
 };
 
-pub fn init() -> Model {
-    Model {}
+const MIN_PASSPHRASE_LENGTH: usize = 8;
+
+pub fn init(orders: &mut impl Orders<GMsg>) -> Model {
+    if Provider::load_from_session().is_some() {
+        orders.perform_cmd(async move {
+            let quota_request = Request::get("/quota");
+            let res = quota_request.send().await;
+            if let Ok(res) = res {
+                if let Ok(quota) = res.json::<Quota>().await {
+                    return Some(GMsg::Settings(Msg::FetchedQuota(quota)));
+                }
+            }
+            None
+        });
+        orders.perform_cmd(async move {
+            // not found when the server doesn't send reminders
+            let reminders_request = Request::get("/reminders");
+            let res = reminders_request.send().await;
+            if let Ok(res) = res {
+                if let Ok(status) = res.json::<ReminderStatus>().await {
+                    return Some(GMsg::Settings(Msg::FetchedReminders(status)));
+                }
+            }
+            None
+        });
+    }
+    Model {
+        passphrase: String::new(),
+        quota: None,
+        reminders: None,
+        email_confirmed: false,
+        confirmation_code: String::new(),
+        quiet_start: String::new(),
+        quiet_end: String::new(),
+    }
 }
 
 #[derive(Debug)]
-pub struct Model {}
+pub struct Model {
+    passphrase: String,
+    quota: Option<Quota>,
+    reminders: Option<ReminderSettings>,
+    /// Whether the server has confirmed the saved email.
+    email_confirmed: bool,
+    confirmation_code: String,
+    quiet_start: String,
+    quiet_end: String,
+}
 
 #[derive(Clone)]
 pub enum Msg {
+    DeviceNameChanged(String),
     ImportTasks,
     ExportTasks,
+    PassphraseChanged(String),
+    UsePassphrase,
+    ChangePassphrase,
+    ForgetPassphrase,
+    FetchedQuota(Quota),
+    FetchedReminders(ReminderStatus),
+    ToggleReminders,
+    ReminderEmailChanged(String),
+    ToggleDigest,
+    DigestHourChanged(String),
+    QuietStartChanged(String),
+    QuietEndChanged(String),
+    SaveReminders,
+    SavedReminders(Result<ReminderStatus, String>),
+    ConfirmationCodeChanged(String),
+    ConfirmEmail,
+    ConfirmedEmail(Result<ReminderStatus, String>),
+}
+
+/// Send a request to `/reminders`, which answers with the saved settings.
+#[allow(clippy::future_not_send)]
+async fn send_reminders_request(
+    request: Result<Request, gloo_net::Error>,
+) -> Result<ReminderStatus, String> {
+    let res = request
+        .map_err(|err| err.to_string())?
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+    if !res.ok() {
+        return Err(res.text().await.unwrap_or_default());
+    }
+    res.json().await.map_err(|err| err.to_string())
+}
+
+fn alert(message: &str) {
+    window()
+        .alert_with_message(message)
+        .unwrap_or_else(|e| log!(e));
 }
 
 #[allow(clippy::too_many_lines)]
@@ -28,10 +121,11 @@ pub enum Msg {
 pub fn update(
     msg: Msg,
     global_model: &mut GlobalModel,
-    _model: &mut Model,
-    _orders: &mut impl Orders<GMsg>,
+    model: &mut Model,
+    orders: &mut impl Orders<GMsg>,
 ) {
     match msg {
+        Msg::DeviceNameChanged(name) => global_model.presence.set_device(name),
         Msg::ImportTasks => match window().prompt_with_message("Paste the tasks json here") {
             Ok(Some(content)) => match serde_json::from_str::<HashMap<TaskId, Task>>(&content) {
                 Ok(tasks) => {
@@ -68,10 +162,146 @@ pub fn update(
                 Err(e) => log!(e.to_string()),
             }
         }
+        Msg::PassphraseChanged(passphrase) => model.passphrase = passphrase,
+        Msg::UsePassphrase => {
+            if model.passphrase.len() < MIN_PASSPHRASE_LENGTH {
+                alert("Passphrases must be at least 8 characters long.");
+                return;
+            }
+            let Some(doc_id) = auth::document_id() else {
+                alert("Sign in before encrypting the document.");
+                return;
+            };
+            let encryption = Encryption::new(doc_id, &std::mem::take(&mut model.passphrase));
+            encryption.save();
+            global_model.encryption = Some(encryption);
+            global_model.locked = None;
+            orders.send_msg(GMsg::ReconnectWebSocket(0));
+        }
+        Msg::ChangePassphrase => {
+            if model.passphrase.len() < MIN_PASSPHRASE_LENGTH {
+                alert("Passphrases must be at least 8 characters long.");
+                return;
+            }
+            let Some(encryption) = &mut global_model.encryption else {
+                return;
+            };
+            if !encryption.ready {
+                alert("Connect to the server before changing the passphrase.");
+                return;
+            }
+            // replace everything on the server with a snapshot under the new key
+            let rotation = encryption.with_passphrase(&std::mem::take(&mut model.passphrase));
+            let snapshot = rotation.encrypt(&global_model.document.snapshot());
+            send_encrypted_message(
+                EncryptedSyncMessage::Compact {
+                    up_to: encryption.last_seq,
+                    snapshot,
+                },
+                orders,
+            );
+            encryption.rotation = Some(Box::new(rotation));
+        }
+        Msg::ForgetPassphrase => {
+            Encryption::forget();
+            global_model.encryption = None;
+            orders.send_msg(GMsg::ReconnectWebSocket(0));
+        }
+        Msg::FetchedQuota(quota) => model.quota = Some(quota),
+        Msg::FetchedReminders(status) => {
+            if let Some(quiet_hours) = status.settings.quiet_hours {
+                model.quiet_start = quiet_hours.start.to_string();
+                model.quiet_end = quiet_hours.end.to_string();
+            }
+            model.reminders = Some(status.settings);
+            model.email_confirmed = status.email_confirmed;
+        }
+        Msg::ToggleReminders => {
+            if let Some(settings) = &mut model.reminders {
+                settings.enabled = !settings.enabled;
+            }
+        }
+        Msg::ReminderEmailChanged(email) => {
+            if let Some(settings) = &mut model.reminders {
+                settings.email = email;
+            }
+        }
+        Msg::ToggleDigest => {
+            if let Some(settings) = &mut model.reminders {
+                settings.mode = match settings.mode {
+                    ReminderMode::Immediate => ReminderMode::Digest { hour: 8 },
+                    ReminderMode::Digest { .. } => ReminderMode::Immediate,
+                };
+            }
+        }
+        Msg::DigestHourChanged(hour) => {
+            if let (Some(settings), Ok(hour)) = (&mut model.reminders, hour.parse()) {
+                settings.mode = ReminderMode::Digest { hour };
+            }
+        }
+        Msg::QuietStartChanged(start) => model.quiet_start = start,
+        Msg::QuietEndChanged(end) => model.quiet_end = end,
+        Msg::SaveReminders => {
+            let Some(settings) = &mut model.reminders else {
+                return;
+            };
+            settings.quiet_hours = if model.quiet_start.is_empty() && model.quiet_end.is_empty() {
+                None
+            } else if let (Ok(start), Ok(end)) =
+                (model.quiet_start.parse(), model.quiet_end.parse())
+            {
+                Some(QuietHours { start, end })
+            } else {
+                alert("Quiet hours must be from 0 to 23, or both empty.");
+                return;
+            };
+            settings.utc_offset_minutes = chrono::Local::now().offset().local_minus_utc() / 60;
+            if let Err(err) = settings.validate() {
+                alert(&err);
+                return;
+            }
+            let settings = settings.clone();
+            orders.perform_cmd(async move {
+                let result =
+                    send_reminders_request(Request::put("/reminders").json(&settings)).await;
+                GMsg::Settings(Msg::SavedReminders(result))
+            });
+        }
+        Msg::SavedReminders(result) => match result {
+            Ok(status) => {
+                model.email_confirmed = status.email_confirmed;
+                if status.settings.enabled && !status.email_confirmed {
+                    alert("Reminder settings saved. Enter the code emailed to you to start getting reminders.");
+                } else {
+                    alert("Reminder settings saved.");
+                }
+            }
+            Err(err) => alert(&format!("Failed to save reminder settings: {err}")),
+        },
+        Msg::ConfirmationCodeChanged(code) => model.confirmation_code = code,
+        Msg::ConfirmEmail => {
+            let confirm = ConfirmEmail {
+                code: model.confirmation_code.clone(),
+            };
+            orders.perform_cmd(async move {
+                let result =
+                    send_reminders_request(Request::post("/reminders/confirm").json(&confirm))
+                        .await;
+                GMsg::Settings(Msg::ConfirmedEmail(result))
+            });
+        }
+        Msg::ConfirmedEmail(result) => match result {
+            Ok(status) => {
+                model.email_confirmed = status.email_confirmed;
+                model.confirmation_code.clear();
+                alert("Email confirmed, reminders will be sent to it.");
+            }
+            Err(err) => alert(&format!("Failed to confirm email: {err}")),
+        },
     }
 }
 
-pub fn view(_global_model: &GlobalModel, _model: &Model) -> Node<GMsg> {
+pub fn view(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
     div![
         C![
             "flex",
@@ -83,7 +313,174 @@ pub fn view(_global_model: &GlobalModel, _model: &Model) -> Node<GMsg> {
             "border-gray-200",
         ],
         div![C!["mx-auto"], "Settings"],
+        view_device(global_model),
         view_button_str("Import Tasks", GMsg::Settings(Msg::ImportTasks)),
         view_button_str("Export Tasks", GMsg::Settings(Msg::ExportTasks)),
+        model.quota.as_ref().map(view_quota),
+        model
+            .reminders
+            .as_ref()
+            .map(|settings| view_reminders(settings, model)),
+        view_encryption(global_model, model),
+    ]
+}
+
+fn view_device(global_model: &GlobalModel) -> Node<GMsg> {
+    div![
+        C!["flex", "flex-col"],
+        label!["Device name, shown to other devices on the same document"],
+        input![
+            attrs! {
+                At::Value => global_model.presence.device(),
+                At::MaxLength => MAX_DEVICE_NAME_LENGTH,
+            },
+            input_ev(Ev::Input, |s| GMsg::Settings(Msg::DeviceNameChanged(s)))
+        ],
+    ]
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn mebibytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn view_quota(quota: &Quota) -> Node<GMsg> {
+    let limits = &quota.limits;
+    div![
+        C!["flex", "flex-col", "mt-4"],
+        div![C!["mx-auto"], "Quota"],
+        p![format!(
+            "Storage: {} of {}",
+            mebibytes(quota.document_bytes),
+            mebibytes(limits.max_document_bytes)
+        )],
+        quota
+            .tasks
+            .map(|tasks| p![format!("Tasks: {} of {}", tasks, limits.max_tasks)]),
+        p![format!(
+            "Descriptions can be up to {} characters",
+            limits.max_description_length
+        )],
+    ]
+}
+
+fn view_reminders(settings: &ReminderSettings, model: &Model) -> Node<GMsg> {
+    let digest_hour = match settings.mode {
+        ReminderMode::Immediate => None,
+        ReminderMode::Digest { hour } => Some(hour),
+    };
+    div![
+        C!["flex", "flex-col", "mt-4"],
+        div![C!["mx-auto"], "Reminders"],
+        p!["Email reminders of tasks that are due or scheduled, even when TaskNet isn't open."],
+        view_checkbox(
+            "reminders",
+            "Send reminder emails",
+            settings.enabled,
+            GMsg::Settings(Msg::ToggleReminders)
+        ),
+        label!["Email"],
+        input![
+            attrs! {
+                At::Type => "email",
+                At::Value => settings.email,
+            },
+            input_ev(Ev::Input, |s| GMsg::Settings(Msg::ReminderEmailChanged(s)))
+        ],
+        (settings.enabled && !model.email_confirmed).then(|| {
+            div![
+                C!["flex", "flex-col"],
+                label!["Code from the confirmation email, nothing is sent until it is entered"],
+                input![
+                    attrs! {
+                        At::Value => model.confirmation_code,
+                        At::Custom("inputmode".into()) => "numeric",
+                    },
+                    input_ev(Ev::Input, |s| {
+                        GMsg::Settings(Msg::ConfirmationCodeChanged(s))
+                    })
+                ],
+                view_button_str("Confirm email", GMsg::Settings(Msg::ConfirmEmail)),
+            ]
+        }),
+        view_checkbox(
+            "digest",
+            "Send one digest a day instead",
+            digest_hour.is_some(),
+            GMsg::Settings(Msg::ToggleDigest)
+        ),
+        digest_hour.map(|hour| {
+            div![
+                label!["Digest hour"],
+                input![
+                    attrs! {
+                        At::Type => "number",
+                        At::Min => 0,
+                        At::Max => 23,
+                        At::Value => hour,
+                    },
+                    input_ev(Ev::Input, |s| GMsg::Settings(Msg::DigestHourChanged(s)))
+                ],
+            ]
+        }),
+        label!["Quiet hours, from and to"],
+        div![
+            C!["flex", "flex-row"],
+            input![
+                attrs! {
+                    At::Type => "number",
+                    At::Min => 0,
+                    At::Max => 23,
+                    At::Value => model.quiet_start,
+                },
+                input_ev(Ev::Input, |s| GMsg::Settings(Msg::QuietStartChanged(s)))
+            ],
+            input![
+                attrs! {
+                    At::Type => "number",
+                    At::Min => 0,
+                    At::Max => 23,
+                    At::Value => model.quiet_end,
+                },
+                input_ev(Ev::Input, |s| GMsg::Settings(Msg::QuietEndChanged(s)))
+            ],
+        ],
+        view_button_str("Save reminders", GMsg::Settings(Msg::SaveReminders)),
+    ]
+}
+
+fn view_encryption(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
+    div![
+        C!["flex", "flex-col", "mt-4"],
+        div![C!["mx-auto"], "End-to-end encryption"],
+        p![
+            "Encrypt the document on this device before syncing so the server can't read it. \
+            Every device needs the same passphrase, and it can't be recovered if forgotten."
+        ],
+        global_model
+            .locked
+            .map(|locked| p![C!["text-red-600"], locked.message()]),
+        label!["Passphrase"],
+        input![
+            attrs! {
+                At::Type => "password",
+                At::Value => model.passphrase,
+            },
+            input_ev(Ev::Input, |s| GMsg::Settings(Msg::PassphraseChanged(s)))
+        ],
+        if global_model.encryption.is_some() {
+            vec![
+                view_button_str("Change passphrase", GMsg::Settings(Msg::ChangePassphrase)),
+                view_button_str(
+                    "Forget passphrase on this device",
+                    GMsg::Settings(Msg::ForgetPassphrase),
+                ),
+            ]
+        } else {
+            vec![view_button_str(
+                "Use passphrase",
+                GMsg::Settings(Msg::UsePassphrase),
+            )]
+        },
     ]
 }
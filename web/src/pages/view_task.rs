@@ -4,11 +4,12 @@ use chrono::{Datelike, Timelike};
 #[allow(clippy::wildcard_imports)]
 use seed::{prelude::*, *};
 
+use tasknet_shared::task::{DateTime, Priority, Status, Task, TaskId};
+
 use crate::{
     components::{duration_string, view_button_str, view_text_input},
     document::Document,
-    task::{DateTime, Priority, Status, Task, TaskId},
-    urgency, GlobalModel, Msg as GMsg, Urls,
+    presence, urgency, GlobalModel, Msg as GMsg, Urls,
 };
 
 const ESCAPE_KEY: &str = "Escape";
@@ -26,7 +27,7 @@ pub fn init(id: TaskId, orders: &mut impl Orders<GMsg>) -> Model {
 
 #[derive(Debug)]
 pub struct Model {
-    selected_task: TaskId,
+    pub selected_task: TaskId,
 }
 
 #[derive(Clone)]
@@ -52,9 +53,12 @@ pub enum Msg {
 pub fn update(
     msg: Msg,
     global_model: &mut GlobalModel,
-    model: &mut Model,
+    model: &Model,
     orders: &mut impl Orders<GMsg>,
 ) {
+    if !matches!(msg, Msg::EscapeKey) {
+        global_model.presence.edited();
+    }
     match msg {
         Msg::SelectedTaskDescriptionChanged(new_description) => {
             global_model
@@ -101,10 +105,7 @@ pub fn update(
             global_model
                 .document
                 .change_task(&model.selected_task, |task| {
-                    task.set_priority(match Priority::try_from(new_priority) {
-                        Ok(p) => Some(p),
-                        Err(()) => None,
-                    });
+                    task.set_priority(Priority::try_from(new_priority).ok());
                 });
         }
         Msg::SelectedTaskDueDateChanged(new_date) => {
@@ -242,15 +243,15 @@ pub fn update(
                                 task.delete();
                             });
                     }
-                    Status::Deleted => match window().confirm_with_message(
-                        "Are you sure you want to permanently delete this task?",
-                    ) {
-                        Ok(true) => {
+                    Status::Deleted => {
+                        if window().confirm_with_message(
+                            "Are you sure you want to permanently delete this task?",
+                        ) == Ok(true)
+                        {
                             /* already removed from set so just don't add it back */
                             global_model.document.remove_task(&model.selected_task);
                         }
-                        Ok(false) | Err(_) => {}
-                    },
+                    }
                 }
             }
         }
@@ -297,7 +298,10 @@ pub fn view(global_model: &GlobalModel, model: &Model) -> Node<GMsg> {
         .document
         .get_task(&model.selected_task)
         .expect("the given task to exist");
-    div![view_selected_task(task, &global_model.document)]
+    div![
+        presence::view_viewing(&global_model.presence, &model.selected_task),
+        view_selected_task(task, &global_model.document)
+    ]
 }
 
 #[allow(clippy::too_many_lines)]
@@ -0,0 +1,132 @@
+use chrono::{DateTime, Utc};
+use gloo_storage::{LocalStorage, Storage};
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+use tasknet_shared::{
+    presence::{Peer, Presence},
+    sync::SyncMessage,
+    task::TaskId,
+};
+
+use crate::components::duration_string;
+
+const DEVICE_NAME_STORAGE_KEY: &str = "tasknet-device-name";
+
+/// How long edits can go without telling other devices, so they still see this one as active.
+const REFRESH_SECONDS: i64 = 30;
+
+/// What this device is doing and what the other devices on the document are doing.
+#[derive(Debug)]
+pub struct Presences {
+    local: Presence,
+    /// When the local presence was last sent, or `None` if the server doesn't have it.
+    sent: Option<DateTime<Utc>>,
+    changed: bool,
+    pub peers: Vec<Peer>,
+}
+
+impl Presences {
+    pub fn load() -> Self {
+        let device = LocalStorage::get(DEVICE_NAME_STORAGE_KEY).unwrap_or_else(|_| {
+            window()
+                .navigator()
+                .platform()
+                .ok()
+                .filter(|platform| !platform.is_empty())
+                .map_or_else(|| "Unnamed device".to_owned(), |p| format!("{p} browser"))
+        });
+        Self {
+            local: Presence {
+                device,
+                ..Presence::default()
+            },
+            sent: None,
+            changed: true,
+            peers: Vec::new(),
+        }
+    }
+
+    pub fn device(&self) -> &str {
+        &self.local.device
+    }
+
+    pub fn set_device(&mut self, device: String) {
+        LocalStorage::set(DEVICE_NAME_STORAGE_KEY, &device)
+            .expect("save device name to LocalStorage");
+        self.local.device = device;
+        self.changed = true;
+    }
+
+    /// Record the task open on this device, if any.
+    pub fn view(&mut self, task: Option<TaskId>) {
+        if self.local.viewing != task {
+            self.local.viewing = task;
+            self.local.editing = false;
+            self.changed = true;
+        }
+    }
+
+    /// Record a change to the open task.
+    pub fn edited(&mut self) {
+        let fresh = matches!(
+            self.sent,
+            Some(sent) if (Utc::now() - sent).num_seconds() < REFRESH_SECONDS
+        );
+        if !self.local.editing || !fresh {
+            self.local.editing = true;
+            self.changed = true;
+        }
+    }
+
+    /// Forget what the server knew, as a new connection starts without any presence.
+    pub fn disconnected(&mut self) {
+        self.sent = None;
+        self.peers.clear();
+    }
+
+    /// The message to send if the server doesn't have the latest presence.
+    pub fn message(&mut self) -> Option<SyncMessage> {
+        if self.sent.is_some() && !self.changed {
+            return None;
+        }
+        self.sent = Some(Utc::now());
+        self.changed = false;
+        Some(SyncMessage::Presence(self.local.clone()))
+    }
+
+    /// The other devices with the task open.
+    pub fn viewing<'a>(&'a self, task: &'a TaskId) -> impl Iterator<Item = &'a Presence> {
+        self.peers
+            .iter()
+            .map(|peer| &peer.presence)
+            .filter(move |presence| presence.viewing.as_ref() == Some(task))
+    }
+}
+
+/// Say which other devices have the task open and whether they are changing it.
+pub fn view_viewing<Ms>(presences: &Presences, task: &TaskId) -> Node<Ms> {
+    let viewing = presences
+        .viewing(task)
+        .map(|presence| {
+            let activity = if presence.editing {
+                "editing"
+            } else {
+                "viewing"
+            };
+            let ago = presence
+                .last_active
+                .map_or_else(String::new, |last_active| {
+                    match duration_string(Utc::now() - last_active).as_str() {
+                        "now" => ", active just now".to_owned(),
+                        ago => format!(", active {ago} ago"),
+                    }
+                });
+            format!("{} is {activity}{ago}", presence.device)
+        })
+        .collect::<Vec<_>>();
+    if viewing.is_empty() {
+        empty![]
+    } else {
+        div![C!["text-orange-700", "px-2"], viewing.join("; ")]
+    }
+}
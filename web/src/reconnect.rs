@@ -0,0 +1,82 @@
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+
+use crate::Msg;
+
+/// How long to wait before the first retry, doubling with each failed attempt.
+const BASE_DELAY_MS: u32 = 1000;
+
+/// The longest to wait between retries.
+const MAX_DELAY_MS: u32 = 60_000;
+
+/// Retries connecting to the server after losing the connection, backing off while it stays
+/// unreachable and waiting while the page is hidden or the device is offline.
+#[derive(Debug, Default)]
+pub struct Reconnect {
+    /// How many retries there have been since the connection was last open.
+    attempts: u32,
+    timer: Option<CmdHandle>,
+    /// Whether a retry is due once the page is visible and the device is online.
+    waiting: bool,
+}
+
+impl Reconnect {
+    /// Retry after a delay, unless one is already due.
+    pub fn schedule(&mut self, orders: &mut impl Orders<Msg>) {
+        if self.timer.is_some() {
+            return;
+        }
+        if !can_connect() {
+            self.waiting = true;
+            return;
+        }
+        let attempt = self.attempts;
+        self.attempts = self.attempts.saturating_add(1);
+        self.timer = Some(
+            orders.perform_cmd_with_handle(cmds::timeout(delay_ms(attempt), move || {
+                Msg::ReconnectWebSocket(attempt)
+            })),
+        );
+    }
+
+    /// Record that a retry is starting.
+    pub fn started(&mut self) {
+        self.timer = None;
+        self.waiting = false;
+    }
+
+    /// Stop retrying, such as once connected or when retrying can't help.
+    pub fn stop(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Hold off a due retry until [`Self::resume`].
+    pub fn pause(&mut self) {
+        if self.timer.take().is_some() {
+            self.waiting = true;
+        }
+    }
+
+    /// Retry straight away if one was held off and the page can connect now.
+    pub fn resume(&mut self, orders: &mut impl Orders<Msg>) {
+        if self.waiting && can_connect() {
+            self.waiting = false;
+            orders.send_msg(Msg::ReconnectWebSocket(self.attempts));
+        }
+    }
+}
+
+/// Whether the page is visible and the device is online, so retrying could succeed and matters.
+pub fn can_connect() -> bool {
+    !document().hidden() && window().navigator().on_line()
+}
+
+/// Back off exponentially, picking somewhere in the upper half of the delay so clients that
+/// lost the connection together don't all retry together.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn delay_ms(attempt: u32) -> u32 {
+    let cap = BASE_DELAY_MS
+        .saturating_mul(2_u32.saturating_pow(attempt))
+        .min(MAX_DELAY_MS);
+    cap / 2 + (f64::from(cap / 2) * js_sys::Math::random()) as u32
+}
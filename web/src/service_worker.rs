@@ -0,0 +1,88 @@
+use gloo_console::{error, log};
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+use web_sys::{ServiceWorkerRegistration, ServiceWorkerState};
+
+use crate::Msg;
+
+/// How often to check for a new version while the app is open.
+pub const UPDATE_CHECK_MS: u32 = 60 * 60 * 1000;
+
+/// Message the service worker takes as the go ahead to replace the running version.
+const SKIP_WAITING: &str = "skip-waiting";
+
+/// Register the service worker that caches the app for offline use.
+pub fn register(orders: &mut impl Orders<Msg>) {
+    let navigator = window().navigator();
+    // only available over https or on localhost
+    if !js_sys::Reflect::has(&navigator, &JsValue::from_str("serviceWorker")).unwrap_or(false) {
+        log!("Service workers are not available");
+        return;
+    }
+    let promise = navigator.service_worker().register("sw.js");
+    let send = orders.msg_sender();
+    orders.perform_cmd(async move {
+        match JsFuture::from(promise).await {
+            Ok(registration) => {
+                let registration: ServiceWorkerRegistration = registration.unchecked_into();
+                watch_for_updates(&registration, move || send(Some(Msg::UpdateAvailable)));
+                Some(Msg::ServiceWorkerRegistered(registration))
+            }
+            Err(err) => {
+                error!("Failed to register service worker", err);
+                None
+            }
+        }
+    });
+}
+
+/// Call `available` once a new version is installed and waiting to replace the running one.
+fn watch_for_updates(registration: &ServiceWorkerRegistration, available: impl Fn() + 'static) {
+    // without a controller this is the first install rather than an update
+    let is_update = || window().navigator().service_worker().controller().is_some();
+    if registration.waiting().is_some() && is_update() {
+        available();
+    }
+
+    let installing = registration.clone();
+    let available = std::rc::Rc::new(available);
+    let on_update_found = Closure::wrap(Box::new(move || {
+        let Some(worker) = installing.installing() else {
+            return;
+        };
+        let available = available.clone();
+        let state_of = worker.clone();
+        let on_state_change = Closure::wrap(Box::new(move || {
+            if state_of.state() == ServiceWorkerState::Installed && is_update() {
+                available();
+            }
+        }) as Box<dyn Fn()>);
+        worker.set_onstatechange(Some(on_state_change.as_ref().unchecked_ref()));
+        on_state_change.forget();
+    }) as Box<dyn Fn()>);
+    registration.set_onupdatefound(Some(on_update_found.as_ref().unchecked_ref()));
+    on_update_found.forget();
+}
+
+/// Ask the server whether there is a new version, which is then installed in the background.
+pub fn check_for_update(registration: &ServiceWorkerRegistration) {
+    if let Err(err) = registration.update() {
+        log!("Failed to check for an update", err);
+    }
+}
+
+/// Switch to the waiting version, reloading the page once it has taken over.
+pub fn apply_update(registration: &ServiceWorkerRegistration) {
+    let Some(waiting) = registration.waiting() else {
+        return;
+    };
+    let container = window().navigator().service_worker();
+    let reload = Closure::wrap(Box::new(|| {
+        window().location().reload().unwrap_or_else(|e| log!(e));
+    }) as Box<dyn Fn()>);
+    container.set_oncontrollerchange(Some(reload.as_ref().unchecked_ref()));
+    reload.forget();
+    if let Err(err) = waiting.post_message(&JsValue::from_str(SKIP_WAITING)) {
+        error!("Failed to message service worker", err);
+    }
+}
@@ -0,0 +1,302 @@
+use std::future::Future;
+
+use base64::Engine;
+use gloo_console::{error, log};
+use gloo_storage::{LocalStorage, Storage as _};
+use js_sys::{Array, Function, Promise, Uint8Array};
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+use web_sys::{
+    DomException, IdbDatabase, IdbObjectStoreParameters, IdbRequest, IdbTransaction,
+    IdbTransactionMode,
+};
+
+use crate::{document::Document, Msg};
+
+const DATABASE_NAME: &str = "tasknet";
+const DATABASE_VERSION: u32 = 1;
+
+/// Holds the document as a full save followed by the changes saved since.
+const CHUNKS_STORE: &str = "document";
+
+/// Replace the chunks with a single full save once there are this many.
+const COMPACT_AFTER_CHUNKS: usize = 100;
+
+/// Where earlier versions saved the document, moved into `IndexedDB` when first loaded.
+const LEGACY_STORAGE_KEY: &str = "tasknet-autodoc";
+
+/// The saved chunks of the document and their keys, along with the database holding them.
+pub type Opened = Result<(IdbDatabase, Vec<(f64, Vec<u8>)>), String>;
+
+/// A chunk of the document that was saved.
+#[derive(Debug, Clone)]
+pub struct Saved {
+    /// Where the chunk is stored, or none if this device can't store it.
+    pub key: Option<f64>,
+    /// The chunks it replaced, when it is a full save.
+    pub replaced: Vec<f64>,
+    pub compacted: bool,
+    pub chunk: Vec<u8>,
+}
+
+/// Saves the document on this device in `IndexedDB`.
+///
+/// Other tabs save into the same store, so compacting only replaces the chunks this tab has loaded.
+#[derive(Debug, Default)]
+pub struct Storage {
+    db: Option<IdbDatabase>,
+    /// Whether the saved document has been loaded, so it is safe to sync.
+    pub loaded: bool,
+    /// The keys of the stored chunks that are in the document here, which are the ones safe to
+    /// replace when compacting.
+    keys: Vec<f64>,
+    /// Whether the next save should replace the stored chunks with a full save.
+    compact: bool,
+    /// Why changes aren't being saved, such as the device running out of space.
+    pub error: Option<String>,
+}
+
+impl Storage {
+    /// Open the database and read the saved document.
+    pub fn open(orders: &mut impl Orders<Msg>) {
+        orders.perform_cmd(async { Msg::StorageOpened(open().await) });
+    }
+
+    /// Take the database once opened, returning the chunks to load into the document.
+    pub fn opened(&mut self, opened: Opened) -> Vec<Vec<u8>> {
+        self.loaded = true;
+        let mut chunks = match opened {
+            Ok((db, chunks)) => {
+                self.db = Some(db);
+                let (keys, chunks) = chunks.into_iter().unzip();
+                self.keys = keys;
+                chunks
+            }
+            Err(err) => {
+                error!("Failed to open IndexedDB", &err);
+                self.error = Some(err);
+                Vec::new()
+            }
+        };
+        let legacy = LocalStorage::get::<String>(LEGACY_STORAGE_KEY)
+            .ok()
+            .and_then(|saved| base64::engine::general_purpose::STANDARD.decode(saved).ok());
+        if let Some(legacy) = legacy {
+            log!("Moving document from LocalStorage to IndexedDB");
+            chunks.push(legacy);
+            self.compact = true;
+        }
+        chunks
+    }
+
+    /// Save the whole document next time, such as when it had changes before it was loaded.
+    pub const fn compact(&mut self) {
+        self.compact = true;
+    }
+
+    /// Store the changes made since the last save, compacting the stored chunks when there are
+    /// enough of them.
+    pub fn save(&mut self, document: &mut Document, orders: &mut impl Orders<Msg>) {
+        let unsaved = document.unsaved();
+        let Some(db) = self.db.clone() else {
+            // still pass the changes on to other tabs
+            if self.loaded && unsaved {
+                orders.send_msg(Msg::StorageSaved(Saved {
+                    key: None,
+                    replaced: Vec::new(),
+                    compacted: false,
+                    chunk: document.save_changes(),
+                }));
+            }
+            return;
+        };
+        // after a failure only try again once there is something new to save
+        let retry = unsaved || self.error.is_none();
+        let compact = retry && (self.compact || self.keys.len() >= COMPACT_AFTER_CHUNKS);
+        let chunk = if compact {
+            self.compact = false;
+            document.save_all()
+        } else if unsaved {
+            document.save_changes()
+        } else {
+            return;
+        };
+        let replaced = if compact {
+            self.keys.clone()
+        } else {
+            Vec::new()
+        };
+        match write(&db, &chunk, &replaced) {
+            Ok(written) => {
+                orders.perform_cmd(async move {
+                    match written.await {
+                        Ok(key) => Msg::StorageSaved(Saved {
+                            key: Some(key),
+                            replaced,
+                            compacted: compact,
+                            chunk,
+                        }),
+                        Err(err) => Msg::StorageFailed(err),
+                    }
+                });
+            }
+            Err(err) => {
+                orders.send_msg(Msg::StorageFailed(err));
+            }
+        }
+    }
+
+    /// Record where a chunk was stored, whether saved here or by another tab whose changes are
+    /// now in the document here.
+    pub fn saved(&mut self, saved: &Saved) {
+        if !self.loaded {
+            // the chunks read when opening will include it if it was stored in time
+            return;
+        }
+        self.keys.retain(|key| !saved.replaced.contains(key));
+        self.keys.extend(saved.key);
+        if saved.compacted && saved.key.is_some() {
+            // the full save has everything the old copy had
+            LocalStorage::delete(LEGACY_STORAGE_KEY);
+            self.error = None;
+        }
+    }
+
+    /// Record a failed save, keeping the changes in memory to save in full later.
+    pub fn failed(&mut self, err: String) {
+        error!("Failed to save document", &err);
+        self.compact = true;
+        self.error = Some(err);
+    }
+}
+
+#[allow(clippy::future_not_send)]
+async fn open() -> Opened {
+    let factory = window()
+        .indexed_db()
+        .ok()
+        .flatten()
+        .ok_or_else(|| "IndexedDB is not available".to_owned())?;
+    let request = factory
+        .open_with_u32(DATABASE_NAME, DATABASE_VERSION)
+        .map_err(describe)?;
+    let upgrading = request.clone();
+    let upgrade = Closure::wrap(Box::new(move || {
+        let db: IdbDatabase = upgrading
+            .result()
+            .expect("database is open while upgrading")
+            .unchecked_into();
+        let mut parameters = IdbObjectStoreParameters::new();
+        parameters.auto_increment(true);
+        if let Err(err) = db.create_object_store_with_optional_parameters(CHUNKS_STORE, &parameters)
+        {
+            error!("Failed to create object store", err);
+        }
+    }) as Box<dyn Fn()>);
+    request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+    let opened = result(&request).await;
+    request.set_onupgradeneeded(None);
+    let db: IdbDatabase = opened?.unchecked_into();
+
+    let transaction = db.transaction_with_str(CHUNKS_STORE).map_err(describe)?;
+    let store = transaction.object_store(CHUNKS_STORE).map_err(describe)?;
+    // both are in key order and read in the same transaction, so they line up
+    let keys = store.get_all_keys().map_err(describe)?;
+    let chunks = store.get_all().map_err(describe)?;
+    let keys = result(&keys).await?.unchecked_into::<Array>();
+    let chunks = result(&chunks).await?.unchecked_into::<Array>();
+    let chunks = keys
+        .iter()
+        .zip(chunks.iter())
+        .filter_map(|(key, chunk)| Some((key.as_f64()?, Uint8Array::new(&chunk).to_vec())))
+        .collect();
+    Ok((db, chunks))
+}
+
+/// Start storing the chunk, after deleting the chunks it replaces, giving its key once stored.
+///
+/// Transactions on the store run in the order they are started, so this is synchronous and the
+/// returned future only waits for it to finish.
+fn write(
+    db: &IdbDatabase,
+    chunk: &[u8],
+    replace: &[f64],
+) -> Result<impl Future<Output = Result<f64, String>>, String> {
+    let transaction = db
+        .transaction_with_str_and_mode(CHUNKS_STORE, IdbTransactionMode::Readwrite)
+        .map_err(describe)?;
+    let store = transaction.object_store(CHUNKS_STORE).map_err(describe)?;
+    for key in replace {
+        store.delete(&JsValue::from_f64(*key)).map_err(describe)?;
+    }
+    let request = store.add(&Uint8Array::from(chunk)).map_err(describe)?;
+    Ok(async move {
+        committed(&transaction).await?;
+        request
+            .result()
+            .map_err(describe)?
+            .as_f64()
+            .ok_or_else(|| "Stored chunk has no key".to_owned())
+    })
+}
+
+/// Wait for one of the promise's callbacks, given to `listen`, to be called.
+#[allow(clippy::future_not_send)]
+async fn settled(listen: impl FnOnce(&Function, &Function)) -> bool {
+    let mut callbacks = None;
+    let promise = Promise::new(&mut |resolve, reject| callbacks = Some((resolve, reject)));
+    let (resolve, reject) = callbacks.expect("promise callbacks are given immediately");
+    listen(&resolve, &reject);
+    JsFuture::from(promise).await.is_ok()
+}
+
+#[allow(clippy::future_not_send)]
+async fn result(request: &IdbRequest) -> Result<JsValue, String> {
+    let succeeded = settled(|resolve, reject| {
+        request.set_onsuccess(Some(resolve));
+        request.set_onerror(Some(reject));
+    })
+    .await;
+    request.set_onsuccess(None);
+    request.set_onerror(None);
+    if succeeded {
+        request.result().map_err(describe)
+    } else {
+        Err(describe(
+            request
+                .error()
+                .ok()
+                .flatten()
+                .map_or(JsValue::UNDEFINED, JsValue::from),
+        ))
+    }
+}
+
+#[allow(clippy::future_not_send)]
+async fn committed(transaction: &IdbTransaction) -> Result<(), String> {
+    let succeeded = settled(|resolve, reject| {
+        transaction.set_oncomplete(Some(resolve));
+        transaction.set_onerror(Some(reject));
+        transaction.set_onabort(Some(reject));
+    })
+    .await;
+    if succeeded {
+        Ok(())
+    } else {
+        Err(describe(
+            transaction
+                .error()
+                .map_or(JsValue::UNDEFINED, JsValue::from),
+        ))
+    }
+}
+
+fn describe(error: JsValue) -> String {
+    match error.dyn_into::<DomException>() {
+        Ok(error) if error.name() == "QuotaExceededError" => {
+            "This device is out of storage space".to_owned()
+        }
+        Ok(error) => format!("{}: {}", error.name(), error.message()),
+        Err(error) => format!("{error:?}"),
+    }
+}
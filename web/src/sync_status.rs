@@ -0,0 +1,113 @@
+use chrono::{DateTime, Utc};
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+
+use crate::{
+    components::{duration_string, view_button_str},
+    GlobalModel, Msg,
+};
+
+/// How syncing with the server is going, beyond whether the connection is open.
+#[derive(Debug, Default)]
+pub struct SyncStatus {
+    /// Local changes the server isn't known to have, once it has said what it has.
+    pub pending: Option<usize>,
+    /// When the server last had every change made here.
+    pub last_synced: Option<DateTime<Utc>>,
+    /// Why the connection last failed or closed, until it opens again.
+    pub error: Option<String>,
+    /// Whether the details are shown.
+    pub open: bool,
+}
+
+impl SyncStatus {
+    /// Record what the server is missing after hearing from it.
+    pub fn heard(&mut self, pending: usize) {
+        self.pending = Some(pending);
+        if pending == 0 {
+            self.last_synced = Some(Utc::now());
+        }
+    }
+
+    /// Record new local changes, if the server has said what it has.
+    pub const fn changed(&mut self, pending: usize) {
+        if self.pending.is_some() {
+            self.pending = Some(pending);
+        }
+    }
+
+    /// A short summary to show alongside the connection state.
+    pub fn summary(&self) -> Option<String> {
+        match self.pending {
+            Some(0) | None => None,
+            Some(pending) => Some(format!("{pending} pending")),
+        }
+    }
+}
+
+pub fn view(global: &GlobalModel) -> Node<Msg> {
+    let status = &global.sync_status;
+    if !status.open {
+        return empty![];
+    }
+    let errors = [
+        global.locked.map(|locked| locked.message().to_owned()),
+        global.sync_error.clone(),
+        status.error.clone(),
+        global.storage.error.clone(),
+    ];
+    div![
+        C![
+            "absolute",
+            "right-0",
+            "top-full",
+            "z-10",
+            "w-80",
+            "bg-white",
+            "border",
+            "shadow-lg",
+            "p-2",
+            "flex",
+            "flex-col"
+        ],
+        if global.tabs.leader {
+            vec![
+                div![C!["px-2"], pending_string(status.pending)],
+                div![C!["px-2"], last_synced_string(status.last_synced)],
+            ]
+        } else {
+            vec![div![
+                C!["px-2"],
+                "Another open tab syncs with the server, changes here are passed to it"
+            ]]
+        },
+        errors
+            .iter()
+            .flatten()
+            .map(|error| div![C!["px-2", "text-red-700"], error]),
+        div![
+            C!["flex", "flex-row", "justify-end"],
+            view_button_str("Force resync", Msg::ForceResync),
+            view_button_str("Close", Msg::ToggleSyncStatus),
+        ],
+    ]
+}
+
+fn pending_string(pending: Option<usize>) -> String {
+    match pending {
+        None => "Not heard from the server yet".to_owned(),
+        Some(0) => "All changes are on the server".to_owned(),
+        Some(1) => "1 change isn't on the server yet".to_owned(),
+        Some(pending) => format!("{pending} changes aren't on the server yet"),
+    }
+}
+
+fn last_synced_string(last_synced: Option<DateTime<Utc>>) -> String {
+    last_synced.map_or_else(
+        || "Not synced since opening".to_owned(),
+        |last_synced| match duration_string(Utc::now() - last_synced).as_str() {
+            "now" => "Last synced just now".to_owned(),
+            ago => format!("Last synced {ago} ago"),
+        },
+    )
+}
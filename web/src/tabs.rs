@@ -0,0 +1,120 @@
+use base64::Engine;
+use gloo_console::{error, log};
+use js_sys::{Function, Promise, Reflect};
+#[allow(clippy::wildcard_imports)]
+use seed::{prelude::*, *};
+use serde::{Deserialize, Serialize};
+use web_sys::{BroadcastChannel, MessageEvent};
+
+use crate::{storage::Saved, Msg};
+
+/// The channel tabs of the app tell each other about changes on.
+const CHANNEL_NAME: &str = "tasknet";
+
+/// Held by the tab that syncs with the server for the others.
+const LOCK_NAME: &str = "tasknet-sync";
+
+/// What one tab tells the others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TabMessage {
+    /// A chunk of the document another tab saved, with where it was stored.
+    Saved {
+        key: Option<f64>,
+        replaced: Vec<f64>,
+        /// The base64 encoded chunk.
+        chunk: String,
+    },
+    /// Ask the syncing tab to reconnect, such as after the passphrase changed.
+    Reconnect,
+    /// Ask the syncing tab to forget what the server has and sync everything again.
+    Resync,
+}
+
+impl TabMessage {
+    pub fn saved(saved: &Saved) -> Self {
+        Self::Saved {
+            key: saved.key,
+            replaced: saved.replaced.clone(),
+            chunk: base64::engine::general_purpose::STANDARD.encode(&saved.chunk),
+        }
+    }
+}
+
+/// Keeps the other open tabs of the app up to date, with only one of them syncing with the server.
+#[derive(Debug, Default)]
+pub struct Tabs {
+    channel: Option<BroadcastChannel>,
+    /// Kept alive for as long as the channel calls it.
+    _listener: Option<Closure<dyn Fn(MessageEvent)>>,
+    /// Whether this tab syncs with the server.
+    pub leader: bool,
+}
+
+impl Tabs {
+    /// Listen to the other tabs and wait to become the one syncing with the server.
+    pub fn open(orders: &mut impl Orders<Msg>) -> Self {
+        let send = orders.msg_sender();
+        let listener = Closure::wrap(Box::new(move |event: MessageEvent| {
+            let message = event
+                .data()
+                .as_string()
+                .and_then(|data| serde_json::from_str(&data).ok());
+            send(message.map(Msg::TabMessage));
+        }) as Box<dyn Fn(MessageEvent)>);
+        let channel = match BroadcastChannel::new(CHANNEL_NAME) {
+            Ok(channel) => {
+                channel.set_onmessage(Some(listener.as_ref().unchecked_ref()));
+                Some(channel)
+            }
+            Err(err) => {
+                error!("Failed to open BroadcastChannel", err);
+                None
+            }
+        };
+        if let Err(err) = request_leadership(orders) {
+            // without locks every tab syncs by itself
+            log!("Web Locks unavailable, syncing from this tab:", err);
+            orders.send_msg(Msg::BecameLeader);
+        }
+        Self {
+            channel,
+            _listener: Some(listener),
+            leader: false,
+        }
+    }
+
+    pub fn send(&self, message: &TabMessage) {
+        let Some(channel) = &self.channel else {
+            return;
+        };
+        let data = serde_json::to_string(message).expect("serialize tab message");
+        if let Err(err) = channel.post_message(&JsValue::from_str(&data)) {
+            error!("Failed to message other tabs", err);
+        }
+    }
+}
+
+/// Decode a chunk from another tab.
+pub fn decode_chunk(chunk: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(chunk)
+        .map_err(|err| error!(format!("Failed to decode chunk from another tab: {err:?}")))
+        .ok()
+}
+
+/// Ask for the lock held by the syncing tab, which the browser grants once no other tab holds it.
+///
+/// The lock is held until the tab closes. web-sys doesn't bind the Web Locks API yet, so it is
+/// called through reflection.
+fn request_leadership(orders: &impl Orders<Msg>) -> Result<(), JsValue> {
+    let locks = Reflect::get(&window().navigator(), &JsValue::from_str("locks"))?;
+    let request = Reflect::get(&locks, &JsValue::from_str("request"))?.dyn_into::<Function>()?;
+    let send = orders.msg_sender();
+    let granted = Closure::once_into_js(move |_lock: JsValue| {
+        send(Some(Msg::BecameLeader));
+        // never settles, so the lock isn't released
+        Promise::new(&mut |_, _| {})
+    });
+    request.call2(&locks, &JsValue::from_str(LOCK_NAME), &granted)?;
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::task::{Priority, Status, Task};
+use tasknet_shared::task::{Priority, Status, Task};
 
 const NEXT_COEFFICIENT: f64 = 15.0;
 const DUE_COEFFICIENT: f64 = 12.0;
@@ -26,21 +26,21 @@ pub fn calculate(task: &Task) -> Option<f64> {
             WAITING_COEFFICIENT
                 + urgency_age(task.entry().0)
                 + urgency_project(task.project())
-                + urgency_due(&task.due().as_ref().map(|d| d.0))
-                + urgency_scheduled(&task.scheduled().as_ref().map(|d| d.0))
+                + urgency_due(task.due().as_ref().map(|d| d.0))
+                + urgency_scheduled(task.scheduled().as_ref().map(|d| d.0))
                 + urgency_tags(task.tags())
                 + urgency_next(task.tags())
-                + urgency_priority(task.priority()),
+                + urgency_priority(task.priority().as_ref()),
         ),
         Status::Pending => Some(
             urgency_age(task.entry().0)
                 + urgency_project(task.project())
-                + urgency_active(&task.start().as_ref().map(|d| d.0))
-                + urgency_due(&task.due().as_ref().map(|d| d.0))
-                + urgency_scheduled(&task.scheduled().as_ref().map(|d| d.0))
+                + urgency_active(task.start().as_ref().map(|d| d.0))
+                + urgency_due(task.due().as_ref().map(|d| d.0))
+                + urgency_scheduled(task.scheduled().as_ref().map(|d| d.0))
                 + urgency_tags(task.tags())
                 + urgency_next(task.tags())
-                + urgency_priority(task.priority()),
+                + urgency_priority(task.priority().as_ref()),
         ),
     }
 }
@@ -61,7 +61,7 @@ const fn urgency_project(project: &[String]) -> f64 {
     }
 }
 
-const fn urgency_active(start: &Option<chrono::DateTime<chrono::Utc>>) -> f64 {
+const fn urgency_active(start: Option<chrono::DateTime<chrono::Utc>>) -> f64 {
     if start.is_some() {
         ACTIVE_COEFFICIENT
     } else {
@@ -70,7 +70,7 @@ const fn urgency_active(start: &Option<chrono::DateTime<chrono::Utc>>) -> f64 {
 }
 
 #[allow(clippy::cast_precision_loss)]
-fn urgency_due(due: &Option<chrono::DateTime<chrono::Utc>>) -> f64 {
+fn urgency_due(due: Option<chrono::DateTime<chrono::Utc>>) -> f64 {
     due.map_or(0.0, |due| {
         let days_overdue = (chrono::offset::Utc::now())
             .signed_duration_since(due)
@@ -86,7 +86,7 @@ fn urgency_due(due: &Option<chrono::DateTime<chrono::Utc>>) -> f64 {
 }
 
 #[allow(clippy::cast_precision_loss)]
-fn urgency_scheduled(scheduled: &Option<chrono::DateTime<chrono::Utc>>) -> f64 {
+fn urgency_scheduled(scheduled: Option<chrono::DateTime<chrono::Utc>>) -> f64 {
     scheduled.map_or(0.0, |scheduled| {
         let days_overdue = (chrono::offset::Utc::now())
             .signed_duration_since(scheduled)
@@ -118,7 +118,7 @@ fn urgency_next(tags: &[String]) -> f64 {
     }
 }
 
-const fn urgency_priority(priority: &Option<Priority>) -> f64 {
+const fn urgency_priority(priority: Option<&Priority>) -> f64 {
     match priority {
         None => 0.0,
         Some(Priority::Low) => LOW_PRIORITY_COEFFICIENT,
@@ -0,0 +1,85 @@
+// Caches the app so it opens offline, serving the version this worker was installed with.
+//
+// The version in the cache name is replaced on each build (see Trunk.toml), so browsers install the
+// new worker, which waits until the page asks it to take over and then drops the old caches.
+const CACHE = "tasknet-BUILD_VERSION";
+
+// Files that don't change their names between builds, unlike the ones trunk adds hashes to.
+const SHELL = [
+  "manifest.json",
+  "assets/favicon.ico",
+  "assets/apple-touch-icon.png",
+  "assets/android-chrome-192x192.png",
+  "assets/android-chrome-512x512.png",
+];
+
+// Server routes that are never cached.
+const NETWORK_ONLY = ["auth/", "sync", "quota", "reminders", "webhooks", "metrics"];
+
+self.addEventListener("install", (event) => {
+  event.waitUntil(precache());
+});
+
+async function precache() {
+  const cache = await caches.open(CACHE);
+  const index = await fetch("./", { cache: "no-cache" });
+  if (!index.ok) {
+    throw new Error(`Failed to fetch the app: ${index.status}`);
+  }
+  const html = await index.clone().text();
+  // the scripts, wasm and styles trunk linked from the page
+  const assets = [...html.matchAll(/["']([^"']+\.(?:js|wasm|css))["']/g)].map((m) => m[1]);
+  await cache.addAll([...new Set([...SHELL, ...assets])]);
+  await cache.put("./", index);
+}
+
+self.addEventListener("activate", (event) => {
+  event.waitUntil(
+    (async () => {
+      for (const name of await caches.keys()) {
+        if (name.startsWith("tasknet-") && name !== CACHE) {
+          await caches.delete(name);
+        }
+      }
+      await self.clients.claim();
+    })()
+  );
+});
+
+self.addEventListener("message", (event) => {
+  if (event.data === "skip-waiting") {
+    self.skipWaiting();
+  }
+});
+
+self.addEventListener("fetch", (event) => {
+  const request = event.request;
+  const scope = self.registration.scope;
+  if (request.method !== "GET" || !request.url.startsWith(scope)) {
+    return;
+  }
+  const path = new URL(request.url).pathname.slice(new URL(scope).pathname.length);
+  if (NETWORK_ONLY.some((prefix) => path.startsWith(prefix))) {
+    return;
+  }
+
+  if (request.mode === "navigate") {
+    // the app routes with the hash so the page itself is always the index
+    if (path === "" || path === "index.html") {
+      event.respondWith(
+        caches
+          .open(CACHE)
+          .then((cache) => cache.match("./"))
+          .then((cached) => cached || fetch(request))
+      );
+    }
+    return;
+  }
+
+  event.respondWith(
+    caches
+      .open(CACHE)
+      .then((cache) => cache.match(request))
+      .then((cached) => cached || fetch(request))
+  );
+});